@@ -0,0 +1,72 @@
+//! Measures how long the router takes to deliver events to a topic with many subscribers.
+//!
+//! Run with `cargo bench --bench fanout`.  The number of subscribers and events can be changed
+//! with the `SUBSCRIBERS` and `EVENTS` environment variables.
+//!
+//! This measures the whole path from publisher to subscribers.  To compare `handle_publish` with
+//! and without subscribers sharing one serialized event, run the router's `publish_fanout` test
+//! (`cargo test --release publish_fanout -- --ignored --nocapture`).
+extern crate wamp;
+extern crate eventual;
+
+use wamp::{Connection, Router, URI, Value};
+use eventual::Async;
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn setting(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+#[cfg(unix)]
+fn main() {
+    let subscribers = setting("SUBSCRIBERS", 1000);
+    let events = setting("EVENTS", 100);
+    let path = env::temp_dir().join(format!("wamp-fanout-{}.sock", std::process::id()));
+
+    let mut router = Router::new();
+    router.add_realm("bench");
+    let listener = router.listen_unix(&path).unwrap();
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let mut clients = Vec::with_capacity(subscribers);
+    for _ in 0..subscribers {
+        let mut client = Connection::new_unix(&path, "bench").connect().unwrap();
+        let received = received.clone();
+        client.subscribe(URI::new("bench.fanout"), Box::new(move |_args, _kwargs| {
+            received.fetch_add(1, Ordering::SeqCst);
+        })).unwrap().await().unwrap();
+        clients.push(client);
+    }
+
+    let mut publisher = Connection::new_unix(&path, "bench").connect().unwrap();
+    let payload = vec![Value::String("x".repeat(256)), Value::Integer(42), Value::Float(3.5)];
+    let start = Instant::now();
+    for _ in 0..events {
+        publisher.publish_and_acknowledge(URI::new("bench.fanout"), Some(payload.clone()), None).unwrap().await().unwrap();
+    }
+    let routed = start.elapsed();
+    while received.load(Ordering::SeqCst) < subscribers * events {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let delivered = start.elapsed();
+
+    println!("{} events to {} subscribers", events, subscribers);
+    println!("  routed in    {:>10.2} ms ({:.3} ms per event)", millis(routed), millis(routed) / events as f64);
+    println!("  delivered in {:>10.2} ms ({:.0} messages per second)", millis(delivered), (subscribers * events) as f64 / (millis(delivered) / 1000.0));
+
+    router.shutdown();
+    listener.stop();
+}
+
+#[cfg(not(unix))]
+fn main() {
+    println!("The fanout benchmark needs Unix domain sockets");
+}
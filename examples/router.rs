@@ -10,6 +10,6 @@ fn main() {
     let mut router = Router::new();
     router.add_realm("kitchen_realm");
     info!("Router listening");
-    let child = router.listen("127.0.0.1:8090");
-    child.join().unwrap();
+    let listener = router.listen("127.0.0.1:8090").unwrap();
+    listener.join();
 }
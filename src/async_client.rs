@@ -0,0 +1,220 @@
+//! A client whose requests return `futures` futures, so that it can be used from tokio services.
+//!
+//! The `AsyncClient` wraps a `Client`, so messages are still handled on the client's own
+//! connection thread.  Procedures can be registered with callbacks that return futures, which
+//! are spawned onto the executor given to `AsyncClient::connect`, and the events of a
+//! subscription can be consumed as a `Stream`.
+//!
+//! Requires the `async` feature.
+use futures::{Future, Stream, Poll, IntoFuture};
+use futures::future::{self, Executor};
+use futures::sync::{mpsc, oneshot};
+use eventual::{Async as EventualAsync, AsyncError, Future as EventualFuture};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use client::{Client, Connection, Subscription, Registration};
+use messages::{URI, Dict, List, Value, Reason, CallError, MatchingPolicy};
+use ::{WampResult, Error, ErrorKind, ID};
+
+/// The futures spawned to run registered procedures.
+pub type Task = Box<Future<Item = (), Error = ()> + Send>;
+
+/// A client whose requests return futures rather than `eventual` futures.
+///
+/// Cloning an `AsyncClient` gives another handle to the same session.
+#[derive(Clone)]
+pub struct AsyncClient {
+    client: Arc<Mutex<Client>>,
+    executor: Arc<Executor<Task> + Send + Sync>
+}
+
+/// The events published to a subscription, as positional and keyword arguments.
+///
+/// The stream ends when the client unsubscribes, or once every handle to the client is dropped.
+pub struct EventStream {
+    receiver: mpsc::UnboundedReceiver<(List, Dict)>
+}
+
+impl Stream for EventStream {
+    type Item = (List, Dict);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<(List, Dict)>, ()> {
+        self.receiver.poll()
+    }
+}
+
+impl AsyncClient {
+    /// Connects to the router, without blocking the calling thread while the session is being
+    /// established.
+    ///
+    /// The futures returned by registered procedures are spawned onto `executor`, such as a tokio
+    /// `TaskExecutor`.  The executor must run them on a different thread from the one that
+    /// spawns them.
+    pub fn connect<E>(connection: Connection, executor: E) -> impl Future<Item = AsyncClient, Error = Error>
+        where E: Executor<Task> + Send + Sync + 'static {
+        let (complete, future) = oneshot::channel();
+        thread::spawn(move || {
+            complete.send(connection.connect()).ok();
+        });
+        let executor: Arc<Executor<Task> + Send + Sync> = Arc::new(executor);
+        future.then(move |result| {
+            let client = match result {
+                Ok(result) => try!(result),
+                Err(_) => return Err(Error::new(ErrorKind::ConnectionLost))
+            };
+            Ok(AsyncClient {
+                client: Arc::new(Mutex::new(client)),
+                executor: executor
+            })
+        })
+    }
+
+    pub fn call(&self, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> impl Future<Item = (List, Dict), Error = CallError> {
+        bridge(self.client.lock().unwrap().call(procedure, args, kwargs))
+    }
+
+    /// Publishes an event, and resolves once the router has acknowledged it.
+    pub fn publish(&self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> impl Future<Item = ID, Error = CallError> {
+        bridge(self.client.lock().unwrap().publish_and_acknowledge(topic, args, kwargs))
+    }
+
+    /// Subscribes to a topic, resolving to the subscription and the stream of its events.
+    pub fn subscribe(&self, topic: URI) -> impl Future<Item = (Subscription, EventStream), Error = CallError> {
+        self.subscribe_with_pattern(topic, MatchingPolicy::Strict)
+    }
+
+    pub fn subscribe_with_pattern(&self, topic_pattern: URI, policy: MatchingPolicy) -> impl Future<Item = (Subscription, EventStream), Error = CallError> {
+        let (sender, receiver) = mpsc::unbounded();
+        let subscription = self.client.lock().unwrap().subscribe_with_pattern(topic_pattern, Box::new(move |args, kwargs| {
+            // If the stream has been dropped, nobody is interested in the event anymore
+            sender.unbounded_send((args, kwargs)).ok();
+        }), policy);
+        bridge(subscription).map(move |subscription| {
+            (subscription, EventStream {
+                receiver: receiver
+            })
+        })
+    }
+
+    /// Registers a procedure whose callback returns a future (or anything that can be turned
+    /// into one, such as a `Result`).  The future is spawned onto the client's executor, and the
+    /// caller receives its result once it has finished.
+    pub fn register<F, R>(&self, procedure: URI, callback: F) -> impl Future<Item = Registration, Error = CallError>
+        where F: FnMut(List, Dict) -> R + Send + 'static,
+              R: IntoFuture<Item = (Option<List>, Option<Dict>), Error = CallError>,
+              R::Future: Send + 'static {
+        self.register_with_pattern(procedure, callback, MatchingPolicy::Strict)
+    }
+
+    pub fn register_with_pattern<F, R>(&self, procedure_pattern: URI, mut callback: F, policy: MatchingPolicy) -> impl Future<Item = Registration, Error = CallError>
+        where F: FnMut(List, Dict) -> R + Send + 'static,
+              R: IntoFuture<Item = (Option<List>, Option<Dict>), Error = CallError>,
+              R::Future: Send + 'static {
+        let executor = self.executor.clone();
+        let registration = self.client.lock().unwrap().register_deferred(procedure_pattern, Box::new(move |args, kwargs, reply| {
+            let task = callback(args, kwargs).into_future().then(move |result| {
+                if let Err(e) = reply.send(result) {
+                    warn!("Could not send the result of an invocation: {}", e);
+                }
+                Ok(())
+            });
+            if executor.execute(Box::new(task)).is_err() {
+                error!("Could not spawn an invocation onto the executor");
+            }
+        }), policy);
+        bridge(registration)
+    }
+
+    pub fn unsubscribe(&self, subscription: Subscription) -> impl Future<Item = (), Error = CallError> {
+        bridge(self.client.lock().unwrap().unsubscribe(subscription))
+    }
+
+    pub fn unregister(&self, registration: Registration) -> impl Future<Item = (), Error = CallError> {
+        bridge(self.client.lock().unwrap().unregister(registration))
+    }
+
+    /// Leaves the session, resolving once the router has acknowledged it.
+    pub fn shutdown(&self) -> impl Future<Item = (), Error = CallError> {
+        bridge(self.client.lock().unwrap().shutdown())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.client.lock().unwrap().is_closed()
+    }
+}
+
+/// Turns a request made by the `Client` into a future.  If the request could not even be sent,
+/// the future fails straight away.
+fn bridge<T: Send + 'static>(request: WampResult<EventualFuture<T, CallError>>) -> Box<Future<Item = T, Error = CallError> + Send> {
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return Box::new(future::err(network_failure(e.get_kind().description())))
+    };
+    let (complete, future) = oneshot::channel();
+    request.receive(move |result| {
+        complete.send(result).ok();
+    });
+    Box::new(future.then(|result| {
+        match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(AsyncError::Failed(e))) => Err(e),
+            Ok(Err(AsyncError::Aborted)) | Err(_) => Err(network_failure("The request was dropped before it completed".to_string()))
+        }
+    }))
+}
+
+fn network_failure(message: String) -> CallError {
+    CallError::new(Reason::NetworkFailure, Some(vec![Value::String(message)]), None)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::AsyncClient;
+    use client::Connection;
+    use router::Router;
+    use messages::{URI, Value, List, Dict, Reason, CallError};
+    use futures::{Future, Stream};
+    use futures::future;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn call_register_and_subscribe() {
+        let mut router = Router::new();
+        router.add_realm("async_realm");
+        let runtime = Runtime::new().unwrap();
+
+        let callee = AsyncClient::connect(Connection::new_local(&router, "async_realm"), runtime.executor()).wait().unwrap();
+        callee.register(URI::new("ca.test.add"), |args: List, _kwargs: Dict| {
+            // Only runs once the executor polls it
+            future::lazy(move || {
+                match (args.get(0), args.get(1)) {
+                    (Some(&Value::Integer(a)), Some(&Value::Integer(b))) => Ok((Some(vec![Value::Integer(a + b)]), None)),
+                    _ => Err(CallError::new(Reason::InvalidArgument, None, None))
+                }
+            })
+        }).wait().unwrap();
+
+        let caller = AsyncClient::connect(Connection::new_local(&router, "async_realm"), runtime.executor()).wait().unwrap();
+        let (args, _) = caller.call(URI::new("ca.test.add"), Some(vec![Value::Integer(2), Value::Integer(3)]), None).wait().unwrap();
+        assert_eq!(args, vec![Value::Integer(5)]);
+        match caller.call(URI::new("ca.test.add"), Some(vec![Value::String("two".to_string())]), None).wait() {
+            Err(error) => assert_eq!(error.get_reason(), &Reason::InvalidArgument),
+            result => panic!("Expected an invalid argument error, got {:?}", result)
+        }
+
+        let (subscription, mut events) = callee.subscribe(URI::new("ca.test.topic")).wait().unwrap();
+        caller.publish(URI::new("ca.test.topic"), Some(vec![Value::Integer(1)]), None).wait().unwrap();
+        caller.publish(URI::new("ca.test.topic"), Some(vec![Value::Integer(2)]), None).wait().unwrap();
+        let received: Vec<List> = events.by_ref().take(2).map(|(args, _kwargs)| args).collect().wait().unwrap();
+        assert_eq!(received, vec![vec![Value::Integer(1)], vec![Value::Integer(2)]]);
+
+        // Unsubscribing ends the stream
+        callee.unsubscribe(subscription).wait().unwrap();
+        assert!(events.collect().wait().unwrap().is_empty());
+
+        caller.shutdown().wait().unwrap();
+        callee.shutdown().wait().unwrap();
+    }
+}
@@ -1,6 +1,5 @@
 use ws::{
     connect,
-    Sender,
     CloseCode,
     Handler,
     Message as WSMessage,
@@ -14,22 +13,35 @@ use ws::{
 
 use ws::util::{Token, Timeout};
 
-use messages::{URI, Dict, List, WelcomeDetails, SubscribeOptions, PublishOptions, CallOptions, InvocationDetails, YieldOptions, ResultDetails, RegisterOptions, Message,  HelloDetails, Reason, ErrorDetails, ClientRoles, MatchingPolicy, ErrorType};
+use messages::{URI, Dict, List, Value, to_args, from_args, WelcomeDetails, SubscribeOptions, PublishOptions, CallOptions, InvocationDetails, YieldOptions, ResultDetails, RegisterOptions, Message,  HelloDetails, Reason, ErrorDetails, ClientRoles, MatchingPolicy, ErrorType};
 use std::collections::HashMap;
-use serde_json;
-use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::time::Duration;
 use ::{WampResult, Error, ErrorKind, ID, CallResult, CallError};
 use std::thread;
 use std::sync::{Mutex, Arc, MutexGuard};
-use rmp_serde::Deserializer as RMPDeserializer;
-use rmp_serde::Serializer;
-use utils::StructMapWriter;
-use std::io::Cursor;
-use eventual::{Complete, Future};
+use eventual::{Async, Complete, Future};
+use serde::{Serialize, Deserialize};
 use url::Url;
 use std::sync::mpsc::{channel, Sender as CHSender};
+use rand;
+use transport::{self, Sender};
+use serializer::{Serializer, Serializers, JsonSerializer};
+use transport::local::{self, Envelope};
+use transport::batched::{self, FLUSH_TIMEOUT};
+use router::{Router, LocalConnector};
+use transport::rawsocket::{self, Stream, FrameType, MAX_LENGTH_EXPONENT};
+use std::net;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "ssl")]
+use mio::tcp::TcpStream;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+#[cfg(feature = "ssl")]
+use transport::tls::{self, TlsClientConfig};
 
 macro_rules! try_websocket {
     ($e: expr) => (
@@ -43,10 +55,28 @@ macro_rules! try_websocket {
 const CONNECTION_TIMEOUT:Token = Token(124);
 const EXPIRE_TIMEOUT: Token = Token(125);
 
+#[derive(Clone)]
 pub struct Connection {
     realm: URI,
-    url: String,
+    endpoint: Endpoint,
     timeout: u64,
+    authmethods: Option<Vec<String>>,
+    batch_flush_interval: Option<u64>,
+    serializers: Serializers,
+    max_message_size: usize,
+    reconnect_policy: Option<ReconnectPolicy>,
+    callbacks: Callbacks,
+    #[cfg(feature = "ssl")]
+    tls_connector: Option<Arc<SslConnector>>,
+}
+
+#[derive(Clone)]
+enum Endpoint {
+    WebSocket(String),
+    RawSocket(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Local(LocalConnector)
 }
 
 pub struct Subscription {
@@ -59,42 +89,91 @@ pub struct Registration {
     registration_id: ID
 }
 
+/// How a client tries to restore its session when the connection to the router is lost.
+///
+/// The delay before each attempt starts at `initial_delay` and is multiplied by `multiplier`
+/// after every failed attempt, up to `max_delay`.  A random fraction of up to `jitter` of each
+/// delay is taken off, so that many clients don't all reconnect at the same moment.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// The delay before the first attempt, in milliseconds
+    pub initial_delay: u64,
+    /// The longest delay between two attempts, in milliseconds
+    pub max_delay: u64,
+    pub multiplier: f64,
+    /// Between 0 and 1
+    pub jitter: f64,
+    /// How many attempts to make before giving up, or `None` to keep trying forever
+    pub max_attempts: Option<u32>
+}
+
+/// Passed to the callback given to `Connection::set_reconnect_callback` as a lost connection is
+/// being restored.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReconnectEvent {
+    /// The connection to the router was lost
+    Disconnected,
+    /// A new connection is about to be attempted.  Contains the number of the attempt, starting at 1.
+    Reconnecting(u32),
+    /// The session was restored.  Contains the new session id.  Subscriptions and registrations
+    /// are sent to the router again, but may not have been acknowledged yet.
+    Reconnected(ID),
+    /// The policy's maximum number of attempts was reached, so the client has given up
+    GaveUp
+}
+
+/// The callbacks an application has given to be told about changes to the connection.
+#[derive(Clone, Default)]
+struct Callbacks {
+    join: Option<Arc<Mutex<Box<FnMut(ID, &WelcomeDetails) + Send>>>>,
+    leave: Option<Arc<Mutex<Box<FnMut(&Reason) + Send>>>>,
+    disconnect: Option<Arc<Mutex<Box<FnMut() + Send>>>>,
+    connect_failure: Option<Arc<Mutex<Box<FnMut(&Error) + Send>>>>,
+    reconnect: Option<Arc<Mutex<Box<FnMut(ReconnectEvent) + Send>>>>
+}
+
 struct SubscriptionCallbackWrapper {
-    callback: Box<FnMut(List, Dict)>
+    callback: Box<FnMut(List, Dict) + Send>,
+    topic: URI,
+    policy: MatchingPolicy,
+    // The id from the first session, which is the one in the `Subscription` given out
+    id: ID
+}
+
+enum RegistrationCallback {
+    Immediate(Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> + Send>),
+    // Given the means to send the result later, for procedures that finish asynchronously
+    Deferred(Box<FnMut(List, Dict, InvocationReply) + Send>)
+}
+
+/// Sends the result of an invocation once a deferred registration callback has produced it.
+pub(crate) struct InvocationReply {
+    request_id: ID,
+    connection_info: Arc<Mutex<ConnectionInfo>>
 }
 
 struct RegistrationCallbackWrapper {
-    callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)>>
+    callback: RegistrationCallback,
+    procedure: URI,
+    policy: MatchingPolicy,
+    // The id from the first session, which is the one in the `Registration` given out
+    id: ID
 }
 
-static WAMP_JSON:&'static str = "wamp.2.json";
-static WAMP_MSGPACK:&'static str = "wamp.2.msgpack";
 
 #[derive(PartialEq, Debug)]
 enum ConnectionState {
     Connecting,
     Connected,
     ShuttingDown,
+    Reconnecting,
     Disconnected
 }
 
 type ConnectionResult = Result<Arc<Mutex<ConnectionInfo>>, Error>;
 
-unsafe impl <'a> Send for ConnectionInfo {}
-
-unsafe impl<'a> Sync for ConnectionInfo {}
-
-unsafe impl <'a> Send for SubscriptionCallbackWrapper {}
-
-unsafe impl<'a> Sync for SubscriptionCallbackWrapper {}
-
-unsafe impl <'a> Send for RegistrationCallbackWrapper {}
-
-unsafe impl<'a> Sync for RegistrationCallbackWrapper {}
-
 pub struct Client {
     connection_info: Arc<Mutex<ConnectionInfo>>,
-    max_session_id: ID,
 }
 
 pub struct ConnectionHandler {
@@ -102,6 +181,12 @@ pub struct ConnectionHandler {
     realm: URI,
     state_transmission: CHSender<ConnectionResult>,
     timeout: Option<Timeout>,
+    authmethods: Option<Vec<String>>,
+    batch_flush_interval: Option<u64>,
+    serializers: Serializers,
+    max_message_size: usize,
+    #[cfg(feature = "ssl")]
+    tls_connector: Option<Arc<SslConnector>>,
 }
 
 struct ConnectionInfo {
@@ -111,13 +196,24 @@ struct ConnectionInfo {
     unsubscription_requests: HashMap<ID, (Complete<(), CallError>, ID)>,
     subscriptions: HashMap<ID, SubscriptionCallbackWrapper>,
     registrations: HashMap<ID, RegistrationCallbackWrapper>,
+    // Maps the ids from the first session to the current ones, for subscriptions and
+    // registrations that were restored after reconnecting
+    subscription_ids: HashMap<ID, ID>,
+    registration_ids: HashMap<ID, ID>,
+    resubscription_requests: HashMap<ID, SubscriptionCallbackWrapper>,
+    reregistration_requests: HashMap<ID, RegistrationCallbackWrapper>,
     call_requests: HashMap<ID, Complete<(List, Dict), CallError>>,
     registration_requests: HashMap<ID, (Complete<Registration, CallError>, RegistrationCallbackWrapper, URI)>,
     unregistration_requests: HashMap<ID, (Complete<(), CallError>, ID)>,
     protocol: String,
+    serializer: Arc<Serializer>,
     publish_requests: HashMap<ID, Complete<ID, CallError>>,
     shutdown_complete: Option<Complete<(), CallError>>,
-    session_id: ID
+    session_id: ID,
+    max_request_id: ID,
+    // Cleared when the client is shut down, so that a lost connection is no longer restored
+    reconnect: Option<Connection>,
+    callbacks: Callbacks
 }
 
 trait MessageSender {
@@ -128,50 +224,142 @@ impl MessageSender for ConnectionInfo {
     fn send_message(&self, message: Message) -> WampResult<()> {
 
         debug!("Sending message {:?} via {}", message, self.protocol);
-        let send_result = if self.protocol == WAMP_JSON {
-            send_message_json(&self.sender, &message)
-        } else {
-            send_message_msgpack(&self.sender, &message)
-        };
-        match send_result {
-            Ok(()) => Ok(()),
-            Err(e) => Err(Error::new(ErrorKind::WSError(e)))
-        }
+        self.sender.send_message(&*self.serializer, &message)
     }
 }
 
-
-
-fn send_message_json(sender: &Sender, message: &Message) -> WSResult<()> {
-    // Send the message
-    sender.send(WSMessage::Text(serde_json::to_string(message).unwrap()))
-
+impl ReconnectPolicy {
+    /// How long to wait before the given attempt, where the first attempt is 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = (self.initial_delay as f64 * self.multiplier.powi(attempt as i32 - 1)).min(self.max_delay as f64);
+        let jitter = self.jitter.max(0.0).min(1.0) * rand::random::<f64>();
+        Duration::from_millis((delay * (1.0 - jitter)) as u64)
+    }
 }
 
-fn send_message_msgpack(sender: &Sender, message: &Message) -> WSResult<()> {
-
-    // Send the message
-    let mut buf: Vec<u8> = Vec::new();
-    message.serialize(&mut Serializer::with(&mut buf, StructMapWriter)).unwrap();
-    sender.send(WSMessage::Binary(buf))
-
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: 500,
+            max_delay: 30000,
+            multiplier: 2.0,
+            jitter: 0.25,
+            max_attempts: None
+        }
+    }
 }
 
 impl Connection {
     pub fn new(url: &str, realm: &str) -> Connection {
         Connection {
             realm: URI::new(realm),
-            url: url.to_string(),
+            endpoint: Endpoint::WebSocket(url.to_string()),
             timeout: 5000,
+            authmethods: None,
+            batch_flush_interval: None,
+            serializers: Serializers::new(),
+            max_message_size: rawsocket::max_length(MAX_LENGTH_EXPONENT),
+            reconnect_policy: None,
+            callbacks: Callbacks::default(),
+            #[cfg(feature = "ssl")]
+            tls_connector: None,
+        }
+    }
+
+    /// Creates a connection to a router listening for RawSocket connections over TCP on the given
+    /// address, such as `"127.0.0.1:8091"`.
+    pub fn new_rawsocket(address: &str, realm: &str) -> Connection {
+        Connection {
+            realm: URI::new(realm),
+            endpoint: Endpoint::RawSocket(address.to_string()),
+            timeout: 5000,
+            authmethods: None,
+            batch_flush_interval: None,
+            serializers: Serializers::new(),
+            max_message_size: rawsocket::max_length(MAX_LENGTH_EXPONENT),
+            reconnect_policy: None,
+            callbacks: Callbacks::default(),
+            #[cfg(feature = "ssl")]
+            tls_connector: None,
+        }
+    }
+
+    /// Creates a connection to a router listening for RawSocket connections on a Unix domain socket.
+    #[cfg(unix)]
+    pub fn new_unix<P: AsRef<Path>>(path: P, realm: &str) -> Connection {
+        Connection {
+            realm: URI::new(realm),
+            endpoint: Endpoint::Unix(path.as_ref().to_path_buf()),
+            timeout: 5000,
+            authmethods: None,
+            batch_flush_interval: None,
+            serializers: Serializers::new(),
+            max_message_size: rawsocket::max_length(MAX_LENGTH_EXPONENT),
+            reconnect_policy: None,
+            callbacks: Callbacks::default(),
+            #[cfg(feature = "ssl")]
+            tls_connector: None,
+        }
+    }
+
+    /// Creates a connection to a router running in the same process.  Messages are passed to the
+    /// router directly, so no socket is opened and nothing is serialized.
+    pub fn new_local(router: &Router, realm: &str) -> Connection {
+        Connection {
+            realm: URI::new(realm),
+            endpoint: Endpoint::Local(router.local_connector()),
+            timeout: 5000,
+            authmethods: None,
+            batch_flush_interval: None,
+            serializers: Serializers::new(),
+            max_message_size: rawsocket::max_length(MAX_LENGTH_EXPONENT),
+            reconnect_policy: None,
+            callbacks: Callbacks::default(),
+            #[cfg(feature = "ssl")]
+            tls_connector: None,
         }
     }
 
     pub fn connect<'a>(&self) -> WampResult<Client> {
+        self.open(None)
+    }
+
+    /// Opens a connection to the router.  If `existing` is given, it is the state of a lost
+    /// connection, which is reused so that the `Client` holding it keeps working.
+    fn open(&self, existing: Option<Arc<Mutex<ConnectionInfo>>>) -> WampResult<Client> {
+        let result = match self.endpoint {
+            Endpoint::WebSocket(ref url) => self.connect_websocket(url, existing),
+            Endpoint::RawSocket(ref address) => {
+                let stream = try_websocket!(net::TcpStream::connect(address.as_str()).map_err(WSError::from));
+                self.connect_rawsocket(Box::new(stream), existing)
+            },
+            #[cfg(unix)]
+            Endpoint::Unix(ref path) => {
+                let stream = try_websocket!(UnixStream::connect(path).map_err(WSError::from));
+                self.connect_rawsocket(Box::new(stream), existing)
+            },
+            Endpoint::Local(ref connector) => self.connect_local(connector, existing)
+        };
+        if let Err(ref e) = result {
+            self.callbacks.connect_failure(e);
+        }
+        result
+    }
+
+    fn connect_websocket(&self, url: &str, existing: Option<Arc<Mutex<ConnectionInfo>>>) -> WampResult<Client> {
         let (tx, rx) = channel();
         
-        let url = self.url.clone();
+        let url = url.to_string();
+        let reconnect = self.reconnect_state();
+        let callbacks = self.callbacks.clone();
         let timeout = self.timeout;
         let realm = self.realm.clone();
+        let authmethods = self.authmethods.clone();
+        let batch_flush_interval = self.batch_flush_interval;
+        let serializers = self.serializers.clone();
+        let max_message_size = self.max_message_size;
+        #[cfg(feature = "ssl")]
+        let tls_connector = self.tls_connector.clone();
         
         thread::spawn(move || {
             trace!("Beginning Connection");
@@ -181,27 +369,19 @@ impl Connection {
                 // Set up timeout
                 out.timeout(timeout, CONNECTION_TIMEOUT).unwrap();
 
-                let info = Arc::new(Mutex::new(ConnectionInfo {
-                    protocol: String::new(),
-                    subscription_requests: HashMap::new(),
-                    unsubscription_requests: HashMap::new(),
-                    subscriptions: HashMap::new(),
-                    registrations: HashMap::new(),
-                    call_requests: HashMap::new(),
-                    registration_requests: HashMap::new(),
-                    unregistration_requests: HashMap::new(),
-                    sender: out,
-                    connection_state: ConnectionState::Connecting,
-                    publish_requests: HashMap::new(),
-                    shutdown_complete: None,
-                    session_id: 0
-                }));
+                let info = attach(&existing, Sender::WebSocket(out), &reconnect, &callbacks);
 
                 let handler = ConnectionHandler {
                     state_transmission: tx.clone(),
                     connection_info: info,
                     realm: realm.clone(),
                     timeout: None,
+                    authmethods: authmethods.clone(),
+                    batch_flush_interval: batch_flush_interval,
+                    serializers: serializers.clone(),
+                    max_message_size: max_message_size,
+                    #[cfg(feature = "ssl")]
+                    tls_connector: tls_connector.clone(),
                 };
 
                 handler
@@ -224,16 +404,457 @@ impl Connection {
                 let info = try!(data);
                 Ok(Client{
                     connection_info: info,
-                    max_session_id: 0,
                 })
             },
             Err(_) => Err(Error::new(ErrorKind::Timeout)),
         }
     }
 
+    fn connect_rawsocket(&self, mut stream: Box<Stream>, existing: Option<Arc<Mutex<ConnectionInfo>>>) -> WampResult<Client> {
+        let (tx, rx) = channel();
+        let serializer = match self.serializers.preferred_raw_socket() {
+            Some(serializer) => serializer,
+            None => return Err(Error::new(ErrorKind::InvalidState("None of the serializers can be used over RawSocket")))
+        };
+        let serializer_id = serializer.raw_socket_id().unwrap();
+        let length_exponent = rawsocket::length_exponent(self.max_message_size);
+        let max_message_size = self.max_message_size;
+        try_websocket!(rawsocket::write_handshake(&mut stream, length_exponent, serializer_id).map_err(WSError::from));
+        let write_stream = try_websocket!(stream.try_clone_stream().map_err(WSError::from));
+        let sender = rawsocket::Sender::new(write_stream);
+        let realm = self.realm.clone();
+        let authmethods = self.authmethods.clone();
+        let handler_sender = sender.clone();
+        let serializers = self.serializers.clone();
+        let reconnect = self.reconnect_state();
+        let callbacks = self.callbacks.clone();
+
+        thread::spawn(move || {
+            trace!("Beginning RawSocket Connection");
+            match rawsocket::read_handshake(&mut stream) {
+                Ok(rawsocket::Handshake::Accepted{serializer, ..}) if serializer == serializer_id => {},
+                Ok(rawsocket::Handshake::Accepted{serializer, ..}) => {
+                    debug!("Router chose RawSocket serializer {}", serializer);
+                    tx.send(Err(Error::new(ErrorKind::InvalidState("Router replied with a different serializer than the one requested")))).ok();
+                    return;
+                },
+                Ok(rawsocket::Handshake::Refused(_)) => {
+                    tx.send(Err(Error::new(ErrorKind::InvalidState("Router refused the RawSocket handshake")))).ok();
+                    return;
+                },
+                Err(e) => {
+                    tx.send(Err(Error::new(ErrorKind::WSError(WSError::from(e))))).ok();
+                    return;
+                }
+            }
+
+            let info = attach(&existing, Sender::RawSocket(handler_sender.clone()), &reconnect, &callbacks);
+            {
+                let mut info = info.lock().unwrap();
+                info.protocol = serializer.protocol().to_string();
+                info.serializer = serializer.clone();
+            }
+            let mut handler = ConnectionHandler {
+                state_transmission: tx,
+                connection_info: info,
+                realm: realm,
+                timeout: None,
+                authmethods: authmethods,
+                batch_flush_interval: None,
+                serializers: serializers,
+                max_message_size: max_message_size,
+                #[cfg(feature = "ssl")]
+                tls_connector: None,
+            };
+            if let Err(e) = handler.send_hello() {
+                error!("Could not send hello message: {}", e);
+                handler_sender.shutdown().ok();
+            }
+
+            loop {
+                match rawsocket::read_frame(&mut stream, rawsocket::max_length(length_exponent)) {
+                    Ok((FrameType::Message, payload)) => {
+                        let message = if serializer.is_binary() {
+                            WSMessage::Binary(payload)
+                        } else {
+                            match String::from_utf8(payload) {
+                                Ok(payload) => WSMessage::Text(payload),
+                                Err(_) => {
+                                    error!("Recieved {} message that was not valid UTF-8", serializer.protocol());
+                                    continue;
+                                }
+                            }
+                        };
+                        handler.on_message(message).ok();
+                    },
+                    Ok((FrameType::Ping, payload)) => {
+                        if handler_sender.pong(&payload).is_err() {
+                            break;
+                        }
+                    },
+                    Ok((FrameType::Pong, _)) => {},
+                    Err(e) => {
+                        debug!("RawSocket connection closed: {}", e);
+                        break;
+                    }
+                }
+            }
+            handler.on_close(CloseCode::Normal, "");
+        });
+
+        match rx.recv_timeout(Duration::from_millis(self.timeout)) {
+            Ok(data) => {
+                let info = try!(data);
+                Ok(Client{
+                    connection_info: info,
+                })
+            },
+            Err(_) => {
+                sender.shutdown().ok();
+                Err(Error::new(ErrorKind::Timeout))
+            }
+        }
+    }
+
+    fn connect_local(&self, connector: &LocalConnector, existing: Option<Arc<Mutex<ConnectionInfo>>>) -> WampResult<Client> {
+        let (tx, rx) = channel();
+        let (client_sender, receiver) = local::channel();
+        let router_sender = connector.connect(client_sender);
+        let max_message_size = self.max_message_size;
+        let realm = self.realm.clone();
+        let authmethods = self.authmethods.clone();
+        let reconnect = self.reconnect_state();
+        let callbacks = self.callbacks.clone();
+
+        thread::spawn(move || {
+            trace!("Beginning Local Connection");
+            let info = attach(&existing, Sender::Local(router_sender.clone()), &reconnect, &callbacks);
+            info.lock().unwrap().protocol = "local".to_string();
+            let mut handler = ConnectionHandler {
+                state_transmission: tx,
+                connection_info: info,
+                realm: realm,
+                timeout: None,
+                authmethods: authmethods,
+                batch_flush_interval: None,
+                serializers: Serializers::new(),
+                max_message_size: max_message_size,
+                #[cfg(feature = "ssl")]
+                tls_connector: None,
+            };
+            if let Err(e) = handler.send_hello() {
+                error!("Could not send hello message: {}", e);
+                router_sender.close().ok();
+            }
+
+            for envelope in receiver.iter() {
+                match envelope {
+                    Envelope::Message(message) => {
+                        // The session is over, for example once goodbye messages have been exchanged
+                        if !handler.handle_message(message) {
+                            router_sender.close().ok();
+                            break;
+                        }
+                    },
+                    Envelope::Close => break
+                }
+            }
+            handler.on_close(CloseCode::Normal, "");
+        });
+
+        match rx.recv_timeout(Duration::from_millis(self.timeout)) {
+            Ok(data) => {
+                let info = try!(data);
+                Ok(Client{
+                    connection_info: info,
+                })
+            },
+            Err(_) => Err(Error::new(ErrorKind::Timeout))
+        }
+    }
+
     pub fn set_timeout(&mut self, timeout: u64) {
         self.timeout = timeout;
     }
+
+    /// Sets the authentication methods offered to the router, in order of preference.
+    ///
+    /// For example, a client presenting a certificate with `set_tls_config` can offer `"tls"`
+    /// to be authenticated using the identity in that certificate.
+    pub fn set_authmethods(&mut self, authmethods: Vec<String>) {
+        self.authmethods = Some(authmethods);
+    }
+
+    /// Asks the router to use a batched serializer (`wamp.2.msgpack.batched` or
+    /// `wamp.2.json.batched`) for WebSocket connections.  Messages are then held back for
+    /// `interval` milliseconds so that they can be sent together in one frame.  If the interval
+    /// is 0, every message is sent in its own frame.
+    pub fn set_batch_flush_interval(&mut self, interval: u64) {
+        self.batch_flush_interval = Some(interval);
+    }
+
+    /// Sets the largest message (in bytes) the client accepts from the router.  Larger messages
+    /// are dropped without being deserialized.  Defaults to 16MB.
+    ///
+    /// The router is told the limit during RawSocket handshakes, rounded down to a power of two
+    /// (with a minimum of 512 bytes), and the connection is closed if it sends a larger frame.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Offers a serializer to the router, preferring it over the serializers already offered.
+    /// If the serializer has the same protocol name as an existing one, it replaces it.
+    pub fn add_serializer(&mut self, serializer: Arc<Serializer>) {
+        self.serializers.add(serializer);
+    }
+
+    /// Makes the client reconnect when its connection to the router is lost, rather than failing
+    /// every request from then on.  Once a new session is established, every subscription and
+    /// registration is sent to the router again, so the `Client` can keep being used as before.
+    ///
+    /// Requests that were waiting for a response when the connection was lost still fail with
+    /// `Reason::NetworkFailure`, since there is no way to know whether the router handled them.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect_policy = Some(policy);
+    }
+
+    /// Sets a callback to be told when the connection is lost and as it is restored.  Only used
+    /// if a reconnect policy has been set.
+    pub fn set_reconnect_callback(&mut self, callback: Box<FnMut(ReconnectEvent) + Send>) {
+        self.callbacks.reconnect = Some(Arc::new(Mutex::new(callback)));
+    }
+
+    /// Sets a callback to be called whenever the router welcomes the client into a session,
+    /// with the id of the session and the details the router sent.
+    pub fn on_join(&mut self, callback: Box<FnMut(ID, &WelcomeDetails) + Send>) {
+        self.callbacks.join = Some(Arc::new(Mutex::new(callback)));
+    }
+
+    /// Sets a callback to be called when the session ends with a GOODBYE, with the reason given
+    /// by the router.  This happens both when the router ends the session and when it
+    /// acknowledges `Client::shutdown`.
+    pub fn on_leave(&mut self, callback: Box<FnMut(&Reason) + Send>) {
+        self.callbacks.leave = Some(Arc::new(Mutex::new(callback)));
+    }
+
+    /// Sets a callback to be called when an open connection to the router is closed, whether or
+    /// not the session was ended first.
+    pub fn on_disconnect(&mut self, callback: Box<FnMut() + Send>) {
+        self.callbacks.disconnect = Some(Arc::new(Mutex::new(callback)));
+    }
+
+    /// Sets a callback to be called when a connection to the router could not be opened, or
+    /// was not welcomed into a session.  This includes failed attempts to reconnect.
+    pub fn on_connect_failure(&mut self, callback: Box<FnMut(&Error) + Send>) {
+        self.callbacks.connect_failure = Some(Arc::new(Mutex::new(callback)));
+    }
+
+    /// The connection to keep in the connection's state, so that it can be opened again later.
+    fn reconnect_state(&self) -> Option<Connection> {
+        if self.reconnect_policy.is_some() {
+            Some(self.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Tries to restore a lost connection, following the reconnect policy.
+    fn reconnect(&self, info: Arc<Mutex<ConnectionInfo>>) {
+        let policy = match self.reconnect_policy {
+            Some(ref policy) => policy.clone(),
+            None => return
+        };
+        self.callbacks.reconnect(ReconnectEvent::Disconnected);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if policy.max_attempts.map_or(false, |max_attempts| attempt > max_attempts) {
+                warn!("Giving up on reconnecting after {} attempts", attempt - 1);
+                info.lock().unwrap().connection_state = ConnectionState::Disconnected;
+                self.callbacks.reconnect(ReconnectEvent::GaveUp);
+                return;
+            }
+            thread::sleep(policy.delay(attempt));
+            if info.lock().unwrap().reconnect.is_none() {
+                // The client was shut down while we were waiting
+                return;
+            }
+            self.callbacks.reconnect(ReconnectEvent::Reconnecting(attempt));
+            info!("Reconnecting to the router (attempt {})", attempt);
+            match self.open(Some(info.clone())) {
+                Ok(_) => {
+                    let mut info = info.lock().unwrap();
+                    if info.reconnect.is_none() {
+                        // The client was shut down while the new session was being opened
+                        info.connection_state = ConnectionState::ShuttingDown;
+                        info.send_message(Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown)).ok();
+                        return;
+                    }
+                    if let Err(e) = info.restore_session() {
+                        warn!("Could not restore subscriptions and registrations: {}", e);
+                    }
+                    let session_id = info.session_id;
+                    drop(info);
+                    self.callbacks.reconnect(ReconnectEvent::Reconnected(session_id));
+                    return;
+                },
+                Err(e) => {
+                    info!("Could not reconnect: {}", e);
+                    let mut info = info.lock().unwrap();
+                    if info.reconnect.is_none() {
+                        info.connection_state = ConnectionState::Disconnected;
+                        return;
+                    }
+                    info.connection_state = ConnectionState::Reconnecting;
+                }
+            }
+        }
+    }
+
+    /// Sets the trust roots and client certificate used for `wss://` connections.
+    ///
+    /// Returns an error if the certificates or keys in the configuration could not be loaded.
+    #[cfg(feature = "ssl")]
+    pub fn set_tls_config(&mut self, config: &TlsClientConfig) -> WampResult<()> {
+        self.tls_connector = Some(Arc::new(try!(tls::build_connector(config))));
+        Ok(())
+    }
+}
+
+impl Callbacks {
+    fn join(&self, session_id: ID, details: &WelcomeDetails) {
+        if let Some(ref callback) = self.join {
+            (&mut *callback.lock().unwrap())(session_id, details);
+        }
+    }
+
+    fn leave(&self, reason: &Reason) {
+        if let Some(ref callback) = self.leave {
+            (&mut *callback.lock().unwrap())(reason);
+        }
+    }
+
+    fn disconnect(&self) {
+        if let Some(ref callback) = self.disconnect {
+            (&mut *callback.lock().unwrap())();
+        }
+    }
+
+    fn connect_failure(&self, error: &Error) {
+        if let Some(ref callback) = self.connect_failure {
+            (&mut *callback.lock().unwrap())(error);
+        }
+    }
+
+    fn reconnect(&self, event: ReconnectEvent) {
+        if let Some(ref callback) = self.reconnect {
+            (&mut *callback.lock().unwrap())(event);
+        }
+    }
+}
+
+impl ConnectionInfo {
+    fn new(sender: Sender) -> ConnectionInfo {
+        ConnectionInfo {
+            protocol: String::new(),
+            serializer: Arc::new(JsonSerializer),
+            subscription_requests: HashMap::new(),
+            unsubscription_requests: HashMap::new(),
+            subscriptions: HashMap::new(),
+            registrations: HashMap::new(),
+            call_requests: HashMap::new(),
+            registration_requests: HashMap::new(),
+            unregistration_requests: HashMap::new(),
+            sender: sender,
+            connection_state: ConnectionState::Connecting,
+            publish_requests: HashMap::new(),
+            shutdown_complete: None,
+            session_id: 0,
+            subscription_ids: HashMap::new(),
+            registration_ids: HashMap::new(),
+            resubscription_requests: HashMap::new(),
+            reregistration_requests: HashMap::new(),
+            max_request_id: 0,
+            reconnect: None,
+            callbacks: Callbacks::default()
+        }
+    }
+
+    /// Prepares the state of a lost connection to be used by a new one.
+    fn reset(&mut self, sender: Sender) {
+        self.sender = sender;
+        self.protocol = String::new();
+        self.serializer = Arc::new(JsonSerializer);
+        self.connection_state = ConnectionState::Connecting;
+    }
+
+    fn next_request_id(&mut self) -> ID {
+        self.max_request_id += 1;
+        self.max_request_id
+    }
+
+    /// Sends every subscription and registration of the previous session to the router again.
+    fn restore_session(&mut self) -> WampResult<()> {
+        let subscriptions: Vec<_> = self.subscriptions.drain().map(|(_, subscription)| subscription).collect();
+        for subscription in subscriptions {
+            let request_id = self.next_request_id();
+            let mut options = SubscribeOptions::new();
+            if subscription.policy != MatchingPolicy::Strict {
+                options.pattern_match = subscription.policy
+            }
+            let topic = subscription.topic.clone();
+            self.resubscription_requests.insert(request_id, subscription);
+            try!(self.send_message(Message::Subscribe(request_id, options, topic)));
+        }
+        let registrations: Vec<_> = self.registrations.drain().map(|(_, registration)| registration).collect();
+        for registration in registrations {
+            let request_id = self.next_request_id();
+            let mut options = RegisterOptions::new();
+            if registration.policy != MatchingPolicy::Strict {
+                options.pattern_match = registration.policy
+            }
+            let procedure = registration.procedure.clone();
+            self.reregistration_requests.insert(request_id, registration);
+            try!(self.send_message(Message::Register(request_id, options, procedure)));
+        }
+        Ok(())
+    }
+}
+
+impl InvocationReply {
+    pub(crate) fn send(self, result: CallResult<(Option<List>, Option<Dict>)>) -> WampResult<()> {
+        let message = invocation_result(self.request_id, result);
+        self.connection_info.lock().unwrap().send_message(message)
+    }
+}
+
+/// Creates the message that answers an invocation, from the result of its callback.
+fn invocation_result(request_id: ID, result: CallResult<(Option<List>, Option<Dict>)>) -> Message {
+    match result {
+        Ok((args, kwargs)) => {
+            Message::Yield(request_id, YieldOptions::new(), args, kwargs)
+        }, Err(error) => {
+            let (reason, args, kwargs) = error.to_tuple();
+            Message::Error(ErrorType::Invocation, request_id, HashMap::new(), reason, args, kwargs)
+        }
+    }
+}
+
+/// Creates the state for a new connection, or reuses the state of a lost connection that is
+/// being restored.
+fn attach(existing: &Option<Arc<Mutex<ConnectionInfo>>>, sender: Sender, reconnect: &Option<Connection>, callbacks: &Callbacks) -> Arc<Mutex<ConnectionInfo>> {
+    match *existing {
+        Some(ref info) => {
+            info.lock().unwrap().reset(sender);
+            info.clone()
+        },
+        None => {
+            let mut info = ConnectionInfo::new(sender);
+            info.reconnect = reconnect.clone();
+            info.callbacks = callbacks.clone();
+            Arc::new(Mutex::new(info))
+        }
+    }
 }
 
 macro_rules! cancel_future_tuple {
@@ -263,71 +884,78 @@ impl Handler for ConnectionHandler {
                 protocol.to_string()
             } None => {
                 warn!("Router did not specify protocol. Defaulting to wamp.2.json");
-                WAMP_JSON.to_string()
+                JsonSerializer.protocol().to_string()
             }
         };
 
-        let hello_message = Message::Hello(self.realm.clone(), HelloDetails::new(ClientRoles::new()));
-        
-        debug!("Sending Hello message");
-        
-        thread::sleep(Duration::from_millis(200));
-        
-        match info.send_message(hello_message) {
-            Ok(_)  => Ok(()),
-            Err(e) => {
-                if let ErrorKind::WSError(e) = e.kind {
-                    Err(e)
-                } else {
-                    Err(WSError::new(WSErrorKind::Internal, "Unknown error"))
+        match self.serializers.negotiate(&info.protocol) {
+            Some((serializer, batched)) => {
+                if batched {
+                    let flush_interval = self.batch_flush_interval.unwrap_or(0);
+                    info.sender = info.sender.clone().into_batched(!serializer.is_binary(), flush_interval);
                 }
+                info.serializer = serializer;
+            },
+            None => {
+                return Err(WSError::new(WSErrorKind::Protocol, format!("Router chose a sub-protocol that was not offered: {}", info.protocol)));
             }
         }
+
+        drop(info);
+
+        thread::sleep(Duration::from_millis(200));
+
+        self.send_hello()
     }
 
     fn on_message(&mut self, message: WSMessage) -> WSResult<()> {
         debug!("Server sent a message: {:?}", message);
-        
-        match message {
-            WSMessage::Text(message) => {
-                match serde_json::from_str(&message) {
-                    Ok(message) => {
-                        if !self.handle_message(message) {
-                            // TODO FIXME
-                            return Ok(());
-                        }
-                    } Err(_) => {
-                        error!("Received unknown message: {}", message);
-                        return Ok(());
-                    }
-                }
-            },
-            WSMessage::Binary(message) => {
-                let mut de = RMPDeserializer::new(Cursor::new(&*message));
-                match Deserialize::deserialize(&mut de) {
-                    Ok(message) => {
-                        if !self.handle_message(message) {
-                            return Ok(());
-                        }
-                    },
-                    Err(_) => {
-                        error!("Could not understand MsgPack message");
+        if self.connection_info.lock().unwrap().sender.is_batched() {
+            match batched::split(message) {
+                Ok(messages) => {
+                    for message in messages {
+                        try!(self.on_single_message(message));
                     }
+                },
+                Err(e) => {
+                    error!("Could not split batched message: {}", e);
                 }
             }
+            Ok(())
+        } else {
+            self.on_single_message(message)
         }
-        Ok(())
     }
 
-
-
     fn on_close(&mut self, _code: CloseCode, _reason: &str) {
         debug!("Closing connection");
         
         let mut info = self.connection_info.lock().unwrap();
         
         info.sender.close(CloseCode::Normal).ok();
-        info.connection_state = ConnectionState::Disconnected;
+        let was_open = match info.connection_state {
+            ConnectionState::Connecting | ConnectionState::Reconnecting => false,
+            _ => true
+        };
+        // Only connections that were lost are restored, not ones that were shut down or never opened
+        let reconnect = match info.connection_state {
+            ConnectionState::Connected | ConnectionState::Disconnected => info.reconnect.clone(),
+            _ => None
+        };
+        if reconnect.is_some() {
+            info.connection_state = ConnectionState::Reconnecting;
+        } else if info.connection_state != ConnectionState::Reconnecting {
+            info.connection_state = ConnectionState::Disconnected;
+        }
+        // Anything that was still being restored will be restored again by the next session
+        let subscriptions: Vec<_> = info.resubscription_requests.drain().map(|(_, subscription)| subscription).collect();
+        for subscription in subscriptions {
+            info.subscriptions.insert(subscription.id, subscription);
+        }
+        let registrations: Vec<_> = info.reregistration_requests.drain().map(|(_, registration)| registration).collect();
+        for registration in registrations {
+            info.registrations.insert(registration.id, registration);
+        }
         
         cancel_future_tuple!(info.subscription_requests);
         cancel_future_tuple!(info.unsubscription_requests);
@@ -345,6 +973,19 @@ impl Handler for ConnectionHandler {
             },
             None => {}
         }
+        let callbacks = info.callbacks.clone();
+        drop(info);
+
+        if was_open {
+            callbacks.disconnect();
+        }
+
+        if let Some(connection) = reconnect {
+            let info = self.connection_info.clone();
+            thread::spawn(move || {
+                connection.reconnect(info);
+            });
+        }
     }
 
     fn on_timeout(&mut self, token: Token) -> WSResult<()> {        
@@ -357,6 +998,11 @@ impl Handler for ConnectionHandler {
             }
         }
 
+        if token == FLUSH_TIMEOUT {
+            let info = self.connection_info.lock().unwrap();
+            try!(info.sender.flush());
+        }
+
         if token == EXPIRE_TIMEOUT {
             debug!("connection lost!");
             let mut info = self.connection_info.lock().unwrap();
@@ -394,11 +1040,29 @@ impl Handler for ConnectionHandler {
         debug!("WS error: {:#?}", err);
     }
 
+    #[cfg(feature = "ssl")]
+    fn upgrade_ssl_client(&mut self, stream: TcpStream, url: &Url) -> WSResult<SslStream<TcpStream>> {
+        let domain = match url.domain() {
+            Some(domain) => domain,
+            None => return Err(WSError::new(WSErrorKind::Protocol, format!("Unable to parse domain from {}. Needed for TLS.", url)))
+        };
+        match self.tls_connector {
+            Some(ref connector) => connector.connect(domain, stream).map_err(WSError::from),
+            None => {
+                let connector = try!(SslConnector::builder(SslMethod::tls()).map_err(|e| {
+                    WSError::new(WSErrorKind::Internal, format!("Failed to upgrade client to TLS: {}", e))
+                })).build();
+                connector.connect(domain, stream).map_err(WSError::from)
+            }
+        }
+    }
+
     fn build_request(&mut self, url: &Url) -> WSResult<Request> {
         trace!("Building request");
         let mut request = try!(Request::from_url(url));
-        request.add_protocol(WAMP_MSGPACK);
-        request.add_protocol(WAMP_JSON);
+        for protocol in self.serializers.protocols(self.batch_flush_interval.is_some()) {
+            request.add_protocol(&protocol);
+        }
         Ok(request)
     }
 
@@ -406,6 +1070,44 @@ impl Handler for ConnectionHandler {
 
 
 impl ConnectionHandler {
+    fn on_single_message(&mut self, message: WSMessage) -> WSResult<()> {
+        if transport::message_len(&message) > self.max_message_size {
+            error!("Dropping a {} byte message, which is larger than the maximum of {} bytes", transport::message_len(&message), self.max_message_size);
+            return Ok(());
+        }
+        let serializer = self.connection_info.lock().unwrap().serializer.clone();
+        match transport::parse_message(&*serializer, message) {
+            Ok(message) => {
+                self.handle_message(message);
+            },
+            Err(e) => {
+                error!("Could not understand {} message: {}", serializer.protocol(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_hello(&self) -> WSResult<()> {
+        let details = match self.authmethods {
+            Some(ref authmethods) => HelloDetails::new_with_auth(ClientRoles::new(), authmethods.clone()),
+            None => HelloDetails::new(ClientRoles::new())
+        };
+        let hello_message = Message::Hello(self.realm.clone(), details);
+
+        debug!("Sending Hello message");
+
+        match self.connection_info.lock().unwrap().send_message(hello_message) {
+            Ok(_)  => Ok(()),
+            Err(e) => {
+                if let ErrorKind::WSError(e) = e.kind {
+                    Err(e)
+                } else {
+                    Err(WSError::new(WSErrorKind::Internal, "Unknown error"))
+                }
+            }
+        }
+    }
+
     fn handle_message(&mut self, message: Message) -> bool {
         let mut info = self.connection_info.lock().unwrap();
 
@@ -457,20 +1159,23 @@ impl ConnectionHandler {
                     }
                 }
             }, ConnectionState::ShuttingDown => {
-                if let Message::Goodbye(_, _) = message {
+                if let Message::Goodbye(_, reason) = message {
                     // The router has seen our goodbye message and has responded in kind
                     info!("Router acknolwedged disconnect");
                     
+                    let callbacks = info.callbacks.clone();
                     match info.shutdown_complete.take() {
                         Some(promise) => promise.complete(()),
                         None          => {}
                     }
+                    drop(info);
+                    callbacks.leave(&reason);
                     return false;
                 } else {
                     warn!("Recieved message after shutting down, ignoring: {:?}", message);
                     return false;
                 }
-            }, ConnectionState::Disconnected => {
+            }, ConnectionState::Reconnecting | ConnectionState::Disconnected => {
                 // Should never happen
                 return false;
             }
@@ -485,12 +1190,23 @@ impl ConnectionHandler {
             Some((promise, callback, topic)) => {
                 debug!("Completing promise");
                 let subscription = Subscription{topic: topic, subscription_id: subscription_id};
+                let mut callback = callback;
+                callback.id = subscription_id;
                 info.subscriptions.insert(subscription_id, callback);
                 drop(info);
                 promise.complete(subscription)
             },
             None => {
-                warn!("Recieved a subscribed notification for a subscription we don't have.  ID: {}", request_id);
+                match info.resubscription_requests.remove(&request_id) {
+                    Some(subscription) => {
+                        debug!("Restored subscription to {:?}", subscription.topic);
+                        info.subscription_ids.insert(subscription.id, subscription_id);
+                        info.subscriptions.insert(subscription_id, subscription);
+                    },
+                    None => {
+                        warn!("Recieved a subscribed notification for a subscription we don't have.  ID: {}", request_id);
+                    }
+                }
             }
         }
     }
@@ -503,7 +1219,15 @@ impl ConnectionHandler {
                 promise.fail(CallError::new(reason, args, kwargs));
             },
             None => {
-                warn!("Recieved a an error notification for a request we didn't make.  ID: {}", request_id);
+                match info.resubscription_requests.remove(&request_id) {
+                    Some(subscription) => {
+                        warn!("Could not restore subscription to {:?}: {:?}", subscription.topic, reason);
+                        info.subscription_ids.remove(&subscription.id);
+                    },
+                    None => {
+                        warn!("Recieved a an error notification for a request we didn't make.  ID: {}", request_id);
+                    }
+                }
             }
         }
     }
@@ -511,7 +1235,8 @@ impl ConnectionHandler {
     fn handle_unsubscribed(&self, mut info: MutexGuard<ConnectionInfo>, request_id: ID) {
         match info.unsubscription_requests.remove(&request_id) {
             Some((promise, subscription_id)) => {
-                info.unsubscription_requests.remove(&subscription_id);
+                info.subscriptions.remove(&subscription_id);
+                info.subscription_ids.retain(|_, current_id| *current_id != subscription_id);
                 drop(info);
                 promise.complete(())
             },
@@ -539,13 +1264,24 @@ impl ConnectionHandler {
         info!("Recieved a registered notification");
         match info.registration_requests.remove(&request_id) {
             Some((promise, callback, procedure)) => {
+                let mut callback = callback;
+                callback.id = registration_id;
                 info.registrations.insert(registration_id, callback);
                 drop(info);
                 let registration = Registration{procedure: procedure, registration_id: registration_id};
                 promise.complete(registration)
             },
             None => {
-                warn!("Recieved a registered notification for a registration we don't have.  ID: {}", request_id);
+                match info.reregistration_requests.remove(&request_id) {
+                    Some(registration) => {
+                        debug!("Restored registration of {:?}", registration.procedure);
+                        info.registration_ids.insert(registration.id, registration_id);
+                        info.registrations.insert(registration_id, registration);
+                    },
+                    None => {
+                        warn!("Recieved a registered notification for a registration we don't have.  ID: {}", request_id);
+                    }
+                }
             }
         }
     }
@@ -558,7 +1294,15 @@ impl ConnectionHandler {
                 promise.fail(CallError::new(reason, args, kwargs))
             },
             None => {
-                warn!("Recieved a registered error for a registration we don't have.  ID: {}", request_id);
+                match info.reregistration_requests.remove(&request_id) {
+                    Some(registration) => {
+                        warn!("Could not restore registration of {:?}: {:?}", registration.procedure, reason);
+                        info.registration_ids.remove(&registration.id);
+                    },
+                    None => {
+                        warn!("Recieved a registered error for a registration we don't have.  ID: {}", request_id);
+                    }
+                }
             }
         }
     }
@@ -567,6 +1311,7 @@ impl ConnectionHandler {
         match info.unregistration_requests.remove(&request_id) {
             Some((promise, registration_id)) => {
                 info.registrations.remove(&registration_id);
+                info.registration_ids.retain(|_, current_id| *current_id != registration_id);
                 drop(info);
                 promise.complete(())
             },
@@ -609,11 +1354,13 @@ impl ConnectionHandler {
         }
     }
 
-    fn handle_welcome(&self, mut info: MutexGuard<ConnectionInfo>, session_id: ID, _details: WelcomeDetails) {
+    fn handle_welcome(&self, mut info: MutexGuard<ConnectionInfo>, session_id: ID, details: WelcomeDetails) {
         // todo cancel timeout
         info.session_id = session_id;
         info.connection_state = ConnectionState::Connected;
+        let callbacks = info.callbacks.clone();
         drop(info);
+        callbacks.join(session_id, &details);
         self.state_transmission.send(Ok(self.connection_info.clone())).unwrap();
     }
 
@@ -636,14 +1383,17 @@ impl ConnectionHandler {
         let kwargs = kwargs.unwrap_or(HashMap::new());
         let message = match info.registrations.get_mut(&registration_id) {
             Some(registration) => {
-                let ref mut callback = registration.callback;
-                match callback(args, kwargs) {
-                        Ok((rargs, rkwargs)) => {
-                            Message::Yield(request_id, YieldOptions::new(), rargs, rkwargs)
-                        }, Err(error) => {
-                            let (reason, args, kwargs) = error.to_tuple();
-                            Message::Error(ErrorType::Invocation, request_id, HashMap::new(), reason, args, kwargs)
-                        }
+                match registration.callback {
+                    RegistrationCallback::Immediate(ref mut callback) => {
+                        invocation_result(request_id, callback(args, kwargs))
+                    },
+                    RegistrationCallback::Deferred(ref mut callback) => {
+                        callback(args, kwargs, InvocationReply {
+                            request_id: request_id,
+                            connection_info: self.connection_info.clone()
+                        });
+                        return;
+                    }
                 }
             },
             None => {
@@ -683,7 +1433,9 @@ impl ConnectionHandler {
 
         info.send_message(Message::Goodbye(ErrorDetails::new(), Reason::GoodbyeAndOut)).unwrap();
         info.connection_state = ConnectionState::ShuttingDown;
-
+        let callbacks = info.callbacks.clone();
+        drop(info);
+        callbacks.leave(&reason);
     }
 
     fn handle_error(&self, info: MutexGuard<ConnectionInfo>, e_type: ErrorType, request_id: ID, _details: Dict, reason: Reason, args: Option<List>, kwargs: Option<Dict>) {
@@ -716,15 +1468,14 @@ impl ConnectionHandler {
 
 impl Client {
     fn get_next_session_id(&mut self) -> ID {
-        self.max_session_id += 1;
-        self.max_session_id
+        self.connection_info.lock().unwrap().next_request_id()
     }
 
-    pub fn subscribe_with_pattern(&mut self, topic_pattern: URI, callback: Box<FnMut(List, Dict)>, policy: MatchingPolicy) -> WampResult<Future<Subscription, CallError>> {
+    pub fn subscribe_with_pattern(&mut self, topic_pattern: URI, callback: Box<FnMut(List, Dict) + Send>, policy: MatchingPolicy) -> WampResult<Future<Subscription, CallError>> {
         // Send a subscribe messages
         let request_id = self.get_next_session_id();
         let (complete, future) = Future::<Subscription, CallError>::pair();
-        let callback = SubscriptionCallbackWrapper {callback: callback};
+        let callback = SubscriptionCallbackWrapper {callback: callback, topic: topic_pattern.clone(), policy: policy, id: 0};
         let mut options = SubscribeOptions::new();
         
         if policy != MatchingPolicy::Strict {
@@ -737,15 +1488,26 @@ impl Client {
         Ok(future)
     }
 
-    pub fn subscribe(&mut self, topic: URI, callback: Box<FnMut(List, Dict)>) -> WampResult<Future<Subscription, CallError>> {
+    pub fn subscribe(&mut self, topic: URI, callback: Box<FnMut(List, Dict) + Send>) -> WampResult<Future<Subscription, CallError>> {
         self.subscribe_with_pattern(topic, callback, MatchingPolicy::Strict)
     }
 
-    pub fn register_with_pattern(&mut self, procedure_pattern: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> >, policy: MatchingPolicy) -> WampResult<Future<Registration, CallError>> {
+    pub fn register_with_pattern(&mut self, procedure_pattern: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> + Send>, policy: MatchingPolicy) -> WampResult<Future<Registration, CallError>> {
+        self.register_callback(procedure_pattern, RegistrationCallback::Immediate(callback), policy)
+    }
+
+    /// Registers a procedure whose callback sends its result later with the `InvocationReply`
+    /// it is given, rather than returning it.  The callback is called while the connection is
+    /// locked, so it must not send the reply before it returns.
+    pub(crate) fn register_deferred(&mut self, procedure_pattern: URI, callback: Box<FnMut(List, Dict, InvocationReply) + Send>, policy: MatchingPolicy) -> WampResult<Future<Registration, CallError>> {
+        self.register_callback(procedure_pattern, RegistrationCallback::Deferred(callback), policy)
+    }
+
+    fn register_callback(&mut self, procedure_pattern: URI, callback: RegistrationCallback, policy: MatchingPolicy) -> WampResult<Future<Registration, CallError>> {
         // Send a register messages
         let request_id = self.get_next_session_id();
         let (complete, future) = Future::<Registration, CallError>::pair();
-        let callback = RegistrationCallbackWrapper {callback: callback};
+        let callback = RegistrationCallbackWrapper {callback: callback, procedure: procedure_pattern.clone(), policy: policy, id: 0};
         let mut options = RegisterOptions::new();
         if policy != MatchingPolicy::Strict {
             options.pattern_match = policy
@@ -758,26 +1520,28 @@ impl Client {
         Ok(future)
     }
 
-    pub fn register(&mut self, procedure: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> >) -> WampResult<Future<Registration, CallError>> {
+    pub fn register(&mut self, procedure: URI, callback: Box<FnMut(List, Dict) -> CallResult<(Option<List>, Option<Dict>)> + Send>) -> WampResult<Future<Registration, CallError>> {
         self.register_with_pattern(procedure, callback, MatchingPolicy::Strict)
     }
 
     pub fn unsubscribe(&mut self, subscription: Subscription) -> WampResult<Future<(), CallError>> {
         let request_id = self.get_next_session_id();
         let mut info = self.connection_info.lock().unwrap();
-        try!(info.send_message(Message::Unsubscribe(request_id, subscription.subscription_id)));
+        let subscription_id = *info.subscription_ids.get(&subscription.subscription_id).unwrap_or(&subscription.subscription_id);
+        try!(info.send_message(Message::Unsubscribe(request_id, subscription_id)));
         let (complete, future) = Future::<(), CallError>::pair();
-        info.unsubscription_requests.insert(request_id, (complete, subscription.subscription_id));
+        info.unsubscription_requests.insert(request_id, (complete, subscription_id));
         Ok(future)
     }
 
     pub fn unregister(&mut self, registration: Registration) -> WampResult<Future<(), CallError>> {
         let request_id = self.get_next_session_id();
         let mut info = self.connection_info.lock().unwrap();
-        try!(info.send_message(Message::Unregister(request_id, registration.registration_id)));
+        let registration_id = *info.registration_ids.get(&registration.registration_id).unwrap_or(&registration.registration_id);
+        try!(info.send_message(Message::Unregister(request_id, registration_id)));
         let (complete, future) = Future::<(), CallError>::pair();
 
-        info.unregistration_requests.insert(request_id, (complete, registration.registration_id));
+        info.unregistration_requests.insert(request_id, (complete, registration_id));
         Ok(future)
     }
 
@@ -799,6 +1563,41 @@ impl Client {
         Ok(future)
     }
 
+    /// Registers a procedure whose arguments are decoded into `Args` and whose result is encoded
+    /// from `Ret`.
+    ///
+    /// Sequences and tuples are decoded from the positional arguments of the call, and any other
+    /// type from its single positional argument.  Structs and maps can also be passed as keyword
+    /// arguments.  If the arguments can't be decoded, the caller receives a
+    /// `wamp.error.invalid_argument` error without the callback being invoked.
+    pub fn register_typed<Args, Ret, F>(&mut self, procedure: URI, callback: F) -> WampResult<Future<Registration, CallError>>
+        where Args: for<'de> Deserialize<'de>, Ret: Serialize, F: Fn(Args) -> CallResult<Ret> + Send + 'static {
+        self.register(procedure, Box::new(move |args, kwargs| {
+            let args = try!(from_args(args, kwargs).map_err(|e| {
+                CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Could not decode arguments: {}", e.get_kind().description()))]), None)
+            }));
+            let result = try!(callback(args));
+            to_args(&result).map_err(|e| {
+                CallError::new(Reason::CustomReason(URI::new("wamp.error.invalid_result")), Some(vec![Value::String(format!("Could not encode result: {}", e.get_kind().description()))]), None)
+            })
+        }))
+    }
+
+    /// Calls a procedure with arguments encoded from `args`, and decodes its result into `Ret`.
+    ///
+    /// Sequences and tuples are sent as the positional arguments, and any other value as a single
+    /// positional argument.  The result is decoded the same way.
+    pub fn call_typed<Args, Ret>(&mut self, procedure: URI, args: &Args) -> WampResult<Future<Ret, CallError>>
+        where Args: Serialize, Ret: for<'de> Deserialize<'de> + Send + 'static {
+        let (args, kwargs) = try!(to_args(args));
+        let future = try!(self.call(procedure, args, kwargs));
+        Ok(future.and_then(|(args, kwargs)| {
+            from_args(args, kwargs).map_err(|e| {
+                CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Could not decode result: {}", e.get_kind().description()))]), None)
+            })
+        }))
+    }
+
     pub fn publish_and_acknowledge(&mut self, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<Future<ID, CallError>> {
         info!("Publishing to {:?} with {:?} | {:?}", topic, args, kwargs);
         let request_id = self.get_next_session_id();
@@ -818,6 +1617,14 @@ impl Client {
             // TODO add timeout in case server doesn't respond.
             try!(info.send_message(Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown)));
             Ok(future)
+        } else if info.connection_state == ConnectionState::Reconnecting || info.connection_state == ConnectionState::Connecting {
+            // There is no session to leave, so just stop trying to restore it.  A session that is
+            // still being opened is closed as soon as the router welcomes the client.
+            info.reconnect = None;
+            if info.connection_state == ConnectionState::Reconnecting {
+                info.connection_state = ConnectionState::Disconnected;
+            }
+            Ok(Future::of(()))
         } else {
             Err(Error::new(ErrorKind::InvalidState("Tried to shut down a client that was already shutting down")))
         }
@@ -838,3 +1645,257 @@ impl fmt::Debug for ConnectionHandler {
         write!(f, "{{Connection id: {}}}", self.connection_info.lock().unwrap().session_id)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Connection, ReconnectPolicy, ReconnectEvent};
+    use router::Router;
+    use messages::{URI, Value, Reason};
+    use ::CallError;
+    use eventual::{Async, AsyncError};
+    use std::collections::HashMap;
+    use std::time::Duration;
+    #[cfg(unix)]
+    use std::{env, io, process, thread};
+    #[cfg(unix)]
+    use std::net::Shutdown;
+    #[cfg(unix)]
+    use std::os::unix::net::{UnixListener, UnixStream};
+    #[cfg(unix)]
+    use std::path::{Path, PathBuf};
+    #[cfg(unix)]
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+
+    /// Forwards connections to a router's Unix socket, so that they can be cut without the router
+    /// knowing to say goodbye first.
+    #[cfg(unix)]
+    struct Proxy {
+        streams: Arc<Mutex<Vec<UnixStream>>>
+    }
+
+    #[cfg(unix)]
+    impl Proxy {
+        fn new(path: &Path, target: PathBuf) -> Proxy {
+            let listener = UnixListener::bind(path).unwrap();
+            let streams = Arc::new(Mutex::new(Vec::new()));
+            let proxy_streams = streams.clone();
+            thread::spawn(move || {
+                for client in listener.incoming() {
+                    let client = match client { Ok(client) => client, Err(_) => break };
+                    // Without a router to forward to, the client is disconnected straight away
+                    let router = match UnixStream::connect(&target) { Ok(router) => router, Err(_) => continue };
+                    let mut streams = proxy_streams.lock().unwrap();
+                    streams.push(client.try_clone().unwrap());
+                    streams.push(router.try_clone().unwrap());
+                    Proxy::forward(client.try_clone().unwrap(), router.try_clone().unwrap());
+                    Proxy::forward(router, client);
+                }
+            });
+            Proxy {
+                streams: streams
+            }
+        }
+
+        fn forward(mut from: UnixStream, mut to: UnixStream) {
+            thread::spawn(move || {
+                io::copy(&mut from, &mut to).ok();
+                to.shutdown(Shutdown::Both).ok();
+            });
+        }
+
+        /// Closes every forwarded connection.
+        fn cut(&self) {
+            for stream in self.streams.lock().unwrap().drain(..) {
+                stream.shutdown(Shutdown::Both).ok();
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Rectangle {
+        width: i64,
+        height: i64
+    }
+
+    #[test]
+    fn reconnect_delay() {
+        let policy = ReconnectPolicy {
+            initial_delay: 100,
+            max_delay: 1000,
+            multiplier: 3.0,
+            jitter: 0.0,
+            max_attempts: Some(5)
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(300));
+        assert_eq!(policy.delay(3), Duration::from_millis(900));
+        assert_eq!(policy.delay(4), Duration::from_millis(1000));
+
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for attempt in 1..5 {
+            let delay = policy.delay(attempt);
+            assert!(delay <= Duration::from_millis(1000));
+            assert!(delay >= Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn typed_call() {
+        let mut router = Router::new();
+        router.add_realm("typed_realm");
+
+        let mut callee = Connection::new_local(&router, "typed_realm").connect().unwrap();
+        callee.register_typed(URI::new("ca.test.area"), |rectangle: Rectangle| -> Result<i64, CallError> {
+            Ok(rectangle.width * rectangle.height)
+        }).unwrap().await().unwrap();
+        callee.register_typed(URI::new("ca.test.double"), |values: Vec<i64>| -> Result<Vec<i64>, CallError> {
+            Ok(values.into_iter().map(|value| value * 2).collect())
+        }).unwrap().await().unwrap();
+
+        let mut caller = Connection::new_local(&router, "typed_realm").connect().unwrap();
+        let area: i64 = caller.call_typed(URI::new("ca.test.area"), &Rectangle { width: 3, height: 4 }).unwrap().await().unwrap();
+        assert_eq!(area, 12);
+
+        // Structs can also be passed as keyword arguments by untyped callers
+        let mut kwargs = HashMap::new();
+        kwargs.insert("width".to_string(), Value::Integer(2));
+        kwargs.insert("height".to_string(), Value::Integer(5));
+        let (args, _) = caller.call(URI::new("ca.test.area"), None, Some(kwargs)).unwrap().await().unwrap();
+        assert_eq!(args, vec![Value::Integer(10)]);
+
+        // A struct is never decoded from several positional arguments
+        match caller.call_typed::<_, i64>(URI::new("ca.test.area"), &(3, 4)).unwrap().await() {
+            Err(AsyncError::Failed(error)) => assert_eq!(error.get_reason(), &Reason::InvalidArgument),
+            result => panic!("Expected an invalid argument error, got {:?}", result)
+        }
+
+        // Sequences are always the positional arguments, however many there are
+        let doubled: Vec<i64> = caller.call_typed(URI::new("ca.test.double"), &vec![1, 2]).unwrap().await().unwrap();
+        assert_eq!(doubled, vec![2, 4]);
+        let doubled: Vec<i64> = caller.call_typed(URI::new("ca.test.double"), &vec![21]).unwrap().await().unwrap();
+        assert_eq!(doubled, vec![42]);
+        let doubled: Vec<i64> = caller.call_typed(URI::new("ca.test.double"), &Vec::<i64>::new()).unwrap().await().unwrap();
+        assert!(doubled.is_empty());
+
+        caller.shutdown().unwrap().await().unwrap();
+        callee.shutdown().unwrap().await().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reconnect_to_restarted_router() {
+        let target = env::temp_dir().join(format!("wamp-reconnect-{}.sock", process::id()));
+        let path = env::temp_dir().join(format!("wamp-reconnect-proxy-{}.sock", process::id()));
+        let proxy = Proxy::new(&path, target.clone());
+
+        let mut router = Router::new();
+        router.add_realm("reconnect_realm");
+        let listener = router.listen_unix(&target).unwrap();
+
+        let mut connection = Connection::new_unix(&path, "reconnect_realm");
+        connection.set_reconnect_policy(ReconnectPolicy {
+            initial_delay: 50,
+            max_delay: 200,
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: Some(50)
+        });
+        let (event_tx, event_rx) = channel();
+        connection.set_reconnect_callback(Box::new(move |event| {
+            event_tx.send(event).ok();
+        }));
+        let mut client = connection.connect().unwrap();
+        let (tx, rx) = channel();
+        let subscription = client.subscribe(URI::new("ca.test.topic"), Box::new(move |args, _kwargs| {
+            tx.send(args).unwrap();
+        })).unwrap().await().unwrap();
+
+        let mut publisher = Connection::new_local(&router, "reconnect_realm").connect().unwrap();
+        publisher.publish(URI::new("ca.test.topic"), Some(vec![Value::Integer(1)]), None).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), vec![Value::Integer(1)]);
+
+        // Lose the connection, and bring up a new router with subscription ids of its own
+        listener.stop();
+        proxy.cut();
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(1)).unwrap(), ReconnectEvent::Disconnected);
+        let mut router = Router::new();
+        router.add_realm("reconnect_realm");
+        let _listener = router.listen_unix(&target).unwrap();
+        loop {
+            match event_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                ReconnectEvent::Reconnected(_) => break,
+                ReconnectEvent::GaveUp => panic!("The client gave up reconnecting"),
+                _ => {}
+            }
+        }
+
+        // The subscription may not have been restored yet, so keep publishing until it has
+        let mut publisher = Connection::new_local(&router, "reconnect_realm").connect().unwrap();
+        let mut received = None;
+        for _ in 0..50 {
+            publisher.publish(URI::new("ca.test.topic"), Some(vec![Value::Integer(2)]), None).unwrap();
+            if let Ok(args) = rx.recv_timeout(Duration::from_millis(100)) {
+                received = Some(args);
+                break;
+            }
+        }
+        assert_eq!(received, Some(vec![Value::Integer(2)]));
+
+        // The subscription handed out by the first session still refers to the restored one
+        client.unsubscribe(subscription).unwrap().await().unwrap();
+        publisher.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::Integer(3)]), None).unwrap().await().unwrap();
+        while let Ok(args) = rx.recv_timeout(Duration::from_millis(200)) {
+            assert_eq!(args, vec![Value::Integer(2)]);
+        }
+
+        client.shutdown().unwrap().await().unwrap();
+        publisher.shutdown().unwrap().await().unwrap();
+        proxy.cut();
+        ::std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn session_callbacks() {
+        let mut router = Router::new();
+        router.add_realm("callback_realm");
+
+        let (tx, rx) = channel();
+        let mut connection = Connection::new_local(&router, "callback_realm");
+        let join_tx = tx.clone();
+        connection.on_join(Box::new(move |session_id, _details| {
+            join_tx.send(format!("join {}", session_id)).unwrap();
+        }));
+        let leave_tx = tx.clone();
+        connection.on_leave(Box::new(move |reason| {
+            leave_tx.send(format!("leave {}", reason)).unwrap();
+        }));
+        let disconnect_tx = tx.clone();
+        connection.on_disconnect(Box::new(move || {
+            disconnect_tx.send("disconnect".to_string()).unwrap();
+        }));
+        let failure_tx = tx.clone();
+        connection.on_connect_failure(Box::new(move |_error| {
+            failure_tx.send("failure".to_string()).unwrap();
+        }));
+
+        let client = connection.connect().unwrap();
+        let session_id = client.connection_info.lock().unwrap().session_id;
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), format!("join {}", session_id));
+
+        // Closing the realm makes the router end the session with a goodbye
+        assert!(router.close_realm("callback_realm"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), format!("leave {}", Reason::CloseRealm));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), "disconnect");
+        assert!(client.is_closed());
+
+        // The realm is gone, so the router doesn't welcome the client
+        connection.set_timeout(1000);
+        assert!(connection.connect().is_err());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)).unwrap(), "failure");
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate serde;
 extern crate serde_json;
 #[macro_use]
@@ -6,28 +7,52 @@ extern crate ws;
 extern crate url;
 extern crate rmp;
 extern crate rmp_serde;
+extern crate serde_cbor;
 extern crate rand;
+extern crate base64;
 extern crate eventual;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate bytes;
+#[cfg(feature = "ssl")]
+extern crate openssl;
+#[cfg(feature = "ssl")]
+extern crate mio;
 
 #[macro_use]
 extern crate log;
 
 mod messages;
 mod utils;
+mod transport;
+pub mod serializer;
 pub mod client;
+#[cfg(feature = "async")]
+pub mod async_client;
 pub mod router;
 
 use ws::Error as WSError;
 use std::fmt;
+use std::error::Error as StdError;
 use url::ParseError;
 use std::sync::mpsc::SendError;
 use serde_json::Error as JSONError;
 use rmp_serde::decode::Error as MsgPackError;
+use serde_cbor::error::Error as CBORError;
 
-pub use messages::{URI, Dict, List, Value, Reason, MatchingPolicy, InvocationPolicy, CallError, ArgList, ArgDict};
-use messages::{ErrorType, Message};
-pub use client::{Client, Connection};
-pub use router::Router;
+pub use messages::{URI, Dict, List, Value, Reason, MatchingPolicy, InvocationPolicy, CallError, ArgList, ArgDict, ArgSpec, ArgType, WelcomeDetails, to_value, from_value, json_to_list, json_to_dict, list_to_json, dict_to_json};
+use messages::ErrorType;
+pub use messages::Message;
+pub use serializer::Serializer;
+pub use client::{Client, Connection, ReconnectPolicy, ReconnectEvent};
+pub use router::{Router, Listener, ListenerAddress, ListenerConfig, Endpoint, SlowConsumerPolicy, RateLimit, LimitedOperation};
+#[cfg(feature = "async")]
+pub use async_client::{AsyncClient, EventStream};
+#[cfg(feature = "ssl")]
+pub use transport::tls::{TlsServerConfig, TlsClientConfig};
 
 pub type CallResult<T> = Result<T, CallError>;
 pub type WampResult<T> = Result<T, Error>;
@@ -48,7 +73,9 @@ pub enum ErrorKind {
     Closing(String),
     JSONError(JSONError),
     MsgPackError(MsgPackError),
+    CBORError(CBORError),
     MalformedData,
+    SerializationError(String),
     InvalidMessageType(Message),
     InvalidState(&'static str),
     Timeout,
@@ -71,6 +98,11 @@ impl Error {
     }
 }
 
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::new(kind)
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -78,6 +110,12 @@ impl fmt::Display for Error {
     }
 }
 
+impl StdError for Error {
+    fn description(&self) -> &str {
+        "WAMP Error"
+    }
+}
+
 impl ErrorKind {
     fn description(&self) -> String {
         match self {
@@ -89,7 +127,9 @@ impl ErrorKind {
             &ErrorKind::Closing(ref s) => s.clone(),
             &ErrorKind::JSONError(ref e) => e.to_string(),
             &ErrorKind::MsgPackError(ref e) => e.to_string(),
+            &ErrorKind::CBORError(ref e) => e.to_string(),
             &ErrorKind::MalformedData => "Malformed Data".to_string(),
+            &ErrorKind::SerializationError(ref s) => s.clone(),
             &ErrorKind::InvalidMessageType(ref t) => format!("Invalid Message Type: {:?}", t),
             &ErrorKind::InvalidState(ref s) => s.to_string(),
             &ErrorKind::Timeout => "Connection timed out".to_string(),
@@ -14,7 +14,7 @@ macro_rules! try_or {
     );
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Message {
     Hello(URI, HelloDetails),
     Welcome(ID, WelcomeDetails),
@@ -335,11 +335,16 @@ mod test {
         Value,
         EventDetails,
         InvocationDetails,
-        ResultDetails
+        ResultDetails,
+        ArgList,
+        ArgDict,
+        ArgSpec,
+        ArgType
     };
     use utils::StructMapWriter;
     use std::collections::{HashMap};
     use serde_json;
+    use serde_cbor;
     use rmp_serde::Deserializer as RMPDeserializer;
     use rmp_serde::Serializer;
     use serde::{Deserialize, Serialize};
@@ -356,6 +361,8 @@ mod test {
             let mut de = RMPDeserializer::new(&buf[..]);
             let new_message: Message = Deserialize::deserialize(&mut de).unwrap();
             assert_eq!(new_message, message);
+            let buf = serde_cbor::to_vec(&message).unwrap();
+            assert_eq!(serde_cbor::from_slice::<Message>(&buf).unwrap(), message);
         }
         );
     }
@@ -476,6 +483,10 @@ mod test {
         two_way_test!(
             Message::Publish(3243542, PublishOptions::new(true), URI::new("ca.dal.test.topic3"), Some(Vec::new()), Some(kwargs)),
             "[16,3243542,{\"acknowledge\":true},\"ca.dal.test.topic3\",[],{\"key1\":[5]}]"
+        );
+        two_way_test!(
+            Message::Publish(3243543, PublishOptions::new(false), URI::new("ca.dal.test.topic4"), Some(vec![Value::UInteger(18446744073709551615), Value::Integer(9223372036854775807), Value::Integer(-5)]), None),
+            "[16,3243543,{},\"ca.dal.test.topic4\",[18446744073709551615,9223372036854775807,-5]]"
         )
     }
 
@@ -614,4 +625,64 @@ mod test {
         )
     }
 
+    #[test]
+    fn arg_accessors() {
+        let mut options = HashMap::new();
+        options.insert("verbose".to_string(), Value::Boolean(true));
+        options.insert("scale".to_string(), Value::Integer(2));
+        let args = vec![Value::String("name".to_string()), Value::Float(0.5), Value::List(vec![]), Value::Dict(options.clone())];
+
+        assert_eq!(args.require_string(0).unwrap(), "name");
+        assert_eq!(args.require_float(1).unwrap(), 0.5);
+        assert_eq!(args.require_list(2).unwrap(), &vec![]);
+        assert_eq!(args.get_bool(4).unwrap(), None);
+        assert!(args.get_bool(0).is_err());
+        assert_eq!(args.require_int(4).unwrap_err().get_reason(), &Reason::InvalidArgument);
+
+        assert_eq!(options.require_bool("verbose").unwrap(), true);
+        assert_eq!(options.require_float("scale").unwrap(), 2.0);
+        assert!(options.require_dict("missing").is_err());
+
+        assert!(args.verify(&[
+            ArgSpec::required("name", ArgType::String),
+            ArgSpec::required("ratio", ArgType::Float),
+            ArgSpec::optional("items", ArgType::List),
+            ArgSpec::required("options", ArgType::Dict),
+            ArgSpec::optional("extra", ArgType::Any)
+        ]).is_ok());
+        assert!(args.verify(&[ArgSpec::required("name", ArgType::Integer)]).is_err());
+        assert!(options.verify(&[ArgSpec::required("verbose", ArgType::Boolean), ArgSpec::optional("count", ArgType::Integer)]).is_ok());
+        assert!(options.verify(&[ArgSpec::required("count", ArgType::Integer)]).is_err());
+
+        // An unsigned argument that passes verification can be read with the same accessor
+        let mut counters = HashMap::new();
+        counters.insert("count".to_string(), Value::UInteger(7));
+        counters.insert("total".to_string(), Value::UInteger(18446744073709551615));
+        assert!(counters.verify(&[ArgSpec::required("count", ArgType::Integer)]).is_ok());
+        assert_eq!(counters.require_int("count").unwrap(), 7);
+        assert_eq!(counters.get_int("count").unwrap(), Some(7));
+        assert!(counters.verify(&[ArgSpec::required("total", ArgType::Integer)]).is_err());
+        assert_eq!(counters.require_int("total").unwrap_err().get_reason(), &Reason::InvalidArgument);
+        assert!(counters.verify(&[ArgSpec::required("total", ArgType::Float)]).is_ok());
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        use serializer::{Serializer, JsonSerializer, MsgPackSerializer, CborSerializer};
+
+        let values = vec![Value::UInteger(9223372036854775808), Value::UInteger(18446744073709551615), Value::Integer(9223372036854775807), Value::Integer(-9223372036854775808)];
+        let message = Message::Publish(1, PublishOptions::new(false), URI::new("ca.dal.test.topic"), Some(values), None);
+        for serializer in [&JsonSerializer as &Serializer, &MsgPackSerializer, &CborSerializer].iter() {
+            let payload = serializer.serialize(&message).unwrap();
+            assert_eq!(serializer.deserialize(&payload).unwrap(), message);
+        }
+
+        // Unsigned values that fit in an i64 can be read as integers, whichever variant holds them
+        let args = vec![Value::UInteger(42), Value::UInteger(18446744073709551615)];
+        assert_eq!(args.require_int(0).unwrap(), 42);
+        assert!(args.get_int(1).is_err());
+        assert!(args.verify(&[ArgSpec::required("small", ArgType::Integer)]).is_ok());
+        assert!(args.verify(&[ArgSpec::optional("small", ArgType::Integer), ArgSpec::required("large", ArgType::Integer)]).is_err());
+        assert_eq!(args.require_float(1).unwrap(), 18446744073709551615.0);
+    }
 }
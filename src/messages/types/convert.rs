@@ -0,0 +1,672 @@
+//! Converts between `Value` and any type implementing `Serialize` or `Deserialize`, so that
+//! structs can be used as arguments and results without walking the `List` or `Dict` by hand.
+use std::collections::HashMap;
+use std::fmt::Display;
+use serde::{self, Serialize, Deserialize};
+use serde::ser::{self, Impossible};
+use serde::de::{self, Visitor, IntoDeserializer, DeserializeSeed};
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use super::{Value, Dict, List};
+use ::{WampResult, Error, ErrorKind};
+
+/// Converts anything that can be serialized into a `Value`.
+///
+/// Structs and maps become `Value::Dict`s, and sequences and tuples become `Value::List`s.
+/// Map keys must serialize to strings.
+pub fn to_value<T: ?Sized + Serialize>(value: &T) -> WampResult<Value> {
+    value.serialize(ValueSerializer)
+}
+
+/// Converts a `Value` into anything that can be deserialized.
+pub fn from_value<T>(value: Value) -> WampResult<T> where T: for<'de> Deserialize<'de> {
+    T::deserialize(value)
+}
+
+/// Converts anything that can be serialized into the positional and keyword arguments of a
+/// message.
+///
+/// Sequences and tuples become the positional arguments, and any other value becomes a single
+/// positional argument.  Unit values and `None` are sent without any arguments.
+pub(crate) fn to_args<T: Serialize>(value: &T) -> WampResult<(Option<List>, Option<Dict>)> {
+    Ok(match try!(to_value(value)) {
+        Value::List(list) => (Some(list), None),
+        Value::Null => (None, None),
+        value => (Some(vec![value]), None)
+    })
+}
+
+/// Converts the positional and keyword arguments of a message into anything that can be
+/// deserialized, undoing `to_args`.
+///
+/// Which arguments are used depends on what the type expects rather than on what was received:
+/// sequences and tuples are read from the positional arguments, and any other type from the
+/// single positional argument.  Structs and maps are read from the keyword arguments instead if
+/// there are no positional arguments, so that they can be passed either way.
+pub(crate) fn from_args<T>(args: List, kwargs: Dict) -> WampResult<T> where T: for<'de> Deserialize<'de> {
+    T::deserialize(Arguments {
+        args: args,
+        kwargs: kwargs
+    })
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::new(ErrorKind::SerializationError(msg.to_string()))
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::new(ErrorKind::SerializationError(msg.to_string()))
+    }
+}
+
+/*-------------------------
+       Serialization
+-------------------------*/
+
+struct ValueSerializer;
+
+struct SerializeList {
+    list: Vec<Value>
+}
+
+struct SerializeTupleVariant {
+    variant: &'static str,
+    list: Vec<Value>
+}
+
+struct SerializeDict {
+    dict: Dict,
+    next_key: Option<String>
+}
+
+struct SerializeStructVariant {
+    variant: &'static str,
+    dict: Dict
+}
+
+fn variant_dict(variant: &'static str, value: Value) -> Value {
+    let mut dict = HashMap::new();
+    dict.insert(variant.to_string(), value);
+    Value::Dict(dict)
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeTupleVariant;
+    type SerializeMap = SerializeDict;
+    type SerializeStruct = SerializeDict;
+    type SerializeStructVariant = SerializeStructVariant;
+
+    fn serialize_bool(self, v: bool) -> WampResult<Value> {
+        Ok(Value::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> WampResult<Value> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> WampResult<Value> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> WampResult<Value> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> WampResult<Value> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> WampResult<Value> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> WampResult<Value> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> WampResult<Value> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> WampResult<Value> {
+        if v > ::std::i64::MAX as u64 {
+            Ok(Value::UInteger(v))
+        } else {
+            Ok(Value::Integer(v as i64))
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> WampResult<Value> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> WampResult<Value> {
+        Ok(Value::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> WampResult<Value> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> WampResult<Value> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> WampResult<Value> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> WampResult<Value> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> WampResult<Value> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> WampResult<Value> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> WampResult<Value> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str) -> WampResult<Value> {
+        Ok(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> WampResult<Value> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, _variant_index: u32, variant: &'static str, value: &T) -> WampResult<Value> {
+        Ok(variant_dict(variant, try!(value.serialize(self))))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> WampResult<SerializeList> {
+        Ok(SerializeList {
+            list: Vec::with_capacity(len.unwrap_or(0))
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> WampResult<SerializeList> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> WampResult<SerializeList> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, len: usize) -> WampResult<SerializeTupleVariant> {
+        Ok(SerializeTupleVariant {
+            variant: variant,
+            list: Vec::with_capacity(len)
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> WampResult<SerializeDict> {
+        Ok(SerializeDict {
+            dict: HashMap::new(),
+            next_key: None
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> WampResult<SerializeDict> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, _len: usize) -> WampResult<SerializeStructVariant> {
+        Ok(SerializeStructVariant {
+            variant: variant,
+            dict: HashMap::new()
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> WampResult<()> {
+        self.list.push(try!(to_value(value)));
+        Ok(())
+    }
+
+    fn end(self) -> WampResult<Value> {
+        Ok(Value::List(self.list))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> WampResult<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> WampResult<Value> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> WampResult<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> WampResult<Value> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTupleVariant {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> WampResult<()> {
+        self.list.push(try!(to_value(value)));
+        Ok(())
+    }
+
+    fn end(self) -> WampResult<Value> {
+        Ok(variant_dict(self.variant, Value::List(self.list)))
+    }
+}
+
+impl ser::SerializeMap for SerializeDict {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> WampResult<()> {
+        self.next_key = Some(try!(key.serialize(KeySerializer)));
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> WampResult<()> {
+        let key = self.next_key.take().expect("serialize_value called before serialize_key");
+        self.dict.insert(key, try!(to_value(value)));
+        Ok(())
+    }
+
+    fn end(self) -> WampResult<Value> {
+        Ok(Value::Dict(self.dict))
+    }
+}
+
+impl ser::SerializeStruct for SerializeDict {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> WampResult<()> {
+        self.dict.insert(key.to_string(), try!(to_value(value)));
+        Ok(())
+    }
+
+    fn end(self) -> WampResult<Value> {
+        Ok(Value::Dict(self.dict))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeStructVariant {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> WampResult<()> {
+        self.dict.insert(key.to_string(), try!(to_value(value)));
+        Ok(())
+    }
+
+    fn end(self) -> WampResult<Value> {
+        Ok(variant_dict(self.variant, Value::Dict(self.dict)))
+    }
+}
+
+/// Serializes the keys of a map, which must be strings (or unit variants, which are serialized
+/// as their name).
+struct KeySerializer;
+
+fn key_must_be_a_string() -> Error {
+    Error::new(ErrorKind::SerializationError("Dict keys must be strings".to_string()))
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    fn serialize_str(self, v: &str) -> WampResult<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_char(self, v: char) -> WampResult<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str) -> WampResult<String> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> WampResult<String> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_i8(self, _v: i8) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_i16(self, _v: i16) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_i32(self, _v: i32) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_i64(self, _v: i64) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_u8(self, _v: u8) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_u16(self, _v: u16) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_u32(self, _v: u32) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_u64(self, _v: u64) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_f32(self, _v: f32) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_f64(self, _v: f64) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_bytes(self, _v: &[u8]) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_none(self) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_some<T: ?Sized + Serialize>(self, _value: &T) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_unit(self) -> WampResult<String> { Err(key_must_be_a_string()) }
+    fn serialize_unit_struct(self, _name: &'static str) -> WampResult<String> { Err(key_must_be_a_string()) }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, _variant_index: u32, _variant: &'static str, _value: &T) -> WampResult<String> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> WampResult<Self::SerializeSeq> { Err(key_must_be_a_string()) }
+    fn serialize_tuple(self, _len: usize) -> WampResult<Self::SerializeTuple> { Err(key_must_be_a_string()) }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> WampResult<Self::SerializeTupleStruct> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, _variant: &'static str, _len: usize) -> WampResult<Self::SerializeTupleVariant> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> WampResult<Self::SerializeMap> { Err(key_must_be_a_string()) }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> WampResult<Self::SerializeStruct> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, _variant: &'static str, _len: usize) -> WampResult<Self::SerializeStructVariant> {
+        Err(key_must_be_a_string())
+    }
+}
+
+/*-------------------------
+      Deserialization
+-------------------------*/
+
+impl <'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> WampResult<V::Value> {
+        match self {
+            Value::Dict(dict) => {
+                let mut map = MapDeserializer::new(dict.into_iter());
+                let result = try!(visitor.visit_map(&mut map));
+                try!(map.end());
+                Ok(result)
+            },
+            Value::List(list) => {
+                let mut seq = SeqDeserializer::new(list.into_iter());
+                let result = try!(visitor.visit_seq(&mut seq));
+                try!(seq.end());
+                Ok(result)
+            },
+            Value::Integer(i) => visitor.visit_i64(i),
+            Value::UInteger(u) => visitor.visit_u64(u),
+            Value::String(s) => visitor.visit_string(s),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            Value::Float(f) => visitor.visit_f64(f),
+            Value::Null => visitor.visit_unit(),
+            Value::Bytes(b) => visitor.visit_byte_buf(b)
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> WampResult<V::Value> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            value => visitor.visit_some(value)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> WampResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> WampResult<V::Value> {
+        let (variant, value) = match self {
+            Value::String(variant) => (variant, Value::Null),
+            Value::Dict(dict) => {
+                if dict.len() != 1 {
+                    return Err(de::Error::invalid_length(dict.len(), &"a dict with a single key naming the variant"));
+                }
+                dict.into_iter().next().unwrap()
+            },
+            other => return Err(de::Error::invalid_type(unexpected(&other), &"a string or a dict naming the variant"))
+        };
+        visitor.visit_enum(EnumDeserializer {
+            variant: variant,
+            value: value
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl <'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+struct Arguments {
+    args: List,
+    kwargs: Dict
+}
+
+impl Arguments {
+    /// The single positional argument, or the keyword arguments if there are no positional ones.
+    fn single(self) -> WampResult<Value> {
+        match self.args.len() {
+            0 if self.kwargs.is_empty() => Ok(Value::Null),
+            0 => Ok(Value::Dict(self.kwargs)),
+            1 => Ok(self.args.into_iter().next().unwrap()),
+            len => Err(de::Error::invalid_length(len, &"a single argument"))
+        }
+    }
+}
+
+impl <'de> de::Deserializer<'de> for Arguments {
+    type Error = Error;
+
+    /// Types that accept anything get the positional arguments as a list if there are several.
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> WampResult<V::Value> {
+        if self.args.len() > 1 {
+            Value::List(self.args).deserialize_any(visitor)
+        } else {
+            try!(self.single()).deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> WampResult<V::Value> {
+        Value::List(self.args).deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> WampResult<V::Value> {
+        Value::List(self.args).deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, name: &'static str, len: usize, visitor: V) -> WampResult<V::Value> {
+        Value::List(self.args).deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> WampResult<V::Value> {
+        try!(self.single()).deserialize_map(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str], visitor: V) -> WampResult<V::Value> {
+        try!(self.single()).deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> WampResult<V::Value> {
+        try!(self.single()).deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> WampResult<V::Value> {
+        if self.args.is_empty() && self.kwargs.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> WampResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit
+        unit_struct identifier ignored_any
+    }
+}
+
+fn unexpected(value: &Value) -> de::Unexpected {
+    match *value {
+        Value::Dict(_) => de::Unexpected::Map,
+        Value::List(_) => de::Unexpected::Seq,
+        Value::Integer(i) => de::Unexpected::Signed(i),
+        Value::UInteger(u) => de::Unexpected::Unsigned(u),
+        Value::String(ref s) => de::Unexpected::Str(s),
+        Value::Boolean(b) => de::Unexpected::Bool(b),
+        Value::Float(f) => de::Unexpected::Float(f),
+        Value::Null => de::Unexpected::Unit,
+        Value::Bytes(ref b) => de::Unexpected::Bytes(b)
+    }
+}
+
+struct EnumDeserializer {
+    variant: String,
+    value: Value
+}
+
+impl <'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = Value;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> WampResult<(V::Value, Value)> {
+        let deserializer: de::value::StringDeserializer<Error> = self.variant.into_deserializer();
+        let variant = try!(seed.deserialize(deserializer));
+        Ok((variant, self.value))
+    }
+}
+
+impl <'de> de::VariantAccess<'de> for Value {
+    type Error = Error;
+
+    fn unit_variant(self) -> WampResult<()> {
+        match self {
+            Value::Null => Ok(()),
+            other => Err(de::Error::invalid_type(unexpected(&other), &"a unit variant"))
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> WampResult<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> WampResult<V::Value> {
+        serde::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> WampResult<V::Value> {
+        serde::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_value, from_value};
+    use messages::Value;
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rectangle { width: u32, height: u32 }
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Drawing {
+        name: String,
+        layer: Option<u8>,
+        shapes: Vec<Shape>,
+        checksum: u64
+    }
+
+    #[test]
+    fn round_trip() {
+        let drawing = Drawing {
+            name: "sketch".to_string(),
+            layer: None,
+            shapes: vec![Shape::Point, Shape::Circle(2.5), Shape::Rectangle { width: 3, height: 4 }],
+            checksum: 18446744073709551615
+        };
+        let value = to_value(&drawing).unwrap();
+
+        let mut rectangle = HashMap::new();
+        rectangle.insert("width".to_string(), Value::Integer(3));
+        rectangle.insert("height".to_string(), Value::Integer(4));
+        let mut variant = HashMap::new();
+        variant.insert("Rectangle".to_string(), Value::Dict(rectangle));
+        let mut circle = HashMap::new();
+        circle.insert("Circle".to_string(), Value::Float(2.5));
+        let mut expected = HashMap::new();
+        expected.insert("name".to_string(), Value::String("sketch".to_string()));
+        expected.insert("layer".to_string(), Value::Null);
+        expected.insert("shapes".to_string(), Value::List(vec![Value::String("Point".to_string()), Value::Dict(circle), Value::Dict(variant)]));
+        expected.insert("checksum".to_string(), Value::UInteger(18446744073709551615));
+        assert_eq!(value, Value::Dict(expected));
+
+        assert_eq!(from_value::<Drawing>(value).unwrap(), drawing);
+    }
+
+    #[test]
+    fn mismatched_types() {
+        assert_eq!(from_value::<(i64, String)>(Value::List(vec![Value::Integer(1), Value::String("a".to_string())])).unwrap(), (1, "a".to_string()));
+        assert!(from_value::<(i64, String)>(Value::List(vec![Value::Integer(1)])).is_err());
+        assert!(from_value::<u8>(Value::Integer(300)).is_err());
+        assert!(from_value::<Drawing>(Value::String("sketch".to_string())).is_err());
+
+        let mut map = HashMap::new();
+        map.insert(1, 2);
+        assert!(to_value(&map).is_err());
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt;
 use serde;
 use super::{List, Dict};
 
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum Reason {
     InvalidURI,
     NoSuchProcedure,
@@ -34,7 +34,7 @@ pub struct CallError {
     kwargs: Option<Dict>
 }
 
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum ErrorType {
     Subscribe,
     Unsubscribe,
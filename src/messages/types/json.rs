@@ -0,0 +1,116 @@
+//! Conversions between `Value` and `serde_json::Value`.
+//!
+//! JSON strings always become `Value::String`, so converting JSON to a `Value` and back gives the
+//! same JSON, and strings survive the trip the other way.  JSON has no binary type, so binary
+//! values are converted to the representation the JSON serializer uses: a string holding a NUL
+//! followed by the base64 encoded bytes.  These come back as that string.
+use std::convert::TryFrom;
+use serde_json::{Value as JSONValue, Map as JSONMap, Number};
+use base64;
+use super::{Value, Dict, List};
+use ::{WampResult, Error, ErrorKind};
+
+impl From<JSONValue> for Value {
+    fn from(value: JSONValue) -> Value {
+        match value {
+            JSONValue::Null => Value::Null,
+            JSONValue::Bool(b) => Value::Boolean(b),
+            JSONValue::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    Value::Integer(i)
+                } else if let Some(u) = n.as_u64() {
+                    Value::UInteger(u)
+                } else {
+                    Value::Float(n.as_f64().unwrap_or(0.0))
+                }
+            },
+            JSONValue::String(s) => Value::String(s),
+            JSONValue::Array(list) => Value::List(json_to_list(list)),
+            JSONValue::Object(map) => Value::Dict(json_to_dict(map))
+        }
+    }
+}
+
+/// Fails only for floats that are infinite or NaN, which JSON can't represent.
+impl TryFrom<Value> for JSONValue {
+    type Error = Error;
+
+    fn try_from(value: Value) -> WampResult<JSONValue> {
+        Ok(match value {
+            Value::Null => JSONValue::Null,
+            Value::Boolean(b) => JSONValue::Bool(b),
+            Value::Integer(i) => JSONValue::Number(i.into()),
+            Value::UInteger(u) => JSONValue::Number(u.into()),
+            Value::Float(f) => {
+                match Number::from_f64(f) {
+                    Some(n) => JSONValue::Number(n),
+                    None => return Err(Error::new(ErrorKind::SerializationError(format!("{} cannot be represented in JSON", f))))
+                }
+            },
+            Value::String(s) => JSONValue::String(s),
+            Value::Bytes(bytes) => JSONValue::String(format!("\0{}", base64::encode(&bytes))),
+            Value::List(list) => JSONValue::Array(try!(list_to_json(list))),
+            Value::Dict(dict) => JSONValue::Object(try!(dict_to_json(dict)))
+        })
+    }
+}
+
+/// Converts a JSON array into a `List`.
+pub fn json_to_list(list: Vec<JSONValue>) -> List {
+    list.into_iter().map(Value::from).collect()
+}
+
+/// Converts a JSON object into a `Dict`.
+pub fn json_to_dict(map: JSONMap<String, JSONValue>) -> Dict {
+    map.into_iter().map(|(key, value)| (key, Value::from(value))).collect()
+}
+
+/// Converts a `List` into a JSON array.
+pub fn list_to_json(list: List) -> WampResult<Vec<JSONValue>> {
+    list.into_iter().map(JSONValue::try_from).collect()
+}
+
+/// Converts a `Dict` into a JSON object.
+pub fn dict_to_json(dict: Dict) -> WampResult<JSONMap<String, JSONValue>> {
+    let mut map = JSONMap::new();
+    for (key, value) in dict {
+        map.insert(key, try!(JSONValue::try_from(value)));
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{json_to_dict, dict_to_json};
+    use messages::Value;
+    use serde_json::{self, Value as JSONValue};
+    use std::convert::TryFrom;
+    use std::f64;
+
+    #[test]
+    fn round_trip() {
+        let json: JSONValue = serde_json::from_str(r#"{"list":[1,-2,18446744073709551615,2.5,null,true,"text","\u0000/wA="],"nested":{"empty":{}}}"#).unwrap();
+        let dict = match json.clone() {
+            JSONValue::Object(map) => json_to_dict(map),
+            _ => unreachable!()
+        };
+        assert_eq!(dict["list"], Value::List(vec![
+            Value::Integer(1),
+            Value::Integer(-2),
+            Value::UInteger(18446744073709551615),
+            Value::Float(2.5),
+            Value::Null,
+            Value::Boolean(true),
+            Value::String("text".to_string()),
+            Value::String("\0/wA=".to_string())
+        ]));
+        assert_eq!(JSONValue::Object(dict_to_json(dict).unwrap()), json);
+
+        // Strings that look like the JSON serializer's binary values are still strings
+        let value = Value::String("\0/wA=".to_string());
+        assert_eq!(Value::from(JSONValue::try_from(value.clone()).unwrap()), value);
+
+        assert_eq!(JSONValue::try_from(Value::Bytes(vec![255, 0])).unwrap(), JSONValue::String("\0/wA=".to_string()));
+        assert!(JSONValue::try_from(Value::Float(f64::NAN)).is_err());
+    }
+}
@@ -58,6 +58,8 @@ mod options;
 mod value;
 mod error;
 mod roles;
+mod convert;
+mod json;
 
 use serde;
 use std::fmt;
@@ -66,6 +68,8 @@ pub use messages::types::options::*;
 pub use messages::types::value::*;
 pub use messages::types::error::*;
 pub use messages::types::roles::*;
+pub use messages::types::convert::*;
+pub use messages::types::json::*;
 
 fn is_not(b: &bool) -> bool {
     !*b
@@ -3,39 +3,51 @@ use serde;
 use std::fmt;
 use serde::ser::SerializeStruct;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct HelloDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     agent: Option<String>,
-    roles: ClientRoles
+    roles: ClientRoles,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authmethods: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authid: Option<String>
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct WelcomeDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     agent: Option<String>,
-    roles:  RouterRoles
+    roles:  RouterRoles,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authid: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authrole: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authmethod: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub authprovider: Option<String>
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct ErrorDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     message: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct SubscribeOptions {
     #[serde(default, rename="match", skip_serializing_if="MatchingPolicy::is_strict")]
     pub pattern_match: MatchingPolicy
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct PublishOptions {
     #[serde(default, skip_serializing_if="is_not")]
     acknowledge: bool
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct RegisterOptions {
     #[serde(default, rename="match", skip_serializing_if="MatchingPolicy::is_strict")]
     pub pattern_match: MatchingPolicy,
@@ -44,13 +56,13 @@ pub struct RegisterOptions {
     pub invocation_policy: InvocationPolicy
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct CallOptions;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct YieldOptions;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct EventDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     publisher: Option<String>,
@@ -63,27 +75,40 @@ pub struct EventDetails {
 
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct InvocationDetails {
     #[serde(default, skip_serializing_if="Option::is_none")]
     pub procedure: Option<URI>,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct ResultDetails;
 
 impl HelloDetails {
     pub fn new(roles: ClientRoles) -> HelloDetails {
         HelloDetails {
             roles: roles,
-            agent: None
+            agent: None,
+            authmethods: None,
+            authid: None
         }
     }
 
     pub fn new_with_agent(roles: ClientRoles, agent: &str) -> HelloDetails {
         HelloDetails {
             roles: roles,
-            agent: Some(agent.to_string())
+            agent: Some(agent.to_string()),
+            authmethods: None,
+            authid: None
+        }
+    }
+
+    pub fn new_with_auth(roles: ClientRoles, authmethods: Vec<String>) -> HelloDetails {
+        HelloDetails {
+            roles: roles,
+            agent: None,
+            authmethods: Some(authmethods),
+            authid: None
         }
     }
 
@@ -93,14 +118,33 @@ impl WelcomeDetails {
     pub fn new(roles: RouterRoles) -> WelcomeDetails {
         WelcomeDetails {
             roles: roles,
-            agent: None
+            agent: None,
+            authid: None,
+            authrole: None,
+            authmethod: None,
+            authprovider: None
         }
     }
 
     pub fn new_with_agent(roles: RouterRoles, agent: &str) -> WelcomeDetails {
         WelcomeDetails {
             roles: roles,
-            agent: Some(agent.to_string())
+            agent: Some(agent.to_string()),
+            authid: None,
+            authrole: None,
+            authmethod: None,
+            authprovider: None
+        }
+    }
+
+    pub fn new_with_auth(roles: RouterRoles, authid: &str, authrole: &str, authmethod: &str, authprovider: &str) -> WelcomeDetails {
+        WelcomeDetails {
+            roles: roles,
+            agent: None,
+            authid: Some(authid.to_string()),
+            authrole: Some(authrole.to_string()),
+            authmethod: Some(authmethod.to_string()),
+            authprovider: Some(authprovider.to_string())
         }
     }
 
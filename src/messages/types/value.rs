@@ -3,6 +3,7 @@ use CallResult;
 use serde;
 use super::{Reason, CallError};
 use std::fmt;
+use std::i64;
 
 pub type Dict = HashMap<String, Value>;
 pub type List = Vec<Value>;
@@ -27,98 +28,325 @@ pub enum Value {
     // So, we just ignore them here
     Dict(Dict),
     Integer(i64),
+    // Only used for integers too large to be an Integer, so that they aren't truncated
+    UInteger(u64),
     String(String),
     List(List),
-    Boolean(bool)
+    Boolean(bool),
+    Float(f64),
+    Null,
+    Bytes(Vec<u8>)
 }
 
 struct URIVisitor;
 struct ValueVisitor;
 
 
+/// The type an argument is expected to have, for use in an `ArgSpec`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ArgType {
+    Integer,
+    // Integers are also accepted where a float is expected
+    Float,
+    String,
+    Boolean,
+    List,
+    Dict,
+    Bytes,
+    Any
+}
+
+/// Describes one expected argument, so that a whole `List` or `Dict` can be checked with
+/// `ArgList::verify` or `ArgDict::verify`.  For a `List`, the name is only used in error messages.
+#[derive(Debug, Clone)]
+pub struct ArgSpec<'a> {
+    pub name: &'a str,
+    pub arg_type: ArgType,
+    pub required: bool
+}
+
 pub trait ArgList {
     fn get_int(&self, index: usize) -> CallResult<Option<i64>>;
     fn get_string<'a>(&'a self, index: usize) -> CallResult<Option<&'a str>>;
+    fn get_bool(&self, index: usize) -> CallResult<Option<bool>>;
+    fn get_float(&self, index: usize) -> CallResult<Option<f64>>;
+    fn get_list<'a>(&'a self, index: usize) -> CallResult<Option<&'a List>>;
+    fn get_dict<'a>(&'a self, index: usize) -> CallResult<Option<&'a Dict>>;
+    fn require_int(&self, index: usize) -> CallResult<i64>;
+    fn require_string<'a>(&'a self, index: usize) -> CallResult<&'a str>;
+    fn require_bool(&self, index: usize) -> CallResult<bool>;
+    fn require_float(&self, index: usize) -> CallResult<f64>;
+    fn require_list<'a>(&'a self, index: usize) -> CallResult<&'a List>;
+    fn require_dict<'a>(&'a self, index: usize) -> CallResult<&'a Dict>;
     fn verify_len(&self, expected_len: usize) -> CallResult<()>;
+    fn verify(&self, spec: &[ArgSpec]) -> CallResult<()>;
 }
 
 pub trait ArgDict {
     fn get_int(&self, key: &str) -> CallResult<Option<i64>>;
     fn get_string<'a>(&'a self, key: &str) -> CallResult<Option<&'a str>>;
+    fn get_bool(&self, key: &str) -> CallResult<Option<bool>>;
+    fn get_float(&self, key: &str) -> CallResult<Option<f64>>;
+    fn get_list<'a>(&'a self, key: &str) -> CallResult<Option<&'a List>>;
+    fn get_dict<'a>(&'a self, key: &str) -> CallResult<Option<&'a Dict>>;
+    fn require_int(&self, key: &str) -> CallResult<i64>;
+    fn require_string<'a>(&'a self, key: &str) -> CallResult<&'a str>;
+    fn require_bool(&self, key: &str) -> CallResult<bool>;
+    fn require_float(&self, key: &str) -> CallResult<f64>;
+    fn require_list<'a>(&'a self, key: &str) -> CallResult<&'a List>;
+    fn require_dict<'a>(&'a self, key: &str) -> CallResult<&'a Dict>;
+    fn verify(&self, spec: &[ArgSpec]) -> CallResult<()>;
 }
 
-impl ArgList for List {
-    fn get_int(&self, index: usize) -> CallResult<Option<i64>> {
-        let value = self.get(index);
+impl <'a> ArgSpec<'a> {
+    #[inline]
+    pub fn required(name: &'a str, arg_type: ArgType) -> ArgSpec<'a> {
+        ArgSpec {
+            name: name,
+            arg_type: arg_type,
+            required: true
+        }
+    }
+
+    #[inline]
+    pub fn optional(name: &'a str, arg_type: ArgType) -> ArgSpec<'a> {
+        ArgSpec {
+            name: name,
+            arg_type: arg_type,
+            required: false
+        }
+    }
+
+    fn check(&self, value: Option<&Value>) -> CallResult<()> {
         match value {
             Some(value) => {
-                if let &Value::Integer(value) = value {
-                    Ok(Some(value))
+                if self.arg_type.matches(value) {
+                    Ok(())
                 } else {
-                    Err(CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Expected integer, got {}", value.summarize()))]), None))
+                    Err(invalid_argument(format!("Expected {} for argument '{}', got {}", self.arg_type.name(), self.name, value.summarize())))
                 }
             },
             None => {
-                Ok(None)
+                if self.required {
+                    Err(invalid_argument(format!("Missing required argument '{}'", self.name)))
+                } else {
+                    Ok(())
+                }
             }
         }
     }
+}
 
-    fn get_string<'a>(&'a self, index: usize) -> CallResult<Option<&'a str>> {
-        let value = self.get(index);
-        match value {
-            Some(value) => {
-                if let &Value::String(ref value) = value {
-                    Ok(Some(value))
-                } else {
-                    Err(CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Expected string, got {}", value.summarize()))]), None))
-                }
-            },
-            None => {
-                Ok(None)
+impl ArgType {
+    fn matches(&self, value: &Value) -> bool {
+        match (*self, value) {
+            // Unsigned integers are only integers if `get_int` can return them
+            (ArgType::Integer, _) => as_int(value).is_some(),
+            (ArgType::Float, &Value::Float(_)) |
+            (ArgType::Float, &Value::Integer(_)) |
+            (ArgType::Float, &Value::UInteger(_)) |
+            (ArgType::String, &Value::String(_)) |
+            (ArgType::Boolean, &Value::Boolean(_)) |
+            (ArgType::List, &Value::List(_)) |
+            (ArgType::Dict, &Value::Dict(_)) |
+            (ArgType::Bytes, &Value::Bytes(_)) |
+            (ArgType::Any, _) => true,
+            _ => false
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            ArgType::Integer => "integer",
+            ArgType::Float => "float",
+            ArgType::String => "string",
+            ArgType::Boolean => "boolean",
+            ArgType::List => "list",
+            ArgType::Dict => "dict",
+            ArgType::Bytes => "bytes",
+            ArgType::Any => "any value"
+        }
+    }
+}
+
+fn invalid_argument(message: String) -> CallError {
+    CallError::new(Reason::InvalidArgument, Some(vec![Value::String(message)]), None)
+}
+
+/// Extracts a value of the expected type, failing with `InvalidArgument` if it has another type.
+fn extract<'a, T, F>(value: Option<&'a Value>, expected: &str, f: F) -> CallResult<Option<T>> where F: FnOnce(&'a Value) -> Option<T> {
+    match value {
+        Some(value) => {
+            match f(value) {
+                Some(result) => Ok(Some(result)),
+                None => Err(invalid_argument(format!("Expected {}, got {}", expected, value.summarize())))
             }
+        },
+        None => {
+            Ok(None)
         }
     }
+}
+
+fn as_int(value: &Value) -> Option<i64> {
+    match value {
+        &Value::Integer(value) => Some(value),
+        &Value::UInteger(value) if value <= i64::MAX as u64 => Some(value as i64),
+        _ => None
+    }
+}
+
+fn as_string<'a>(value: &'a Value) -> Option<&'a str> {
+    if let &Value::String(ref value) = value { Some(value) } else { None }
+}
+
+fn as_bool(value: &Value) -> Option<bool> {
+    if let &Value::Boolean(value) = value { Some(value) } else { None }
+}
+
+fn as_float(value: &Value) -> Option<f64> {
+    match value {
+        &Value::Float(value) => Some(value),
+        &Value::Integer(value) => Some(value as f64),
+        &Value::UInteger(value) => Some(value as f64),
+        _ => None
+    }
+}
+
+fn as_list<'a>(value: &'a Value) -> Option<&'a List> {
+    if let &Value::List(ref value) = value { Some(value) } else { None }
+}
+
+fn as_dict<'a>(value: &'a Value) -> Option<&'a Dict> {
+    if let &Value::Dict(ref value) = value { Some(value) } else { None }
+}
+
+fn require<T>(value: CallResult<Option<T>>, name: fmt::Arguments) -> CallResult<T> {
+    match try!(value) {
+        Some(value) => Ok(value),
+        None => Err(invalid_argument(format!("Missing required argument {}", name)))
+    }
+}
+
+impl ArgList for List {
+    fn get_int(&self, index: usize) -> CallResult<Option<i64>> {
+        extract(self.get(index), "integer", as_int)
+    }
+
+    fn get_string<'a>(&'a self, index: usize) -> CallResult<Option<&'a str>> {
+        extract(self.get(index), "string", as_string)
+    }
+
+    fn get_bool(&self, index: usize) -> CallResult<Option<bool>> {
+        extract(self.get(index), "boolean", as_bool)
+    }
+
+    fn get_float(&self, index: usize) -> CallResult<Option<f64>> {
+        extract(self.get(index), "float", as_float)
+    }
+
+    fn get_list<'a>(&'a self, index: usize) -> CallResult<Option<&'a List>> {
+        extract(self.get(index), "list", as_list)
+    }
+
+    fn get_dict<'a>(&'a self, index: usize) -> CallResult<Option<&'a Dict>> {
+        extract(self.get(index), "dict", as_dict)
+    }
+
+    fn require_int(&self, index: usize) -> CallResult<i64> {
+        require(self.get_int(index), format_args!("{}", index))
+    }
+
+    fn require_string<'a>(&'a self, index: usize) -> CallResult<&'a str> {
+        require(self.get_string(index), format_args!("{}", index))
+    }
+
+    fn require_bool(&self, index: usize) -> CallResult<bool> {
+        require(self.get_bool(index), format_args!("{}", index))
+    }
+
+    fn require_float(&self, index: usize) -> CallResult<f64> {
+        require(self.get_float(index), format_args!("{}", index))
+    }
+
+    fn require_list<'a>(&'a self, index: usize) -> CallResult<&'a List> {
+        require(self.get_list(index), format_args!("{}", index))
+    }
+
+    fn require_dict<'a>(&'a self, index: usize) -> CallResult<&'a Dict> {
+        require(self.get_dict(index), format_args!("{}", index))
+    }
 
     fn verify_len(&self, expected_len: usize) -> CallResult<()> {
         if self.len() >= expected_len {
             Ok(())
         } else {
-            Err(CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Expected {} arguments, got {}", expected_len, self.len()))]), None))
+            Err(invalid_argument(format!("Expected {} arguments, got {}", expected_len, self.len())))
         }
     }
+
+    fn verify(&self, spec: &[ArgSpec]) -> CallResult<()> {
+        for (index, arg) in spec.iter().enumerate() {
+            try!(arg.check(self.get(index)));
+        }
+        Ok(())
+    }
 }
 
 impl ArgDict for Dict {
     fn get_int(&self, key: &str) -> CallResult<Option<i64>> {
-        let value = self.get(key);
-        match value {
-            Some(value) => {
-                if let &Value::Integer(value) = value {
-                    Ok(Some(value))
-                } else {
-                    Err(CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Expected integer, got {}", value.summarize()))]), None))
-                }
-            },
-            None => {
-                Ok(None)
-            }
-        }
+        extract(self.get(key), "integer", as_int)
     }
+
     fn get_string<'a>(&'a self, key: &str) -> CallResult<Option<&'a str>> {
-        let value = self.get(key);
-        match value {
-            Some(value) => {
-                if let &Value::String(ref value) = value {
-                    Ok(Some(value))
-                } else {
-                    Err(CallError::new(Reason::InvalidArgument, Some(vec![Value::String(format!("Expected string, got {}", value.summarize()))]), None))
-                }
-            },
-            None => {
-                Ok(None)
-            }
+        extract(self.get(key), "string", as_string)
+    }
+
+    fn get_bool(&self, key: &str) -> CallResult<Option<bool>> {
+        extract(self.get(key), "boolean", as_bool)
+    }
+
+    fn get_float(&self, key: &str) -> CallResult<Option<f64>> {
+        extract(self.get(key), "float", as_float)
+    }
+
+    fn get_list<'a>(&'a self, key: &str) -> CallResult<Option<&'a List>> {
+        extract(self.get(key), "list", as_list)
+    }
+
+    fn get_dict<'a>(&'a self, key: &str) -> CallResult<Option<&'a Dict>> {
+        extract(self.get(key), "dict", as_dict)
+    }
+
+    fn require_int(&self, key: &str) -> CallResult<i64> {
+        require(self.get_int(key), format_args!("'{}'", key))
+    }
+
+    fn require_string<'a>(&'a self, key: &str) -> CallResult<&'a str> {
+        require(self.get_string(key), format_args!("'{}'", key))
+    }
+
+    fn require_bool(&self, key: &str) -> CallResult<bool> {
+        require(self.get_bool(key), format_args!("'{}'", key))
+    }
+
+    fn require_float(&self, key: &str) -> CallResult<f64> {
+        require(self.get_float(key), format_args!("'{}'", key))
+    }
+
+    fn require_list<'a>(&'a self, key: &str) -> CallResult<&'a List> {
+        require(self.get_list(key), format_args!("'{}'", key))
+    }
+
+    fn require_dict<'a>(&'a self, key: &str) -> CallResult<&'a Dict> {
+        require(self.get_dict(key), format_args!("'{}'", key))
+    }
+
+    fn verify(&self, spec: &[ArgSpec]) -> CallResult<()> {
+        for arg in spec {
+            try!(arg.check(self.get(arg.name)));
         }
+        Ok(())
     }
 }
 
@@ -135,6 +363,9 @@ impl Value {
             &Value::Integer(i) => {
                 i.to_string()
             },
+            &Value::UInteger(u) => {
+                u.to_string()
+            },
             &Value::String(ref s) => {
                 if s.len() > 50 {
                     s[..50].to_string()
@@ -152,6 +383,15 @@ impl Value {
             &Value::Boolean(b) => {
                 b.to_string()
             }
+            &Value::Float(f) => {
+                f.to_string()
+            }
+            &Value::Null => {
+                "null".to_string()
+            }
+            &Value::Bytes(ref b) => {
+                format!("<{} bytes>", b.len())
+            }
         }
     }
 }
@@ -171,6 +411,36 @@ impl <'de> serde::de::Visitor<'de> for ValueVisitor {
             Ok(Value::String(value.to_string()))
     }
 
+    #[inline]
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Value, E>
+        where E: serde::de::Error {
+            Ok(Value::Bytes(value.to_vec()))
+    }
+
+    #[inline]
+    fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Value, E>
+        where E: serde::de::Error {
+            Ok(Value::Bytes(value))
+    }
+
+    #[inline]
+    fn visit_f64<E>(self, value: f64) -> Result<Value, E>
+    where E: serde::de::Error {
+        Ok(Value::Float(value))
+    }
+
+    #[inline]
+    fn visit_unit<E>(self) -> Result<Value, E>
+    where E: serde::de::Error {
+        Ok(Value::Null)
+    }
+
+    #[inline]
+    fn visit_none<E>(self) -> Result<Value, E>
+    where E: serde::de::Error {
+        Ok(Value::Null)
+    }
+
 
     #[inline]
     fn visit_i64<E>(self, value: i64) -> Result<Value, E>
@@ -181,7 +451,11 @@ impl <'de> serde::de::Visitor<'de> for ValueVisitor {
     #[inline]
     fn visit_u64<E>(self, value: u64) -> Result<Value, E>
     where E: serde::de::Error {
-        Ok(Value::Integer(value as i64))
+        if value > i64::MAX as u64 {
+            Ok(Value::UInteger(value))
+        } else {
+            Ok(Value::Integer(value as i64))
+        }
     }
 
     #[inline]
@@ -239,8 +513,12 @@ impl serde::Serialize for Value {
             &Value::Dict(ref dict) => dict.serialize(serializer),
             &Value::String(ref s) => serializer.serialize_str(s),
             &Value::Integer(i) => serializer.serialize_i64(i),
+            &Value::UInteger(u) => serializer.serialize_u64(u),
             &Value::List(ref list) => list.serialize(serializer),
-            &Value::Boolean(b) => serializer.serialize_bool(b)
+            &Value::Boolean(b) => serializer.serialize_bool(b),
+            &Value::Float(f) => serializer.serialize_f64(f),
+            &Value::Null => serializer.serialize_unit(),
+            &Value::Bytes(ref b) => serializer.serialize_bytes(b)
         }
     }
 }
@@ -0,0 +1,261 @@
+//! Serves RawSocket connections over TCP as tokio tasks, rather than on a thread each.
+//!
+//! Each connection is split into two parts.  One reads frames from the socket and hands them to
+//! the connection's handler.  The other writes out the frames in the connection's queue, which is
+//! where everything sent to the connection goes, so sending to a slow client never blocks.
+//!
+//! This is only part of an asynchronous router.  Only RawSocket connections over TCP are served
+//! this way; WebSocket, Unix domain socket and local connections still run on threads of their
+//! own.  Messages are handled by the same synchronous handler as other connections, which is
+//! locked while it works and locks the router's realms in turn, so handling a message blocks the
+//! runtime thread it is read on until any other connection using the realm lets go of it.
+use super::{ConnectionHandler, RouterInfo, ListenerConfig};
+use super::listener::{ACCEPT_BACKOFF, HANDSHAKE_TIMEOUT};
+use transport::Sender;
+use transport::outbound::{self, Frame};
+use transport::rawsocket::{self, Handshake, FrameType, ERROR_SERIALIZER_UNSUPPORTED};
+use ws::{Handler, Message as WSMessage, CloseCode};
+use futures::{Future, Stream};
+use futures::future::{self, Either};
+use tokio;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::io::{read_exact, write_all};
+use tokio::timer::{Delay, Timeout};
+use bytes::{BytesMut, BufMut};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Splits the bytes that follow the handshake into frames, and turns frames back into bytes.
+struct FrameCodec {
+    max_length: usize
+}
+
+impl Decoder for FrameCodec {
+    type Item = (FrameType, Vec<u8>);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<(FrameType, Vec<u8>)>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let (frame_type, len) = try!(rawsocket::parse_frame_header(&buf[..4], self.max_length));
+        if buf.len() < 4 + len {
+            let missing = 4 + len - buf.len();
+            buf.reserve(missing);
+            return Ok(None);
+        }
+        let frame = buf.split_to(4 + len);
+        Ok(Some((frame_type, frame[4..].to_vec())))
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = (FrameType, Vec<u8>);
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: (FrameType, Vec<u8>), buf: &mut BytesMut) -> io::Result<()> {
+        let (frame_type, payload) = frame;
+        let header = try!(rawsocket::frame_header(frame_type, payload.len()));
+        buf.reserve(4 + payload.len());
+        buf.put_slice(&header);
+        buf.put_slice(&payload);
+        Ok(())
+    }
+}
+
+/// Accepts connections until the returned future is dropped, spawning a task for each one.
+pub fn listen(listener: TcpListener, router: Arc<RouterInfo>, config: Arc<ListenerConfig>) -> impl Future<Item = (), Error = ()> + Send {
+    listener.incoming().then(|stream| {
+        match stream {
+            Ok(stream) => Either::A(future::ok(Some(stream))),
+            Err(e) => {
+                error!("Could not accept connection: {}", e);
+                // Nothing is accepted until the delay is over, since the stream waits for it
+                Either::B(Delay::new(Instant::now() + Duration::from_millis(ACCEPT_BACKOFF)).then(|_| Ok(None)))
+            }
+        }
+    }).filter_map(|stream| stream).for_each(move |stream| {
+        tokio::spawn(serve(stream, router.clone(), config.clone()));
+        Ok(())
+    })
+}
+
+/// Runs a single connection, from the handshake until it is closed by either side.
+fn serve(stream: TcpStream, router: Arc<RouterInfo>, config: Arc<ListenerConfig>) -> impl Future<Item = (), Error = ()> + Send {
+    debug!("New RawSocket connection over TCP");
+    let handshake_router = router.clone();
+    let length_exponent = router.length_exponent();
+    let handshake = Timeout::new(read_exact(stream, [0u8; 4]), Duration::from_millis(HANDSHAKE_TIMEOUT)).map_err(|e| {
+        e.into_inner().unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "The client did not send its handshake in time"))
+    });
+    handshake.and_then(move |(stream, buf)| {
+        let accepted = match rawsocket::parse_handshake(&buf) {
+            Ok(Handshake::Accepted{serializer, max_length}) => {
+                match handshake_router.serializers.lock().unwrap().get_raw_socket(serializer) {
+                    Some(found) => Ok((serializer, found, max_length)),
+                    None => {
+                        warn!("Client requested unsupported RawSocket serializer {}", serializer);
+                        Err(ERROR_SERIALIZER_UNSUPPORTED)
+                    }
+                }
+            },
+            Ok(Handshake::Refused(error)) => {
+                warn!("Client sent an invalid RawSocket handshake (error {})", error);
+                Err(error)
+            },
+            Err(e) => return Either::A(future::err(e))
+        };
+        let mut reply = Vec::new();
+        match accepted {
+            Ok((serializer_id, _, _)) => rawsocket::write_handshake(&mut reply, length_exponent, serializer_id),
+            Err(error) => rawsocket::write_handshake_error(&mut reply, error)
+        }.expect("Writing to a Vec cannot fail");
+        Either::B(write_all(stream, reply).map(move |(stream, _)| {
+            (stream, accepted.ok().map(|(_, serializer, max_length)| (serializer, max_length)))
+        }))
+    }).map_err(|e| {
+        error!("Could not complete RawSocket handshake: {}", e);
+    }).and_then(move |(stream, serializer)| {
+        let (serializer, client_max_length) = match serializer {
+            Some(accepted) => accepted,
+            None => return Either::A(future::ok(()))
+        };
+        let (sink, frames) = Framed::new(stream, FrameCodec {
+            max_length: rawsocket::max_length(length_exponent)
+        }).split();
+        let sender = outbound::Sender::new(router.outbound_queue(&*serializer), client_max_length);
+        let mut handler = ConnectionHandler::new(router, Sender::Queued(sender.clone()));
+        handler.config = config;
+        {
+            let mut info = handler.info.lock().unwrap();
+            info.protocol = serializer.protocol().to_string();
+            info.serializer = serializer.clone();
+        }
+        let handler = Arc::new(Mutex::new(handler));
+
+        let writer = sender.receiver().take_while(|frame| {
+            Ok(match *frame {
+                Frame::Close => false,
+                _ => true
+            })
+        }).filter_map(|frame| {
+            match frame {
+                Frame::Message(WSMessage::Text(payload)) => Some((FrameType::Message, payload.into_bytes())),
+                Frame::Message(WSMessage::Binary(payload)) => Some((FrameType::Message, payload)),
+                Frame::Pong(payload) => Some((FrameType::Pong, payload)),
+                Frame::Close => None
+            }
+        }).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "The connection's queue failed")
+        }).forward(sink).map(|_| ());
+
+        let reader_handler = handler.clone();
+        let pong_sender = sender.clone();
+        let reader = frames.for_each(move |(frame_type, payload)| {
+            match frame_type {
+                FrameType::Message => {
+                    let message = if serializer.is_binary() {
+                        WSMessage::Binary(payload)
+                    } else {
+                        match String::from_utf8(payload) {
+                            Ok(payload) => WSMessage::Text(payload),
+                            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Received {} message that was not valid UTF-8", serializer.protocol())))
+                        }
+                    };
+                    reader_handler.lock().unwrap().on_message(message).map_err(|e| {
+                        io::Error::new(io::ErrorKind::Other, e.to_string())
+                    })
+                },
+                FrameType::Ping => {
+                    pong_sender.pong(payload).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+                },
+                FrameType::Pong => Ok(())
+            }
+        });
+
+        Either::B(reader.select2(writer).then(move |result| {
+            match result {
+                Err(Either::A((e, _))) | Err(Either::B((e, _))) => debug!("RawSocket connection closed: {}", e),
+                Ok(_) => debug!("RawSocket connection closed")
+            }
+            sender.close().ok();
+            handler.lock().unwrap().on_close(CloseCode::Normal, "");
+            Ok(())
+        }))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::FrameCodec;
+    use transport::rawsocket::{FrameType, max_length};
+    use router::Router;
+    use client::Connection;
+    use messages::{URI, Value, Dict, List};
+    use ::CallResult;
+    use eventual::Async;
+    use tokio::codec::{Decoder, Encoder};
+    use tokio::runtime::Runtime;
+    use bytes::BytesMut;
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    fn add(args: List, _kwargs: Dict) -> CallResult<(Option<List>, Option<Dict>)> {
+        let a = match args[0] { Value::Integer(a) => a, _ => 0 };
+        let b = match args[1] { Value::Integer(b) => b, _ => 0 };
+        Ok((Some(vec![Value::Integer(a + b)]), None))
+    }
+
+    #[test]
+    fn codec() {
+        let mut codec = FrameCodec {
+            max_length: max_length(0)
+        };
+        let mut buf = BytesMut::new();
+        codec.encode((FrameType::Message, b"[6,{},\"wamp.close.normal\"]".to_vec()), &mut buf).unwrap();
+        codec.encode((FrameType::Ping, Vec::new()), &mut buf).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 26]);
+
+        let mut partial = buf.split_to(10);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.extend_from_slice(&buf);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some((FrameType::Message, b"[6,{},\"wamp.close.normal\"]".to_vec())));
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some((FrameType::Ping, Vec::new())));
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+
+        let mut buf = BytesMut::new();
+        codec.encode((FrameType::Message, vec![0u8; 513]), &mut buf).unwrap();
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn listen_async() {
+        let mut router = Router::new();
+        router.add_realm("async_realm");
+        // Find a free port to listen on
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(router.listen_async(&address).unwrap());
+        let address = address.to_string();
+
+        let mut callee = Connection::new_rawsocket(&address, "async_realm").connect().unwrap();
+        callee.register(URI::new("ca.test.add"), Box::new(add)).unwrap().await().unwrap();
+        let (tx, rx) = channel();
+        callee.subscribe(URI::new("ca.test.topic"), Box::new(move |args, _kwargs| {
+            tx.send(args).unwrap();
+        })).unwrap().await().unwrap();
+
+        let mut caller = Connection::new_rawsocket(&address, "async_realm").connect().unwrap();
+        let (args, _) = caller.call(URI::new("ca.test.add"), Some(vec![Value::Integer(2), Value::Integer(3)]), None).unwrap().await().unwrap();
+        assert_eq!(args, vec![Value::Integer(5)]);
+
+        caller.publish(URI::new("ca.test.topic"), Some(vec![Value::String("hello".to_string())]), None).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), vec![Value::String("hello".to_string())]);
+
+        caller.shutdown().unwrap().await().unwrap();
+        callee.shutdown().unwrap().await().unwrap();
+    }
+}
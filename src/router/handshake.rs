@@ -1,4 +1,7 @@
-use super::{ConnectionHandler, ConnectionState, WAMP_JSON, WAMP_MSGPACK};
+use super::{ConnectionHandler, ConnectionState};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use transport::websocket::Window;
 
 use router::messaging::send_message;
 use ws::{Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, Request, Response, CloseCode};
@@ -7,16 +10,101 @@ use messages::{Message, URI, HelloDetails, WelcomeDetails, RouterRoles, ErrorDet
 use ::{WampResult, Error, ErrorKind};
 
 impl ConnectionHandler {
-    pub fn handle_hello(&mut self, realm: URI, _details: HelloDetails) -> WampResult<()> {
+    pub fn handle_hello(&mut self, realm: URI, details: HelloDetails) -> WampResult<()> {
         debug!("Responding to hello message (realm: {:?})", realm);
+        if !self.config.allows_realm(&realm.uri) {
+            info!("Refusing connection to realm {}, which this listener does not allow", realm.uri);
+            return self.abort(Reason::NoSuchRealm, "The realm does not exist");
+        }
+        let welcome_details = match self.authenticate(&details) {
+            Some(welcome_details) => welcome_details,
+            None => {
+                info!("Refusing connection, none of the authentication methods {:?} succeeded", details.authmethods);
+                return self.abort(Reason::NotAuthorized, "None of the requested authentication methods succeeded");
+            }
+        };
+        if !self.set_realm(realm.uri.clone()) {
+            info!("Refusing connection to realm {}, which does not exist", realm.uri);
+            return self.abort(Reason::NoSuchRealm, "The realm does not exist");
+        }
+        self.set_role_rate_limits();
         let id = {
             let mut info = self.info.lock().unwrap();
             info.state = ConnectionState::Connected;
             info.id
         };
 
-        try!(self.set_realm(realm.uri));
-        send_message(&self.info, &Message::Welcome(id, WelcomeDetails::new(RouterRoles::new())))
+        send_message(&self.info, &Message::Welcome(id, welcome_details))
+    }
+
+    /// Tries each of the authentication methods the client asked for that the listener allows, in
+    /// order, and returns the details of the first that succeeds.  Clients that don't ask for any
+    /// methods, or only for methods the router can't try, are anonymous if the listener allows it,
+    /// as they were before the router supported any authentication.  A client whose methods were
+    /// tried and failed is refused.
+    fn authenticate(&mut self, details: &HelloDetails) -> Option<WelcomeDetails> {
+        let mut tried = false;
+        if let Some(ref authmethods) = details.authmethods {
+            for authmethod in authmethods {
+                if !self.config.allows_authmethod(authmethod) {
+                    continue;
+                }
+                let welcome_details = match &authmethod[..] {
+                    "anonymous" => Some(WelcomeDetails::new(RouterRoles::new())),
+                    "tls" => self.authenticate_tls(details),
+                    _ => continue
+                };
+                if welcome_details.is_some() {
+                    return welcome_details;
+                }
+                tried = true;
+            }
+        }
+        if !tried && self.config.allows_authmethod("anonymous") {
+            Some(WelcomeDetails::new(RouterRoles::new()))
+        } else {
+            None
+        }
+    }
+
+    /// Authenticates the client using the identity in the certificate it presented during the TLS
+    /// handshake.  A client that also asks for an authid is refused unless its certificate is for
+    /// that authid.
+    #[cfg(feature = "ssl")]
+    fn authenticate_tls(&mut self, details: &HelloDetails) -> Option<WelcomeDetails> {
+        let identity = match self.tls_identity {
+            Some(ref identity) => identity.lock().unwrap().clone(),
+            None => None
+        };
+        let identity = identity.and_then(|identity| match details.authid {
+            Some(ref authid) if *authid != identity.authid => {
+                info!("Client asked for authid {}, but its certificate is for {}", authid, identity.authid);
+                None
+            },
+            _ => Some(identity)
+        });
+        identity.map(|identity| {
+            debug!("Authenticated client as {} ({}) using its certificate", identity.authid, identity.authrole);
+            let mut info = self.info.lock().unwrap();
+            info.authid = Some(identity.authid.clone());
+            info.authrole = Some(identity.authrole.clone());
+            WelcomeDetails::new_with_auth(RouterRoles::new(), &identity.authid, &identity.authrole, "tls", "x509")
+        })
+    }
+
+    #[cfg(not(feature = "ssl"))]
+    fn authenticate_tls(&mut self, _details: &HelloDetails) -> Option<WelcomeDetails> {
+        None
+    }
+
+    pub fn abort(&mut self, reason: Reason, message: &str) -> WampResult<()> {
+        send_message(&self.info, &Message::Abort(ErrorDetails::new_with_message(message), reason)).ok();
+        let mut info = self.info.lock().unwrap();
+        info.state = ConnectionState::Disconnected;
+        match info.sender.close(CloseCode::Normal) {
+            Err(e) => Err(Error::new(ErrorKind::WSError(e))),
+            _ => Ok(())
+        }
     }
 
     pub fn handle_goodbye(&mut self, _details: ErrorDetails, reason: Reason) -> WampResult<()> {
@@ -39,6 +127,7 @@ impl ConnectionHandler {
             },
             ConnectionState::ShuttingDown => {
                 info!("Recieved goobye message in response to our goodbye message with reason: {:?}", reason);
+                self.remove();
                 let mut info = self.info.lock().unwrap();
                 info.state = ConnectionState::Disconnected;
                 match info.sender.close(CloseCode::Normal) {
@@ -54,29 +143,71 @@ impl ConnectionHandler {
     }
 
 
-    fn set_realm(&mut self, realm: String) -> WampResult<()> {
+    /// Joins the connection to a realm, returning false if there is no such realm or it is being closed.
+    fn set_realm(&mut self, realm: String) -> bool {
         debug!("Setting realm to {}", realm);
-        let realm = self.router.realms.lock().unwrap()[&realm].clone();
+        let realm = match self.router.realms.lock().unwrap().get(&realm) {
+            Some(realm) => realm.clone(),
+            None => return false
+        };
         {
-            realm.lock().unwrap().connections.push(self.info.clone());
+            let mut realm = realm.lock().unwrap();
+            if realm.closed {
+                return false;
+            }
+            realm.connections.push(self.info.clone());
         }
         self.realm = Some(realm);
-        Ok(())
+        true
     }
 
     pub fn process_protocol(&mut self, request: &Request, response: &mut Response) -> WSResult<()> {
         debug!("Checking protocol");
         let protocols = try!(request.protocols());
-        for protocol in protocols {
-            if protocol == WAMP_JSON || protocol == WAMP_MSGPACK {
+        for protocol in protocols.iter() {
+            let negotiated = self.router.serializers.lock().unwrap().negotiate(protocol);
+            if let Some((serializer, batched)) = negotiated {
                 response.set_protocol(protocol);
                 let mut info = self.info.lock().unwrap();
                 info.protocol = protocol.to_string();
+                if batched {
+                    let flush_interval = self.router.batch_flush_interval.load(Ordering::Relaxed) as u64;
+                    info.sender = info.sender.clone().into_batched(!serializer.is_binary(), flush_interval);
+                }
+                let window = Arc::new(Window::new());
+                let window_size = self.router.outbound_limit.load(Ordering::Relaxed);
+                info.sender = info.sender.clone().into_queued(self.router.outbound_queue(&*serializer), window.clone(), window_size);
+                self.window = Some(window);
+                info.serializer = serializer;
                 return Ok(())
             }
         }
-        Err(WSError::new(WSErrorKind::Protocol, format!("Neither {} nor {} were selected as Websocket sub-protocols", WAMP_JSON, WAMP_MSGPACK)))
+        Err(WSError::new(WSErrorKind::Protocol, format!("None of the Websocket sub-protocols {:?} are supported", protocols)))
     }
 
 
 }
+
+#[cfg(test)]
+mod test {
+    use router::Router;
+    use transport::local::{self, Envelope};
+    use messages::{Message, URI, HelloDetails, ClientRoles, Reason};
+    use std::time::Duration;
+
+    #[test]
+    fn tls_without_certificate() {
+        let mut router = Router::new();
+        router.add_realm("tls_realm");
+
+        let (client_sender, receiver) = local::channel();
+        let router_sender = router.local_connector().connect(client_sender);
+        let details = HelloDetails::new_with_auth(ClientRoles::new(), vec!["tls".to_string()]);
+        router_sender.send_message(Message::Hello(URI::new("tls_realm"), details)).unwrap();
+
+        match receiver.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(Message::Abort(_, reason)) => assert_eq!(reason, Reason::NotAuthorized),
+            _ => panic!("Expected an abort message")
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Contains the handles returned by the router's `listen` methods, and the settings each listener
+//! applies to the connections it accepts.
+//!
+//! A listener accepts connections until its handle is stopped or dropped, so a router can open and
+//! close listeners independently of one another.  Every listener shares the router's realms.
+use ws::Sender as WSSender;
+use std::net::{SocketAddr, TcpStream, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(feature = "ssl")]
+use transport::tls::TlsServerConfig;
+
+/// How long (in milliseconds) a listener waits after failing to accept a connection before it
+/// tries again.  Errors such as running out of file descriptors last until some connections close,
+/// and retrying straight away would only spin.
+pub const ACCEPT_BACKOFF: u64 = 100;
+
+/// How long (in milliseconds) a RawSocket client has to send its handshake once it has connected.
+pub const HANDSHAKE_TIMEOUT: u64 = 10000;
+
+/// What a listener accepts connections on.
+pub enum Endpoint {
+    /// WebSocket connections on the given address, such as `"127.0.0.1:8090"`
+    WebSocket(String),
+    /// Secure WebSocket (`wss://`) connections on the given address
+    #[cfg(feature = "ssl")]
+    SecureWebSocket(String, TlsServerConfig),
+    /// RawSocket connections over TCP on the given address
+    RawSocket(String),
+    /// RawSocket connections on a Unix domain socket at the given path
+    #[cfg(unix)]
+    Unix(PathBuf)
+}
+
+/// The settings a listener applies to the connections it accepts.  By default, clients can join
+/// any realm using any authentication method the router supports.
+#[derive(Clone, Debug, Default)]
+pub struct ListenerConfig {
+    realms: Option<Vec<String>>,
+    authmethods: Option<Vec<String>>
+}
+
+impl ListenerConfig {
+    pub fn new() -> ListenerConfig {
+        ListenerConfig::default()
+    }
+
+    /// Only lets clients join the given realms.  Clients asking for any other realm are told that
+    /// it does not exist.
+    pub fn set_realms(&mut self, realms: Vec<String>) {
+        self.realms = Some(realms);
+    }
+
+    /// Only lets clients authenticate with the given methods.  Clients that don't ask for any
+    /// methods, or only for methods that aren't allowed or that the router doesn't support, are
+    /// only let in if `"anonymous"` is one of them.
+    pub fn set_authmethods(&mut self, authmethods: Vec<String>) {
+        self.authmethods = Some(authmethods);
+    }
+
+    pub fn allows_realm(&self, realm: &str) -> bool {
+        self.realms.as_ref().map_or(true, |realms| realms.iter().any(|allowed| allowed == realm))
+    }
+
+    pub fn allows_authmethod(&self, authmethod: &str) -> bool {
+        self.authmethods.as_ref().map_or(true, |authmethods| authmethods.iter().any(|allowed| allowed == authmethod))
+    }
+}
+
+/// Where a listener accepts connections.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenerAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf)
+}
+
+impl ListenerAddress {
+    /// Connects to the listener, returning false if it could not be reached.
+    fn wake(&self) -> bool {
+        match *self {
+            ListenerAddress::Tcp(address) => {
+                let mut address = address;
+                if address.ip().is_unspecified() {
+                    let loopback = if address.is_ipv4() { Ipv4Addr::new(127, 0, 0, 1).into() } else { Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1).into() };
+                    address.set_ip(loopback);
+                }
+                TcpStream::connect(address).is_ok()
+            },
+            #[cfg(unix)]
+            ListenerAddress::Unix(ref path) => {
+                let woken = UnixStream::connect(path).is_ok();
+                fs::remove_file(path).ok();
+                woken
+            }
+        }
+    }
+}
+
+enum Stopper {
+    WebSocket(WSSender),
+    /// A thread accepting RawSocket connections, which checks the flag after each connection
+    Accept(Arc<AtomicBool>)
+}
+
+/// A handle to a listener started by the router.
+///
+/// The listener stops accepting connections when `stop` is called or the handle is dropped.
+/// Stopping a WebSocket listener also closes its connections, since they are run by the
+/// listener's event loop.  RawSocket connections run on threads of their own, and are left open.
+#[must_use = "the listener stops as soon as its handle is dropped"]
+pub struct Listener {
+    address: ListenerAddress,
+    stopper: Option<Stopper>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Listener {
+    pub(crate) fn websocket(address: SocketAddr, sender: WSSender, thread: JoinHandle<()>) -> Listener {
+        Listener {
+            address: ListenerAddress::Tcp(address),
+            stopper: Some(Stopper::WebSocket(sender)),
+            thread: Some(thread)
+        }
+    }
+
+    pub(crate) fn rawsocket(address: ListenerAddress, stopped: Arc<AtomicBool>, thread: JoinHandle<()>) -> Listener {
+        Listener {
+            address: address,
+            stopper: Some(Stopper::Accept(stopped)),
+            thread: Some(thread)
+        }
+    }
+
+    /// The address the listener is bound to.  For TCP listeners this has the actual port, even if
+    /// the listener was asked to bind to port 0.
+    pub fn local_addr(&self) -> &ListenerAddress {
+        &self.address
+    }
+
+    /// Stops accepting connections, and waits for the listener to finish.
+    pub fn stop(self) {
+        // Dropping the handle stops the listener
+    }
+
+    /// Waits until the listener stops, which it only does on its own if it fails.
+    pub fn join(mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        self.stopper = None;
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let woken = match self.stopper.take() {
+            Some(Stopper::WebSocket(sender)) => {
+                debug!("Stopping listener on {:?}", self.address);
+                sender.shutdown().is_ok()
+            },
+            Some(Stopper::Accept(stopped)) => {
+                debug!("Stopping listener on {:?}", self.address);
+                stopped.store(true, Ordering::SeqCst);
+                // Wake the thread blocked accepting connections, so that it sees it has been stopped
+                self.address.wake()
+            },
+            None => true
+        };
+        if let Some(thread) = self.thread.take() {
+            if woken {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ListenerAddress, ListenerConfig, Endpoint};
+    use router::Router;
+    use client::Connection;
+    #[cfg(unix)]
+    use std::env;
+    #[cfg(unix)]
+    use std::process;
+
+    /// Stops a Unix domain socket listener, which has to be woken by connecting to its socket
+    /// file before the file can be removed.
+    #[cfg(unix)]
+    fn stop_unix_listener(router: &Router) {
+        let path = env::temp_dir().join(format!("wamp-listener-{}.sock", process::id()));
+        let listener = router.listen_unix(&path).unwrap();
+        assert_eq!(*listener.local_addr(), ListenerAddress::Unix(path.clone()));
+        assert!(path.exists());
+
+        Connection::new_unix(&path, "listener_realm").connect().unwrap();
+        listener.stop();
+        assert!(!path.exists());
+        assert!(Connection::new_unix(&path, "listener_realm").connect().is_err());
+    }
+
+    #[test]
+    fn stop_listener() {
+        let mut router = Router::new();
+        router.add_realm("listener_realm");
+
+        let listener = router.listen("127.0.0.1:0").unwrap();
+        let address = match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => address,
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+        assert!(address.port() != 0);
+        assert!(router.listen(&address.to_string()).is_err());
+
+        let url = format!("ws://{}", address);
+        Connection::new(&url, "listener_realm").connect().unwrap();
+        listener.stop();
+        assert!(Connection::new(&url, "listener_realm").connect().is_err());
+
+        #[cfg(unix)]
+        stop_unix_listener(&router);
+    }
+
+    #[test]
+    fn listener_config() {
+        let mut router = Router::new();
+        router.add_realm("public_realm");
+        router.add_realm("private_realm");
+
+        let mut config = ListenerConfig::new();
+        config.set_realms(vec!["public_realm".to_string()]);
+        config.set_authmethods(vec!["anonymous".to_string()]);
+        assert!(config.allows_realm("public_realm"));
+        assert!(!config.allows_realm("private_realm"));
+        assert!(!config.allows_authmethod("tls"));
+
+        let public = router.listen_with(Endpoint::WebSocket("127.0.0.1:0".to_string()), config).unwrap();
+        let private = router.listen("127.0.0.1:0").unwrap();
+        let url = |listener: &super::Listener| match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => format!("ws://{}", address),
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+
+        Connection::new(&url(&public), "public_realm").connect().unwrap();
+        assert!(Connection::new(&url(&public), "private_realm").connect().is_err());
+        Connection::new(&url(&private), "private_realm").connect().unwrap();
+    }
+
+    #[test]
+    fn listener_authmethods() {
+        let mut router = Router::new();
+        router.add_realm("auth_realm");
+
+        let mut config = ListenerConfig::new();
+        config.set_authmethods(vec!["tls".to_string()]);
+        let restricted = router.listen_with(Endpoint::RawSocket("127.0.0.1:0".to_string()), config).unwrap();
+        let open = router.listen_rawsocket("127.0.0.1:0").unwrap();
+        let address = |listener: &super::Listener| match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => address.to_string(),
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+
+        // Anonymous clients are let in by default, whether or not they ask for it
+        Connection::new_rawsocket(&address(&open), "auth_realm").connect().unwrap();
+        let mut anonymous = Connection::new_rawsocket(&address(&open), "auth_realm");
+        anonymous.set_authmethods(vec!["anonymous".to_string()]);
+        anonymous.connect().unwrap();
+        let mut unsupported = Connection::new_rawsocket(&address(&open), "auth_realm");
+        unsupported.set_authmethods(vec!["ticket".to_string()]);
+        unsupported.connect().unwrap();
+
+        // ...but not by a listener that only allows TLS client certificates
+        assert!(Connection::new_rawsocket(&address(&restricted), "auth_realm").connect().is_err());
+        let mut anonymous = Connection::new_rawsocket(&address(&restricted), "auth_realm");
+        anonymous.set_authmethods(vec!["anonymous".to_string()]);
+        assert!(anonymous.connect().is_err());
+        let mut unsupported = Connection::new_rawsocket(&address(&restricted), "auth_realm");
+        unsupported.set_authmethods(vec!["ticket".to_string()]);
+        assert!(unsupported.connect().is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use super::{ConnectionHandler, RouterInfo};
+use transport::Sender;
+use transport::local::{self, Envelope};
+use ws::{Handler, CloseCode};
+use std::sync::Arc;
+use std::thread;
+
+/// Lets clients in the same process connect to a router without going through a socket.
+///
+/// See `Router::local_connector` and `Connection::new_local`.
+#[derive(Clone)]
+pub struct LocalConnector {
+    router: Arc<RouterInfo>
+}
+
+impl LocalConnector {
+    pub(super) fn new(router: Arc<RouterInfo>) -> LocalConnector {
+        LocalConnector {
+            router: router
+        }
+    }
+
+    /// Opens a connection to the router, which will send its messages to `client`.  Returns the
+    /// sender the client should use to send messages to the router.
+    pub(crate) fn connect(&self, client: local::Sender) -> local::Sender {
+        let (sender, receiver) = local::channel();
+        let mut handler = ConnectionHandler::new(self.router.clone(), Sender::Local(client));
+        handler.info.lock().unwrap().protocol = "local".to_string();
+        thread::spawn(move || {
+            debug!("New local connection");
+            for envelope in receiver.iter() {
+                match envelope {
+                    Envelope::Message(message) => {
+                        if let Err(e) = handler.on_wamp_message(message) {
+                            error!("Closing local connection: {}", e);
+                            break;
+                        }
+                    },
+                    Envelope::Close => break
+                }
+            }
+            handler.on_close(CloseCode::Normal, "");
+        });
+        sender
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use router::Router;
+    use client::Connection;
+    use transport::local::{self, Envelope};
+    use messages::{Message, URI, Value, Dict, List, HelloDetails, ClientRoles, Reason};
+    use ::CallResult;
+    use eventual::Async;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn add(args: List, _kwargs: Dict) -> CallResult<(Option<List>, Option<Dict>)> {
+        let a = match args[0] { Value::Integer(a) => a, _ => 0 };
+        let b = match args[1] { Value::Integer(b) => b, _ => 0 };
+        Ok((Some(vec![Value::Integer(a + b)]), None))
+    }
+
+    #[test]
+    fn call_and_publish() {
+        let mut router = Router::new();
+        router.add_realm("local_realm");
+
+        let mut callee = Connection::new_local(&router, "local_realm").connect().unwrap();
+        callee.register(URI::new("ca.test.add"), Box::new(add)).unwrap().await().unwrap();
+
+        let (tx, rx) = channel();
+        callee.subscribe(URI::new("ca.test.topic"), Box::new(move |args, _kwargs| {
+            tx.send(args).unwrap();
+        })).unwrap().await().unwrap();
+
+        let mut caller = Connection::new_local(&router, "local_realm").connect().unwrap();
+        let (args, _) = caller.call(URI::new("ca.test.add"), Some(vec![Value::Integer(2), Value::Integer(3)]), None).unwrap().await().unwrap();
+        assert_eq!(args, vec![Value::Integer(5)]);
+
+        caller.publish(URI::new("ca.test.topic"), Some(vec![Value::String("hello".to_string())]), None).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), vec![Value::String("hello".to_string())]);
+
+        caller.shutdown().unwrap().await().unwrap();
+        callee.shutdown().unwrap().await().unwrap();
+    }
+
+    /// Connects to a realm without a client, so that the test sees every message the router sends.
+    fn hello(router: &Router, realm: &str) -> (local::Sender, local::Receiver) {
+        let (client_sender, receiver) = local::channel();
+        let router_sender = router.local_connector().connect(client_sender);
+        router_sender.send_message(Message::Hello(URI::new(realm), HelloDetails::new(ClientRoles::new()))).unwrap();
+        (router_sender, receiver)
+    }
+
+    #[test]
+    fn shutdown_without_waiting() {
+        let mut router = Router::new();
+        router.add_realm("shutdown_realm");
+        router.set_shutdown_grace_period(60000);
+
+        let first = Connection::new_local(&router, "shutdown_realm").connect().unwrap();
+        let second = Connection::new_local(&router, "shutdown_realm").connect().unwrap();
+
+        // Both clients reply to the goodbye straight away, so the grace period is never used up
+        let start = Instant::now();
+        router.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(first.is_closed());
+        assert!(second.is_closed());
+    }
+
+    #[test]
+    fn shutdown_when_transport_closes() {
+        let mut router = Router::new();
+        router.add_realm("shutdown_realm");
+        router.set_shutdown_grace_period(60000);
+
+        let (sender, receiver) = hello(&router, "shutdown_realm");
+        match receiver.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(Message::Welcome(..)) => {},
+            _ => panic!("Expected a welcome message")
+        }
+        // The client closes its connection rather than replying to the goodbye
+        let client = thread::spawn(move || {
+            match receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Envelope::Message(Message::Goodbye(..)) => sender.close().unwrap(),
+                _ => panic!("Expected a goodbye message")
+            }
+        });
+
+        let start = Instant::now();
+        router.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn close_one_realm() {
+        let mut router = Router::new();
+        router.add_realm("closing_realm");
+        router.add_realm("open_realm");
+        router.set_shutdown_grace_period(100);
+
+        let (_closing_sender, closing) = hello(&router, "closing_realm");
+        let (_open_sender, open) = hello(&router, "open_realm");
+        for receiver in &[&closing, &open] {
+            match receiver.recv_timeout(Duration::from_secs(1)).unwrap() {
+                Envelope::Message(Message::Welcome(..)) => {},
+                _ => panic!("Expected a welcome message")
+            }
+        }
+
+        assert!(router.close_realm("closing_realm"));
+        match closing.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(Message::Goodbye(_, reason)) => assert_eq!(reason, Reason::CloseRealm),
+            _ => panic!("Expected a goodbye message")
+        }
+        assert!(open.recv_timeout(Duration::from_millis(200)).is_err());
+        assert!(!router.close_realm("closing_realm"));
+
+        let (_late_sender, late) = hello(&router, "closing_realm");
+        match late.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(Message::Abort(_, reason)) => assert_eq!(reason, Reason::NoSuchRealm),
+            _ => panic!("Expected an abort message")
+        }
+    }
+}
@@ -1,48 +1,85 @@
-use super::{ConnectionHandler, ConnectionInfo, WAMP_JSON, ConnectionState};
-use ws::{Sender, Handler, Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, Request, Response, CloseCode};
+use super::{ConnectionHandler, ConnectionInfo, ConnectionState};
+use ws::{Handler, Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, Request, Response, CloseCode, Frame as WSFrame, OpCode};
 use std::sync::{Arc, Mutex};
 
 use std::collections::{HashMap};
-use serde_json;
-use serde::{Deserialize, Serialize};
-use rmp_serde::Deserializer as RMPDeserializer;
-use rmp_serde::Serializer;
-use utils::StructMapWriter;
-use std::io::Cursor;
-use messages::{Message, ErrorType, Reason};
+use std::mem;
+use messages::{Message, ErrorType, Reason, URI};
+use transport;
+use router::ratelimit::{LimitedOperation, RATE_LIMITED};
+use transport::batched::{self, FLUSH_TIMEOUT};
+use ws::util::Token;
+#[cfg(feature = "ssl")]
+use mio::tcp::TcpStream;
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslStream;
+use std::sync::atomic::Ordering;
 use ::{ID, WampResult, Error, ErrorKind, Dict, List};
 
+const PUBLISH_TYPE: u64 = 16;
+const CALL_TYPE: u64 = 48;
+static PAYLOAD_SIZE_EXCEEDED: &'static str = "wamp.error.payload_size_exceeded";
 
-pub fn send_message(info: &Arc<Mutex<ConnectionInfo>>, message: &Message) -> WampResult<()> {
-    let info = info.lock().unwrap();
 
-    debug!("Sending message {:?} via {}", message, info.protocol);
-    let send_result = if info.protocol == WAMP_JSON {
-        send_message_json(&info.sender, message)
-    } else {
-        send_message_msgpack(&info.sender, message)
+/// Sends a message to a connection.  The connection is only locked long enough to find its
+/// sender, so that other threads can use it while the message is being written.
+pub fn send_message(info: &Arc<Mutex<ConnectionInfo>>, message: &Message) -> WampResult<()> {
+    let (sender, serializer) = {
+        let info = info.lock().unwrap();
+        debug!("Sending message {:?} via {}", message, info.protocol);
+        (info.sender.clone(), info.serializer.clone())
     };
-    match send_result {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Error::new(ErrorKind::WSError(e)))
-    }
+    sender.send_message(&*serializer, message)
 }
 
-fn send_message_json(sender: &Sender, message: &Message) -> WSResult<()> {
-    // Send the message
-    sender.send(WSMessage::Text(serde_json::to_string(message).unwrap()))
-
-}
-
-fn send_message_msgpack(sender: &Sender, message: &Message) -> WSResult<()> {
-    // Send the message
-    let mut buf: Vec<u8> = Vec::new();
-    message.serialize(&mut Serializer::with(&mut buf, StructMapWriter)).unwrap();
-    sender.send(WSMessage::Binary(buf))
+fn rate_limited(error_type: ErrorType, request_id: ID) -> Error {
+    Error::new(ErrorKind::ErrorReason(error_type, request_id, Reason::CustomReason(URI::new(RATE_LIMITED))))
 }
 
 impl ConnectionHandler {
 
+    /// Handles a message that arrived without being serialized, as on a local connection.
+    pub fn on_wamp_message(&mut self, message: Message) -> WSResult<()> {
+        match self.handle_message(message) {
+            Err(e) => self.on_message_error(e),
+            _ => Ok(())
+        }
+    }
+
+    fn on_single_message(&mut self, msg: WSMessage) -> WSResult<()> {
+        let max_size = self.router.max_message_size.load(Ordering::Relaxed);
+        if transport::message_len(&msg) > max_size {
+            return self.on_oversized_message(msg, max_size);
+        }
+        let message = match self.parse_message(msg) {
+            Err(e) => return self.on_message_error(e),
+            Ok(m) => m
+        };
+        self.on_wamp_message(message)
+    }
+
+    /// Answers a message that is too large to be deserialized.  Calls and publications get an
+    /// error, since the client may be waiting on them, but anything else aborts the session.
+    fn on_oversized_message(&mut self, msg: WSMessage, max_size: usize) -> WSResult<()> {
+        let serializer = self.info.lock().unwrap().serializer.clone();
+        let header = match msg {
+            WSMessage::Text(ref payload) => serializer.peek(payload.as_bytes()),
+            WSMessage::Binary(ref payload) => serializer.peek(payload)
+        };
+        warn!("Received a {} byte message, which is larger than the maximum of {} bytes", transport::message_len(&msg), max_size);
+        match header {
+            Some((PUBLISH_TYPE, request_id)) => self.send_error(ErrorType::Publish, request_id, Reason::CustomReason(URI::new(PAYLOAD_SIZE_EXCEEDED))),
+            Some((CALL_TYPE, request_id)) => self.send_error(ErrorType::Call, request_id, Reason::CustomReason(URI::new(PAYLOAD_SIZE_EXCEEDED))),
+            _ => {
+                self.remove();
+                match self.abort(Reason::CustomReason(URI::new("wamp.error.protocol_violation")), "Message exceeded the maximum message size") {
+                    Err(e) => self.on_message_error(e),
+                    Ok(()) => Ok(())
+                }
+            }
+        }
+    }
+
     fn handle_message(&mut self, message: Message) -> WampResult<()> {
         debug!("Recieved message {:?}", message);
         match message {
@@ -50,9 +87,19 @@ impl ConnectionHandler {
                 self.handle_hello(realm, details)
             },
             Message::Subscribe(request_id, options, topic) => {
+                if !self.within_rate_limit(LimitedOperation::Subscribe) {
+                    return Err(rate_limited(ErrorType::Subscribe, request_id));
+                }
                 self.handle_subscribe(request_id,  options, topic)
             },
             Message::Publish(request_id, options, topic, args, kwargs) => {
+                if !self.within_rate_limit(LimitedOperation::Publish) {
+                    if options.should_acknowledge() {
+                        return Err(rate_limited(ErrorType::Publish, request_id));
+                    }
+                    debug!("Dropping publication to {} that exceeded the rate limit", topic.uri);
+                    return Ok(());
+                }
                 self.handle_publish(request_id, options, topic, args, kwargs)
             },
             Message::Unsubscribe(request_id, topic_id) => {
@@ -68,6 +115,9 @@ impl ConnectionHandler {
                 self.handle_unregister(request_id, procedure_id)
             },
             Message::Call(request_id, options, procedure, args, kwargs) => {
+                if !self.within_rate_limit(LimitedOperation::Call) {
+                    return Err(rate_limited(ErrorType::Call, request_id));
+                }
                 self.handle_call(request_id, options, procedure, args, kwargs)
             },
             Message::Yield(invocation_id, options, args, kwargs) => {
@@ -87,11 +137,15 @@ impl ConnectionHandler {
             debug!("Responding to error message for invocation (id: {})", request_id);
             match self.realm {
                 Some(ref realm) => {
-                    let mut realm = realm.lock().unwrap();
-                    let mut manager = &mut realm.registration_manager;
-                    if let Some((call_id, callee)) = manager.active_calls.remove(&request_id) {
+                    let active_call = realm.lock().unwrap().registration_manager.active_calls.remove(&request_id);
+                    if let Some((call_id, callee)) = active_call {
                         let error_message = Message::Error(ErrorType::Call, call_id, details, reason, args, kwargs);
-                        send_message(&callee, &error_message)
+                        // As with results, a caller that can't be sent the error is no reason to
+                        // close the callee's connection
+                        if let Err(e) = send_message(&callee, &error_message) {
+                            warn!("Could not send error to caller: {}", e);
+                        }
+                        Ok(())
                     } else {
                         Err(Error::new(ErrorKind::InvalidState("Recieved an error message for a call that wasn't sent")))
                     }
@@ -105,25 +159,8 @@ impl ConnectionHandler {
     }
 
     fn parse_message(&self, msg: WSMessage) -> WampResult<Message> {
-        match msg {
-            WSMessage::Text(payload) => {
-                match serde_json::from_str(&payload) {
-                    Ok(message) => Ok(message),
-                    Err(e) => Err(Error::new(ErrorKind::JSONError(e)))
-                }
-            },
-            WSMessage::Binary(payload) => {
-                let mut de = RMPDeserializer::new(Cursor::new(payload));
-                match Deserialize::deserialize(&mut de) {
-                    Ok(message) => {
-                        Ok(message)
-                    },
-                    Err(e) => {
-                        Err(Error::new(ErrorKind::MsgPackError(e)))
-                    }
-                }
-            }
-        }
+        let serializer = self.info.lock().unwrap().serializer.clone();
+        transport::parse_message(&*serializer, msg)
     }
 
     fn send_error(&self, err_type: ErrorType, request_id: ID, reason: Reason) -> WSResult<()> {
@@ -156,8 +193,17 @@ impl ConnectionHandler {
                 error!("Could not parse MsgPack: {}", e.description());
                 self.terminate_connection()
             },
+            ErrorKind::CBORError(e) => {
+                error!("Could not parse CBOR: {}", e);
+                self.terminate_connection()
+            },
+            ErrorKind::SerializationError(e) => {
+                error!("Could not serialize message: {}", e);
+                self.terminate_connection()
+            },
             ErrorKind::MalformedData => {
-                unimplemented!()
+                error!("Could not split batched message");
+                self.terminate_connection()
             },
             ErrorKind::InvalidMessageType(msg) => {
                 error!("Router unable to handle message {:?}", msg);
@@ -197,18 +243,79 @@ impl Handler for ConnectionHandler {
 
     fn on_message(&mut self, msg: WSMessage) -> WSResult<()> {
         debug!("Receveied message: {:?}", msg);
-        let message = match self.parse_message(msg) {
-            Err(e) => return self.on_message_error(e),
-            Ok(m) => m
-        };
-        match self.handle_message(message) {
-            Err(e) => self.on_message_error(e),
-            _ => Ok(())
+        if self.info.lock().unwrap().sender.is_batched() {
+            let messages = match batched::split(msg) {
+                Ok(messages) => messages,
+                Err(_) => return self.on_message_error(Error::new(ErrorKind::MalformedData))
+            };
+            for msg in messages {
+                try!(self.on_single_message(msg));
+            }
+            Ok(())
+        } else {
+            self.on_single_message(msg)
+        }
+    }
+
+    /// Refuses messages sent in fragments once they add up to more than the maximum message
+    /// size, rather than putting them back together.  A message sent in a single frame that is
+    /// too large is passed on, to be answered by `on_oversized_message`.  Pongs are passed to the
+    /// thread writing to the connection, which waits for them when the client falls behind.
+    fn on_frame(&mut self, frame: WSFrame) -> WSResult<Option<WSFrame>> {
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(WSError::new(WSErrorKind::Protocol, "Encountered frame with reserved bits set."));
+        }
+        if frame.opcode() == OpCode::Pong {
+            if let Some(ref window) = self.window {
+                window.acknowledge(frame.payload());
+            }
+        }
+        if frame.is_control() {
+            return Ok(Some(frame));
+        }
+        if frame.opcode() == OpCode::Continue {
+            self.fragmented_len += frame.payload().len();
+        } else if frame.is_final() {
+            return Ok(Some(frame));
+        } else {
+            self.fragmented_len = frame.payload().len();
+        }
+        let max_size = self.router.max_message_size.load(Ordering::Relaxed);
+        if self.fragmented_len > max_size {
+            warn!("Received a fragmented message of more than {} bytes, which is larger than the maximum of {} bytes", self.fragmented_len, max_size);
+            return Err(WSError::new(WSErrorKind::Capacity, "Message exceeded the maximum message size"));
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_timeout(&mut self, token: Token) -> WSResult<()> {
+        if token == FLUSH_TIMEOUT {
+            let info = self.info.lock().unwrap();
+            try!(info.sender.flush());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "ssl")]
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> WSResult<SslStream<TcpStream>> {
+        match self.tls_acceptor {
+            Some(ref acceptor) => {
+                let (stream, identity) = try!(acceptor.accept(stream));
+                self.tls_identity = Some(identity);
+                Ok(stream)
+            },
+            None => Err(WSError::new(WSErrorKind::Internal, "Recieved a TLS connection on a listener that was not configured for TLS"))
         }
     }
 
     fn on_close(&mut self, _code: CloseCode, _reason: &str) {
-        let state = self.info.lock().unwrap().state.clone();
+        // Once the transport has closed, the connection is done whatever state it was in, so that
+        // `Router::close_sessions` stops waiting for it
+        let state = {
+            let mut info = self.info.lock().unwrap();
+            info.sender.stop_writer();
+            mem::replace(&mut info.state, ConnectionState::Disconnected)
+        };
         if state != ConnectionState::Disconnected {
             trace!("Client disconnected.  Closing connection");
             self.terminate_connection().ok();
@@ -2,21 +2,56 @@ mod handshake;
 mod messaging;
 mod pubsub;
 mod rpc;
+mod local;
+mod ratelimit;
+mod listener;
+mod rawsocket;
+#[cfg(feature = "async")]
+mod async_rawsocket;
 
+pub use self::local::LocalConnector;
+pub use self::ratelimit::{RateLimit, LimitedOperation};
+pub use self::listener::{Listener, ListenerAddress, ListenerConfig, Endpoint};
 
-use ws::{listen as ws_listen, Sender, Result as WSResult };
+
+use ws::{Result as WSResult, Error as WSError, Builder as WSBuilder, Settings as WSSettings, Sender as WSSender, Message as WSMessage};
+#[cfg(feature = "async")]
+use futures::Future;
+#[cfg(feature = "async")]
+use tokio::net::TcpListener;
+use std::cmp;
+#[cfg(feature = "async")]
+use std::io;
+use std::net;
+#[cfg(feature = "async")]
+use std::net::SocketAddr;
+#[cfg(feature = "ssl")]
+use transport::tls::{self, TlsServerConfig, TlsAcceptor, TlsIdentity};
+use ::{WampResult, Error, ErrorKind};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
 use std::collections::{HashMap};
 use std::marker::Sync;
 use rand::{thread_rng};
 use rand::distributions::{Range, IndependentSample};
 use router::pubsub::SubscriptionPatternNode;
 use router::rpc::RegistrationPatternNode;
+use router::ratelimit::{RateLimits, Buckets};
 use super::ID;
-use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 use router::messaging::send_message;
-use messages::{ErrorDetails, Reason, Message};
+use messages::{ErrorDetails, Reason, Message, URI};
+use transport::{self, Sender};
+use transport::websocket::Window;
+use transport::outbound::{Queue, DEFAULT_LIMIT};
+pub use transport::outbound::SlowConsumerPolicy;
+use serializer::{Serializer, Serializers, JsonSerializer};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
 
 
 struct SubscriptionManager {
@@ -33,7 +68,8 @@ struct RegistrationManager {
 struct Realm {
     subscription_manager: SubscriptionManager,
     registration_manager: RegistrationManager,
-    connections: Vec<Arc<Mutex<ConnectionInfo>>>
+    connections: Vec<Arc<Mutex<ConnectionInfo>>>,
+    closed: bool
 }
 
 pub struct Router {
@@ -42,6 +78,13 @@ pub struct Router {
 
 struct RouterInfo {
     realms: Mutex<HashMap<String, Arc<Mutex<Realm>>>>,
+    batch_flush_interval: AtomicUsize,
+    serializers: Mutex<Serializers>,
+    outbound_limit: AtomicUsize,
+    slow_consumer_policy: Mutex<SlowConsumerPolicy>,
+    max_message_size: AtomicUsize,
+    rate_limits: Mutex<RateLimits>,
+    shutdown_grace_period: AtomicUsize,
 }
 
 struct ConnectionHandler {
@@ -50,13 +93,27 @@ struct ConnectionHandler {
     realm: Option<Arc<Mutex<Realm>>>,
     subscribed_topics: Vec<ID>,
     registered_procedures: Vec<ID>,
+    rate_limits: Buckets,
+    role_rate_limits: Option<Arc<Mutex<Buckets>>>,
+    config: Arc<ListenerConfig>,
+    /// What the peer has acknowledged reading, for WebSocket connections sending through a queue
+    window: Option<Arc<Window>>,
+    /// The length of the fragmented WebSocket message being received, so far
+    fragmented_len: usize,
+    #[cfg(feature = "ssl")]
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    #[cfg(feature = "ssl")]
+    tls_identity: Option<Arc<Mutex<Option<TlsIdentity>>>>,
 }
 
 pub struct ConnectionInfo {
     state: ConnectionState,
     sender: Sender,
     protocol: String,
-    id: u64
+    serializer: Arc<Serializer>,
+    id: u64,
+    authid: Option<String>,
+    authrole: Option<String>
 }
 
 #[derive(Clone, PartialEq)]
@@ -67,9 +124,6 @@ enum ConnectionState {
     Disconnected
 }
 
-static WAMP_JSON:&'static str = "wamp.2.json";
-static WAMP_MSGPACK:&'static str = "wamp.2.msgpack";
-
 fn random_id() -> u64 {
     let mut rng = thread_rng();
     // TODO make this a constant
@@ -77,6 +131,12 @@ fn random_id() -> u64 {
     between.ind_sample(&mut rng)
 }
 
+/// The goodbye message sent to a connection that is closed because it fell too far behind.
+fn slow_consumer_farewell(serializer: &Serializer) -> WampResult<WSMessage> {
+    let farewell = Message::Goodbye(ErrorDetails::new(), Reason::CustomReason(URI::new("wamp.close.slow_consumer")));
+    transport::encode_message(serializer, &farewell)
+}
+
 
 unsafe impl Sync for Router {}
 
@@ -86,30 +146,216 @@ impl Router {
         Router{
             info: Arc::new(RouterInfo {
                 realms: Mutex::new(HashMap::new()),
+                batch_flush_interval: AtomicUsize::new(0),
+                serializers: Mutex::new(Serializers::new()),
+                outbound_limit: AtomicUsize::new(DEFAULT_LIMIT),
+                slow_consumer_policy: Mutex::new(SlowConsumerPolicy::Disconnect),
+                max_message_size: AtomicUsize::new(transport::rawsocket::max_length(transport::rawsocket::MAX_LENGTH_EXPONENT)),
+                rate_limits: Mutex::new(RateLimits::new()),
+                shutdown_grace_period: AtomicUsize::new(5000),
             })
         }
     }
 
-    pub fn listen(&self, url: &str) -> JoinHandle<()> {
+    /// Listens for WebSocket connections on the given address.
+    ///
+    /// Returns an error if the address could not be bound.  The listener runs until the returned
+    /// handle is stopped or dropped.
+    pub fn listen(&self, url: &str) -> WampResult<Listener> {
+        self.listen_with(Endpoint::WebSocket(url.to_string()), ListenerConfig::new())
+    }
+
+    /// Listens for secure WebSocket (`wss://`) connections on the given address.
+    ///
+    /// Returns an error if the certificates or keys in the configuration could not be loaded, or
+    /// the address could not be bound.
+    #[cfg(feature = "ssl")]
+    pub fn listen_tls(&self, url: &str, config: &TlsServerConfig) -> WampResult<Listener> {
+        self.listen_with(Endpoint::SecureWebSocket(url.to_string(), config.clone()), ListenerConfig::new())
+    }
+
+    /// Listens for RawSocket connections over TCP on the given address, running each connection on
+    /// a thread of its own.
+    pub fn listen_rawsocket(&self, url: &str) -> WampResult<Listener> {
+        self.listen_with(Endpoint::RawSocket(url.to_string()), ListenerConfig::new())
+    }
+
+    /// Listens for RawSocket connections on a Unix domain socket at the given path.
+    ///
+    /// Access to the router can then be controlled with the permissions of the socket file, which
+    /// is removed when the listener is stopped.  Returns an error if the socket could not be bound.
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(&self, path: P) -> WampResult<Listener> {
+        self.listen_with(Endpoint::Unix(path.as_ref().to_path_buf()), ListenerConfig::new())
+    }
+
+    /// Listens on the given endpoint, applying the configuration to every connection it accepts.
+    ///
+    /// A router can listen on any number of endpoints at once, all of which share its realms.  For
+    /// example, a public WebSocket listener could be limited to one realm, while a Unix domain
+    /// socket for local services allows every realm.
+    pub fn listen_with(&self, endpoint: Endpoint, config: ListenerConfig) -> WampResult<Listener> {
         let router_info = self.info.clone();
+        let config = Arc::new(config);
+        match endpoint {
+            Endpoint::WebSocket(url) => {
+                self.listen_websocket(&url, self.info.websocket_settings(), move |sender| {
+                    let mut handler = ConnectionHandler::new(router_info.clone(), Sender::WebSocket(sender));
+                    handler.config = config.clone();
+                    handler
+                })
+            },
+            #[cfg(feature = "ssl")]
+            Endpoint::SecureWebSocket(url, tls_config) => {
+                let acceptor = Arc::new(try!(tls::build_acceptor(&tls_config)));
+                let settings = WSSettings {
+                    encrypt_server: true,
+                    ..self.info.websocket_settings()
+                };
+                self.listen_websocket(&url, settings, move |sender| {
+                    let mut handler = ConnectionHandler::new(router_info.clone(), Sender::WebSocket(sender));
+                    handler.tls_acceptor = Some(acceptor.clone());
+                    handler.config = config.clone();
+                    handler
+                })
+            },
+            Endpoint::RawSocket(url) => {
+                let listener = try!(net::TcpListener::bind(&url[..]).map_err(|e| Error::new(ErrorKind::WSError(WSError::from(e)))));
+                let address = ListenerAddress::Tcp(try!(listener.local_addr().map_err(|e| Error::new(ErrorKind::WSError(WSError::from(e))))));
+                Ok(rawsocket::listen(listener, address, router_info, config))
+            },
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let listener = try!(UnixListener::bind(&path).map_err(|e| Error::new(ErrorKind::WSError(WSError::from(e)))));
+                Ok(rawsocket::listen(listener, ListenerAddress::Unix(path), router_info, config))
+            }
+        }
+    }
+
+    /// Binds a WebSocket listener and runs it on a thread of its own.  The socket is built on that
+    /// thread, since its connection handlers never leave it.
+    fn listen_websocket<F>(&self, url: &str, settings: WSSettings, factory: F) -> WampResult<Listener>
+        where F: FnMut(WSSender) -> ConnectionHandler + Send + 'static
+    {
         let url = url.to_string();
-        thread::spawn(move ||{
-            ws_listen(&url[..], |sender| {
-                ConnectionHandler {
-                    info: Arc::new(Mutex::new(ConnectionInfo{
-                        state: ConnectionState::Initializing,
-                        sender: sender,
-                        protocol: String::new(),
-                        id: random_id()
-                    })),
-                    subscribed_topics: Vec::new(),
-                    registered_procedures: Vec::new(),
-                    realm: None,
-                    router: router_info.clone()
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let bound = WSBuilder::new().with_settings(settings).build(factory).and_then(|socket| {
+                socket.bind(&url[..])
+            }).and_then(|socket| {
+                let address = try!(socket.local_addr().map_err(WSError::from));
+                Ok((socket, address))
+            });
+            let socket = match bound {
+                Ok((socket, address)) => {
+                    tx.send(Ok((address, socket.broadcaster()))).ok();
+                    socket
+                },
+                Err(e) => {
+                    tx.send(Err(e)).ok();
+                    return;
                 }
-            }).unwrap();
-        })
+            };
+            if let Err(e) = socket.run() {
+                error!("WebSocket listener on {} failed: {}", url, e);
+            }
+        });
+        let bound = try!(rx.recv().map_err(|_| Error::new(ErrorKind::ConnectionLost)));
+        let (address, sender) = try!(bound.map_err(|e| Error::new(ErrorKind::WSError(e))));
+        info!("Listening for WebSocket connections on {}", address);
+        Ok(Listener::websocket(address, sender, thread))
+    }
+
+    /// Listens for RawSocket connections over TCP, running each connection as a task rather than
+    /// on a thread of its own.
+    ///
+    /// The returned future accepts connections until it is dropped, and must be run on a tokio
+    /// runtime (for example with `tokio::run`).  Requires the `async` feature.
+    ///
+    /// This is only a partial asynchronous core.  Only RawSocket over TCP can be served as tasks;
+    /// the other `listen` methods still use threads.  Messages are handled synchronously on the
+    /// runtime's threads, which block while a message waits for its connection and the realm it
+    /// belongs to.
+    #[cfg(feature = "async")]
+    pub fn listen_async(&self, addr: &SocketAddr) -> io::Result<impl Future<Item = (), Error = ()> + Send> {
+        self.listen_async_with(addr, ListenerConfig::new())
+    }
+
+    /// Listens for RawSocket connections over TCP as tasks, like `listen_async`, applying the
+    /// configuration to every connection it accepts.
+    #[cfg(feature = "async")]
+    pub fn listen_async_with(&self, addr: &SocketAddr, config: ListenerConfig) -> io::Result<impl Future<Item = (), Error = ()> + Send> {
+        let listener = try!(TcpListener::bind(addr));
+        Ok(async_rawsocket::listen(listener, self.info.clone(), Arc::new(config)))
+    }
+
+    /// Sets how many bytes of messages can wait to be sent to a connection, and what happens to a
+    /// connection that falls so far behind that more are waiting.  Defaults to 4MB, after which the
+    /// connection is closed with a `wamp.close.slow_consumer` goodbye message.  Only events are
+    /// ever dropped, so a connection that falls behind on other messages (such as call results)
+    /// is disconnected whatever the policy.
+    ///
+    /// Each WebSocket connection also has its own thread writing to it, which sends no more than
+    /// the limit ahead of what the client has acknowledged with pongs, so that the router notices
+    /// a client that has stopped reading.  Local connections are not limited.  This applies to
+    /// connections made after it is called.
+    pub fn set_outbound_limit(&mut self, limit: usize, policy: SlowConsumerPolicy) {
+        self.info.outbound_limit.store(limit, Ordering::Relaxed);
+        *self.info.slow_consumer_policy.lock().unwrap() = policy;
+    }
+
+    /// Sets the largest message (in bytes) the router accepts from clients.  Larger calls and
+    /// publications are answered with a `wamp.error.payload_size_exceeded` error without being
+    /// deserialized, and any other message that is too large aborts the session.  Defaults to 16MB.
+    ///
+    /// RawSocket clients are told the limit during the handshake, rounded down to a power of two
+    /// (with a minimum of 512 bytes), and are disconnected if they send a larger frame.  WebSocket
+    /// clients are disconnected if they send a message in fragments that add up to more than the
+    /// limit.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.info.max_message_size.store(size, Ordering::Relaxed);
+    }
 
+    /// Limits how often each session can perform an operation.  Calls and subscriptions over the
+    /// limit are refused with a `wamp.error.rate_limited` error, as are publications that asked to
+    /// be acknowledged.  Other publications are dropped.
+    ///
+    /// This applies to sessions established after it is called.
+    pub fn set_rate_limit(&mut self, operation: LimitedOperation, limit: RateLimit) {
+        self.info.rate_limits.lock().unwrap().set_session_limit(operation, limit);
+    }
+
+    /// Limits how often all of the sessions with the given authrole can perform an operation
+    /// between them.  Sessions that were not authenticated have the `anonymous` authrole.
+    ///
+    /// Sessions are only limited if their authrole had limits when they were established, but
+    /// changing an authrole's limits later applies to all of those sessions.
+    pub fn set_role_rate_limit(&mut self, authrole: &str, operation: LimitedOperation, limit: RateLimit) {
+        self.info.rate_limits.lock().unwrap().set_role_limit(authrole, operation, limit);
+    }
+
+    /// Returns a connector that clients in this process can use to connect to the router directly,
+    /// without a socket and without serializing messages.  See `Connection::new_local`.
+    ///
+    /// Local connections don't go through a listener, so no `ListenerConfig` applies to them:
+    /// they can join any realm, and are let in anonymously.
+    pub fn local_connector(&self) -> LocalConnector {
+        LocalConnector::new(self.info.clone())
+    }
+
+    /// Sets how long (in milliseconds) messages to clients using a batched serializer are held
+    /// back, so that they can be sent together in one frame.  Defaults to 0, which sends every
+    /// message in its own frame.
+    ///
+    /// This applies to connections made after it is called.
+    pub fn set_batch_flush_interval(&mut self, interval: u64) {
+        self.info.batch_flush_interval.store(interval as usize, Ordering::Relaxed);
+    }
+
+    /// Adds a serializer that clients can choose when connecting, in addition to JSON, MsgPack and CBOR.
+    /// If the serializer has the same protocol name as an existing one, it replaces it.
+    pub fn add_serializer(&mut self, serializer: Arc<Serializer>) {
+        self.info.serializers.lock().unwrap().add(serializer);
     }
 
     pub fn add_realm(&mut self, realm: &str) {
@@ -119,6 +365,7 @@ impl Router {
         }
         realms.insert(realm.to_string(), Arc::new(Mutex::new(Realm {
             connections: Vec::new(),
+            closed: false,
             subscription_manager: SubscriptionManager {
                 subscriptions: SubscriptionPatternNode::new(),
                 subscription_ids_to_uris: HashMap::new()
@@ -132,28 +379,117 @@ impl Router {
         debug!("Added realm {}", realm);
     }
 
+    /// Says goodbye to every session, then closes the connections of those that haven't replied
+    /// within the grace period (see `set_shutdown_grace_period`).  Returns as soon as every
+    /// session has replied.
     pub fn shutdown(&self) {
-        for realm in self.info.realms.lock().unwrap().values() {
-            for connection in realm.lock().unwrap().connections.iter() {
-                send_message(connection, &Message::Goodbye(ErrorDetails::new(), Reason::SystemShutdown)).ok();
-                let mut connection = connection.lock().unwrap();
-                connection.state = ConnectionState::ShuttingDown;
-            }
+        let connections = self.info.realms.lock().unwrap().values().flat_map(|realm| {
+            realm.lock().unwrap().connections.clone()
+        }).collect();
+        self.close_sessions(connections, Reason::SystemShutdown);
+    }
+
+    /// Closes a realm, saying goodbye to each of its sessions with `wamp.error.close_realm` in the
+    /// same way as `shutdown`.  The realm is removed straight away, so no new sessions can join it.
+    ///
+    /// Returns false if there was no such realm.
+    pub fn close_realm(&mut self, realm: &str) -> bool {
+        let realm = match self.info.realms.lock().unwrap().remove(realm) {
+            Some(realm) => realm,
+            None => return false
+        };
+        let connections = {
+            let mut realm = realm.lock().unwrap();
+            realm.closed = true;
+            realm.connections.clone()
+        };
+        self.close_sessions(connections, Reason::CloseRealm);
+        true
+    }
+
+    /// Sets how long (in milliseconds) `shutdown` and `close_realm` wait for sessions to reply to
+    /// their goodbye message, before closing their connections anyway.  Defaults to 5 seconds.
+    pub fn set_shutdown_grace_period(&mut self, grace_period: u64) {
+        self.info.shutdown_grace_period.store(grace_period as usize, Ordering::Relaxed);
+    }
+
+    fn close_sessions(&self, connections: Vec<Arc<Mutex<ConnectionInfo>>>, reason: Reason) {
+        for connection in connections.iter() {
+            connection.lock().unwrap().state = ConnectionState::ShuttingDown;
+            send_message(connection, &Message::Goodbye(ErrorDetails::new(), reason.clone())).ok();
         }
-        info!("Goodbye messages sent.  Waiting 5 seconds for response");
-        thread::sleep(Duration::from_secs(5));
-        for realm in self.info.realms.lock().unwrap().values() {
-            for connection in realm.lock().unwrap().connections.iter() {
-                let connection = connection.lock().unwrap();
-                connection.sender.shutdown().ok();
-            }
+        let grace_period = Duration::from_millis(self.info.shutdown_grace_period.load(Ordering::Relaxed) as u64);
+        info!("Goodbye messages sent.  Waiting up to {}ms for responses", self.info.shutdown_grace_period.load(Ordering::Relaxed));
+        let deadline = Instant::now() + grace_period;
+        let is_closed = |connection: &Arc<Mutex<ConnectionInfo>>| {
+            connection.lock().unwrap().state == ConnectionState::Disconnected
+        };
+        while Instant::now() < deadline && !connections.iter().all(&is_closed) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for connection in connections.iter().filter(|connection| !is_closed(connection)) {
+            let connection = connection.lock().unwrap();
+            connection.sender.shutdown().ok();
         }
     }
 }
 
 
 
+impl RouterInfo {
+    /// Creates the queue of messages waiting to be sent to a connection using the given serializer.
+    fn outbound_queue(&self, serializer: &Serializer) -> Queue {
+        Queue::new(self.outbound_limit.load(Ordering::Relaxed), *self.slow_consumer_policy.lock().unwrap(), slow_consumer_farewell(serializer).ok())
+    }
+
+    /// The length exponent announced in RawSocket handshakes, which limits the messages clients send.
+    fn length_exponent(&self) -> u8 {
+        transport::rawsocket::length_exponent(self.max_message_size.load(Ordering::Relaxed))
+    }
+
+    /// The settings for WebSocket listeners.  Frames the router sends are no larger than those it
+    /// accepts.  The input buffer starts small and grows as frames arrive, since sizing it for the
+    /// largest message would take that much memory for every connection; messages that are too
+    /// large are refused by `ConnectionHandler::on_frame` instead.
+    fn websocket_settings(&self) -> WSSettings {
+        let max_message_size = self.max_message_size.load(Ordering::Relaxed);
+        let defaults = WSSettings::default();
+        WSSettings {
+            fragment_size: cmp::min(max_message_size, defaults.fragment_size),
+            ..defaults
+        }
+    }
+}
+
 impl ConnectionHandler {
+    fn new(router: Arc<RouterInfo>, sender: Sender) -> ConnectionHandler {
+        let rate_limits = router.rate_limits.lock().unwrap().session_buckets();
+        ConnectionHandler {
+            info: Arc::new(Mutex::new(ConnectionInfo{
+                state: ConnectionState::Initializing,
+                sender: sender,
+                protocol: String::new(),
+                serializer: Arc::new(JsonSerializer),
+                id: random_id(),
+                authid: None,
+                authrole: None
+            })),
+            subscribed_topics: Vec::new(),
+            registered_procedures: Vec::new(),
+            rate_limits: rate_limits,
+            role_rate_limits: None,
+            config: Arc::new(ListenerConfig::new()),
+            window: None,
+            fragmented_len: 0,
+            realm: None,
+            router: router,
+            #[cfg(feature = "ssl")]
+            tls_acceptor: None,
+            #[cfg(feature = "ssl")]
+            tls_identity: None,
+        }
+    }
+
     fn remove(&mut self) {
         match self.realm {
             Some(ref realm) => {
@@ -1,28 +1,78 @@
 mod patterns;
-use super::{ConnectionHandler, random_id};
+use super::{ConnectionHandler, ConnectionInfo, random_id};
 
 use router::messaging::send_message;
 use messages::{Message, URI, SubscribeOptions, PublishOptions, EventDetails, ErrorType, Reason};
-use ::{List, Dict,  MatchingPolicy, WampResult, Error, ErrorKind};
+use transport;
+use ws::Message as WSMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use ::{List, Dict,  MatchingPolicy, WampResult, Error, ErrorKind, ID};
 pub use router::pubsub::patterns::SubscriptionPatternNode;
 
+/// The frames an event has been encoded into while it is sent to its subscribers.  Every
+/// subscriber to the same subscription receives exactly the same event, so it only needs to be
+/// serialized once for each serializer used by those subscribers.
+struct EncodedEvents {
+    frames: HashMap<(String, ID), WSMessage>,
+    shared: bool
+}
+
+impl EncodedEvents {
+    fn new() -> EncodedEvents {
+        EncodedEvents {
+            frames: HashMap::new(),
+            shared: true
+        }
+    }
+
+    /// Serializes the event again for every subscriber, as the router used to, for comparison.
+    #[cfg(test)]
+    fn unshared() -> EncodedEvents {
+        EncodedEvents {
+            frames: HashMap::new(),
+            shared: false
+        }
+    }
+
+    /// Sends the event for the given subscription, serializing it only if no other subscriber
+    /// with the same serializer has received it yet.
+    fn send(&mut self, subscriber: &Arc<Mutex<ConnectionInfo>>, subscription_id: ID, event: &Message) -> WampResult<()> {
+        let (sender, serializer) = {
+            let info = subscriber.lock().unwrap();
+            (info.sender.clone(), info.serializer.clone())
+        };
+        if sender.is_local() || !self.shared {
+            return sender.send_message(&*serializer, event);
+        }
+        let key = (serializer.protocol().to_string(), subscription_id);
+        if !self.frames.contains_key(&key) {
+            let frame = try!(transport::encode_message(&*serializer, event));
+            self.frames.insert(key.clone(), frame);
+        }
+        sender.send_encoded(event, &self.frames[&key])
+    }
+}
+
 impl ConnectionHandler{
     pub fn handle_subscribe(&mut self, request_id: u64, options: SubscribeOptions, topic: URI) -> WampResult<()> {
         debug!("Responding to subscribe message (id: {}, topic: {})", request_id, topic.uri);
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.subscription_manager;
-                let topic_id = {
+                let (topic_id, sent) = {
+                    let mut realm = realm.lock().unwrap();
+                    let mut manager = &mut realm.subscription_manager;
                     let topic_id = match manager.subscriptions.subscribe_with(&topic, self.info.clone(), options.pattern_match.clone()) {
                         Ok(topic_id) => topic_id,
                         Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Subscribe, request_id, e.reason())))
                     };
-                    self.subscribed_topics.push(topic_id);
-                    topic_id
+                    manager.subscription_ids_to_uris.insert(topic_id, (topic.uri, options.pattern_match == MatchingPolicy::Prefix));
+                    // Acknowledge the subscription while the realm is still locked, so that no
+                    // event for it can be sent to the subscriber first
+                    (topic_id, send_message(&self.info, &Message::Subscribed(request_id, topic_id)))
                 };
-                manager.subscription_ids_to_uris.insert(topic_id, (topic.uri, options.pattern_match == MatchingPolicy::Prefix));
-                send_message(&self.info, &Message::Subscribed(request_id, topic_id))
+                self.subscribed_topics.push(topic_id);
+                sent
             },
              None => {
                 Err(Error::new(ErrorKind::InvalidState("Recieved a message while not attached to a realm")))
@@ -33,17 +83,18 @@ impl ConnectionHandler{
     pub fn handle_unsubscribe(&mut self, request_id: u64, topic_id: u64) -> WampResult<()> {
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.subscription_manager;
-                let (topic_uri, is_prefix) =  match manager.subscription_ids_to_uris.get(&topic_id) {
-                    Some(&(ref uri, ref is_prefix)) => (uri.clone(), is_prefix.clone()),
-                    None => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unsubscribe, request_id, Reason::NoSuchSubscription)))
-                };
-
+                let topic_id = {
+                    let mut realm = realm.lock().unwrap();
+                    let mut manager = &mut realm.subscription_manager;
+                    let (topic_uri, is_prefix) =  match manager.subscription_ids_to_uris.get(&topic_id) {
+                        Some(&(ref uri, ref is_prefix)) => (uri.clone(), is_prefix.clone()),
+                        None => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unsubscribe, request_id, Reason::NoSuchSubscription)))
+                    };
 
-                let topic_id = match manager.subscriptions.unsubscribe_with(&topic_uri, &self.info, is_prefix) {
-                    Ok(topic_id) => topic_id,
-                    Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unsubscribe, request_id, e.reason())))
+                    match manager.subscriptions.unsubscribe_with(&topic_uri, &self.info, is_prefix) {
+                        Ok(topic_id) => topic_id,
+                        Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unsubscribe, request_id, e.reason())))
+                    }
                 };
                 self.subscribed_topics.retain(|id| {
                     *id != topic_id
@@ -58,17 +109,33 @@ impl ConnectionHandler{
 
     pub fn handle_publish(&mut self, request_id: u64, options: PublishOptions, topic: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
         debug!("Responding to publish message (id: {}, topic: {})", request_id, topic.uri);
+        let publication_id = try!(self.send_event(&topic, args, kwargs, EncodedEvents::new()));
+        if options.should_acknowledge() {
+            try!(send_message(&self.info, &Message::Published(request_id, publication_id)));
+        }
+        Ok(())
+    }
+
+    /// Sends an event to every subscriber of the topic, returning the event's publication ID.  The
+    /// realm is only locked while the subscribers are being found, so a subscriber that is slow to
+    /// receive events doesn't hold up anything else happening in the realm.
+    fn send_event(&mut self, topic: &URI, args: Option<List>, kwargs: Option<Dict>, mut encoded: EncodedEvents) -> WampResult<ID> {
         match self.realm {
             Some(ref realm) => {
-                let realm = realm.lock().unwrap();
-                let manager = &realm.subscription_manager;
+                let subscribers: Vec<_> = {
+                    let realm = realm.lock().unwrap();
+                    let manager = &realm.subscription_manager;
+                    info!("Current topic tree: {:?}", manager.subscriptions);
+                    manager.subscriptions.filter(topic.clone()).map(|(subscriber, topic_id, policy)| {
+                        (subscriber.clone(), topic_id, policy)
+                    }).collect()
+                };
                 let publication_id = random_id();
-                let mut event_message = Message::Event(1, publication_id, EventDetails::new(), args.clone(), kwargs.clone());
+                let mut event_message = Message::Event(1, publication_id, EventDetails::new(), args, kwargs);
                 let my_id = {
                     self.info.lock().unwrap().id.clone()
                 };
-                info!("Current topic tree: {:?}", manager.subscriptions);
-                for (subscriber, topic_id, policy) in manager.subscriptions.filter(topic.clone()) {
+                for (subscriber, topic_id, policy) in subscribers {
                     if subscriber.lock().unwrap().id != my_id {
                         if let Message::Event(ref mut old_topic, ref _publish_id, ref mut details, ref _args, ref _kwargs) = event_message {
                             *old_topic = topic_id;
@@ -78,13 +145,14 @@ impl ConnectionHandler{
                                 Some(topic.clone())
                             };
                         }
-                        try!(send_message(subscriber, &event_message));
+                        // The subscriber's connection is cleaned up when it closes, so the other
+                        // subscribers should still get the event
+                        if let Err(e) = encoded.send(&subscriber, topic_id, &event_message) {
+                            warn!("Could not send event to subscriber: {}", e);
+                        }
                     }
                 }
-                if options.should_acknowledge() {
-                    try!(send_message(&self.info, &Message::Published(request_id, publication_id)));
-                }
-                Ok(())
+                Ok(publication_id)
             },
             None => {
                 Err(Error::new(ErrorKind::InvalidState("Recieved a message while not attached to a realm")))
@@ -93,3 +161,438 @@ impl ConnectionHandler{
     }
 
 }
+
+#[cfg(test)]
+mod test {
+    use super::EncodedEvents;
+    use router::{Router, ListenerAddress, SlowConsumerPolicy, ConnectionHandler};
+    use client::Connection;
+    use serializer::{Serializer, JsonSerializer};
+    use transport::Sender;
+    use transport::local::{self, Envelope};
+    use transport::outbound;
+    use transport::rawsocket::{self, Handshake, FrameType, MAX_LENGTH_EXPONENT};
+    use messages::{Message, URI, Value, HelloDetails, ClientRoles, SubscribeOptions, PublishOptions, MatchingPolicy, Reason};
+    use eventual::Async;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use ::{WampResult, ID};
+
+    /// A RawSocket client speaking JSON, which sees messages exactly as the router sends them.
+    struct RawClient {
+        stream: TcpStream
+    }
+
+    impl RawClient {
+        fn connect(address: &SocketAddr, realm: &str) -> RawClient {
+            RawClient::connect_with_exponent(address, realm, MAX_LENGTH_EXPONENT)
+        }
+
+        /// Connects, announcing that the client accepts messages of up to `max_length(length_exponent)` bytes.
+        fn connect_with_exponent(address: &SocketAddr, realm: &str, length_exponent: u8) -> RawClient {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            rawsocket::write_handshake(&mut stream, length_exponent, JsonSerializer.raw_socket_id().unwrap()).unwrap();
+            match rawsocket::read_handshake(&mut stream).unwrap() {
+                Handshake::Accepted{..} => {},
+                Handshake::Refused(error) => panic!("The router refused the handshake (error {})", error)
+            }
+            let mut client = RawClient {
+                stream: stream
+            };
+            client.send(Message::Hello(URI::new(realm), HelloDetails::new(ClientRoles::new())));
+            match client.receive() {
+                Message::Welcome(..) => {},
+                message => panic!("Expected a welcome message, got {:?}", message)
+            }
+            client
+        }
+
+        fn send(&mut self, message: Message) {
+            let payload = JsonSerializer.serialize(&message).unwrap();
+            rawsocket::write_frame(&mut self.stream, FrameType::Message, &payload).unwrap();
+        }
+
+        fn receive(&mut self) -> Message {
+            let (_, payload) = rawsocket::read_frame(&mut self.stream, rawsocket::max_length(MAX_LENGTH_EXPONENT)).unwrap();
+            JsonSerializer.deserialize(&payload).unwrap()
+        }
+
+        fn subscribe(&mut self, request_id: u64, topic: &str, policy: MatchingPolicy) -> u64 {
+            let mut options = SubscribeOptions::new();
+            options.pattern_match = policy;
+            self.send(Message::Subscribe(request_id, options, URI::new(topic)));
+            match self.receive() {
+                Message::Subscribed(id, subscription_id) if id == request_id => subscription_id,
+                message => panic!("Expected a subscribed message, got {:?}", message)
+            }
+        }
+    }
+
+    /// The JSON serializer, counting how many events it serializes.
+    struct CountingSerializer {
+        events: AtomicUsize
+    }
+
+    impl Serializer for CountingSerializer {
+        fn protocol(&self) -> &str {
+            JsonSerializer.protocol()
+        }
+
+        fn is_binary(&self) -> bool {
+            false
+        }
+
+        fn serialize(&self, message: &Message) -> WampResult<Vec<u8>> {
+            if let Message::Event(..) = *message {
+                self.events.fetch_add(1, Ordering::SeqCst);
+            }
+            JsonSerializer.serialize(message)
+        }
+
+        fn deserialize(&self, payload: &[u8]) -> WampResult<Message> {
+            JsonSerializer.deserialize(payload)
+        }
+    }
+
+    /// A session whose messages wait in a queue that nothing reads, as if a RawSocket or WebSocket
+    /// client had joined the realm.
+    fn queued_session(router: &Router, realm: &str, serializer: Arc<Serializer>) -> ConnectionHandler {
+        let queue = router.info.outbound_queue(&*serializer);
+        let mut handler = ConnectionHandler::new(router.info.clone(), Sender::Queued(outbound::Sender::new(queue, usize::max_value())));
+        handler.info.lock().unwrap().serializer = serializer;
+        handler.handle_hello(URI::new(realm), HelloDetails::new(ClientRoles::new())).unwrap();
+        handler
+    }
+
+    fn subscribe(handler: &mut ConnectionHandler, request_id: ID, topic: &str, policy: MatchingPolicy) {
+        let mut options = SubscribeOptions::new();
+        options.pattern_match = policy;
+        handler.handle_subscribe(request_id, options, URI::new(topic)).unwrap();
+    }
+
+    fn receive(receiver: &local::Receiver) -> Message {
+        match receiver.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(message) => message,
+            Envelope::Close => panic!("The router closed the connection")
+        }
+    }
+
+    #[test]
+    fn subscribed_before_events() {
+        let mut router = Router::new();
+        router.add_realm("ordering_realm");
+
+        let (client_sender, receiver) = local::channel();
+        let router_sender = router.local_connector().connect(client_sender);
+        router_sender.send_message(Message::Hello(URI::new("ordering_realm"), HelloDetails::new(ClientRoles::new()))).unwrap();
+        match receive(&receiver) {
+            Message::Welcome(..) => {},
+            message => panic!("Expected a welcome message, got {:?}", message)
+        }
+
+        // Keep publishing while the subscriber subscribes and unsubscribes
+        let stopped = Arc::new(AtomicBool::new(false));
+        let publisher_stopped = stopped.clone();
+        let mut publisher = Connection::new_local(&router, "ordering_realm").connect().unwrap();
+        let publisher = thread::spawn(move || {
+            while !publisher_stopped.load(Ordering::SeqCst) {
+                publisher.publish(URI::new("ca.test.topic"), Some(vec![Value::Integer(1)]), None).unwrap();
+            }
+            publisher.shutdown().unwrap().await().unwrap();
+        });
+
+        for request_id in 1..51 {
+            router_sender.send_message(Message::Subscribe(request_id * 2, SubscribeOptions::new(), URI::new("ca.test.topic"))).unwrap();
+            let subscription_id = match receive(&receiver) {
+                Message::Subscribed(id, subscription_id) if id == request_id * 2 => subscription_id,
+                message => panic!("Expected a subscribed message, got {:?}", message)
+            };
+            router_sender.send_message(Message::Unsubscribe(request_id * 2 + 1, subscription_id)).unwrap();
+            // Events published before the router saw the unsubscribe message can arrive first
+            loop {
+                match receive(&receiver) {
+                    Message::Event(..) => {},
+                    Message::Unsubscribed(id) if id == request_id * 2 + 1 => break,
+                    message => panic!("Expected an unsubscribed message, got {:?}", message)
+                }
+            }
+        }
+
+        stopped.store(true, Ordering::SeqCst);
+        publisher.join().unwrap();
+    }
+
+    #[test]
+    fn encoded_events() {
+        let mut router = Router::new();
+        router.add_realm("encoded_realm");
+        let listener = router.listen_rawsocket("127.0.0.1:0").unwrap();
+        let address = match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => address,
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+
+        // Two subscribers share a strict subscription, and a third matches the topic by prefix.
+        // All of them use the same serializer, so the event is only encoded once per subscription.
+        let mut strict = vec![RawClient::connect(&address, "encoded_realm"), RawClient::connect(&address, "encoded_realm")];
+        let strict_id = strict[0].subscribe(1, "ca.test.topic", MatchingPolicy::Strict);
+        assert_eq!(strict[1].subscribe(1, "ca.test.topic", MatchingPolicy::Strict), strict_id);
+        let mut prefix = RawClient::connect(&address, "encoded_realm");
+        let prefix_id = prefix.subscribe(1, "ca.test", MatchingPolicy::Prefix);
+        assert!(prefix_id != strict_id);
+
+        let mut publisher = Connection::new_local(&router, "encoded_realm").connect().unwrap();
+        publisher.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::Integer(1)]), None).unwrap().await().unwrap();
+
+        for client in strict.iter_mut() {
+            match client.receive() {
+                Message::Event(subscription_id, _, details, args, _) => {
+                    assert_eq!(subscription_id, strict_id);
+                    assert_eq!(details.topic, None);
+                    assert_eq!(args, Some(vec![Value::Integer(1)]));
+                },
+                message => panic!("Expected an event, got {:?}", message)
+            }
+        }
+        match prefix.receive() {
+            Message::Event(subscription_id, _, details, args, _) => {
+                assert_eq!(subscription_id, prefix_id);
+                assert_eq!(details.topic, Some(URI::new("ca.test.topic")));
+                assert_eq!(args, Some(vec![Value::Integer(1)]));
+            },
+            message => panic!("Expected an event, got {:?}", message)
+        }
+
+        publisher.shutdown().unwrap().await().unwrap();
+    }
+
+    #[test]
+    fn slow_consumer() {
+        let mut router = Router::new();
+        router.add_realm("slow_realm");
+        router.set_outbound_limit(1 << 19, SlowConsumerPolicy::Disconnect);
+        let listener = router.listen_rawsocket("127.0.0.1:0").unwrap();
+        let address = match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => address,
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+
+        // The subscriber stops reading, and far more is published than the socket can buffer
+        let mut subscriber = RawClient::connect(&address, "slow_realm");
+        subscriber.subscribe(1, "ca.test.topic", MatchingPolicy::Strict);
+        let mut publisher = Connection::new_local(&router, "slow_realm").connect().unwrap();
+        let payload = "x".repeat(1 << 16);
+        for _ in 0..399 {
+            publisher.publish(URI::new("ca.test.topic"), Some(vec![Value::String(payload.clone())]), None).unwrap();
+        }
+        // Once the last publication is acknowledged, the router has sent (or dropped) every event
+        publisher.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::String(payload.clone())]), None).unwrap().await().unwrap();
+
+        let mut events = 0;
+        let mut reason = None;
+        while let Ok((_, payload)) = rawsocket::read_frame(&mut subscriber.stream, rawsocket::max_length(MAX_LENGTH_EXPONENT)) {
+            match JsonSerializer.deserialize(&payload).unwrap() {
+                Message::Event(..) => events += 1,
+                Message::Goodbye(_, goodbye_reason) => reason = Some(goodbye_reason),
+                message => panic!("Expected an event or a goodbye message, got {:?}", message)
+            }
+        }
+        assert_eq!(reason, Some(Reason::CustomReason(URI::new("wamp.close.slow_consumer"))));
+        assert!(events < 400);
+
+        publisher.shutdown().unwrap().await().unwrap();
+    }
+
+    /// Writes a masked WebSocket text frame, as a client must.  The mask is all zeroes, which
+    /// leaves the payload as it is.
+    fn write_websocket_text(stream: &mut TcpStream, message: &Message) {
+        let payload = JsonSerializer.serialize(message).unwrap();
+        assert!(payload.len() < 1 << 16);
+        stream.write_all(&[0x81, 0x80 | 126, (payload.len() >> 8) as u8, payload.len() as u8, 0, 0, 0, 0]).unwrap();
+        stream.write_all(&payload).unwrap();
+    }
+
+    /// Reads an unmasked WebSocket frame, returning its opcode and payload.
+    fn read_websocket_frame(stream: &mut TcpStream) -> ::std::io::Result<(u8, Vec<u8>)> {
+        let mut header = [0; 2];
+        try!(stream.read_exact(&mut header));
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                try!(stream.read_exact(&mut len));
+                len.iter().fold(0, |len, byte| (len << 8) | *byte as usize)
+            },
+            127 => {
+                let mut len = [0; 8];
+                try!(stream.read_exact(&mut len));
+                len.iter().fold(0, |len, byte| (len << 8) | *byte as usize)
+            },
+            len => len as usize
+        };
+        let mut payload = vec![0; len];
+        try!(stream.read_exact(&mut payload));
+        Ok((header[0] & 0x0F, payload))
+    }
+
+    #[test]
+    fn slow_websocket_consumer() {
+        let mut router = Router::new();
+        router.add_realm("slow_realm");
+        router.set_outbound_limit(1 << 19, SlowConsumerPolicy::Disconnect);
+        let listener = router.listen("127.0.0.1:0").unwrap();
+        let address = match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => address,
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+
+        // A client that never answers the router's pings, since it doesn't read anything
+        let mut subscriber = TcpStream::connect(&address).unwrap();
+        subscriber.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        write!(subscriber, "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+                            Sec-WebSocket-Protocol: wamp.2.json\r\n\r\n", address).unwrap();
+        // Skip the handshake response, which ends with an empty line
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            subscriber.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        write_websocket_text(&mut subscriber, &Message::Hello(URI::new("slow_realm"), HelloDetails::new(ClientRoles::new())));
+        write_websocket_text(&mut subscriber, &Message::Subscribe(1, SubscribeOptions::new(), URI::new("ca.test.topic")));
+        thread::sleep(Duration::from_millis(200));
+
+        let mut publisher = Connection::new_local(&router, "slow_realm").connect().unwrap();
+        // Events fit in a single frame, which the client doesn't need to put back together
+        let payload = "x".repeat(1 << 15);
+        for _ in 0..399 {
+            publisher.publish(URI::new("ca.test.topic"), Some(vec![Value::String(payload.clone())]), None).unwrap();
+        }
+        publisher.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::String(payload.clone())]), None).unwrap().await().unwrap();
+
+        let mut events = 0;
+        let mut reason = None;
+        let mut close_code = None;
+        while let Ok((opcode, payload)) = read_websocket_frame(&mut subscriber) {
+            match opcode {
+                0x1 => match JsonSerializer.deserialize(&payload).unwrap() {
+                    Message::Welcome(..) | Message::Subscribed(..) => {},
+                    Message::Event(..) => events += 1,
+                    Message::Goodbye(_, goodbye_reason) => reason = Some(goodbye_reason),
+                    message => panic!("Expected an event or a goodbye message, got {:?}", message)
+                },
+                0x8 => {
+                    close_code = Some(((payload[0] as u16) << 8) | payload[1] as u16);
+                    break;
+                },
+                _ => {}
+            }
+        }
+        assert_eq!(reason, Some(Reason::CustomReason(URI::new("wamp.close.slow_consumer"))));
+        assert_eq!(close_code, Some(1008));
+        assert!(events < 400);
+
+        publisher.shutdown().unwrap().await().unwrap();
+    }
+
+    #[test]
+    fn event_serialized_once_per_subscription() {
+        let mut router = Router::new();
+        router.add_realm("encoding_realm");
+        let serializer = Arc::new(CountingSerializer { events: AtomicUsize::new(0) });
+
+        let mut subscribers = Vec::new();
+        for request_id in 0..3 {
+            let mut subscriber = queued_session(&router, "encoding_realm", serializer.clone());
+            subscribe(&mut subscriber, request_id, "ca.test.topic", MatchingPolicy::Strict);
+            subscribers.push(subscriber);
+        }
+        for request_id in 0..2 {
+            let mut subscriber = queued_session(&router, "encoding_realm", serializer.clone());
+            subscribe(&mut subscriber, request_id, "ca.test", MatchingPolicy::Prefix);
+            subscribers.push(subscriber);
+        }
+        let mut publisher = queued_session(&router, "encoding_realm", Arc::new(JsonSerializer));
+
+        // The prefix subscribers are sent the topic as well, so they get an event of their own
+        publisher.handle_publish(1, PublishOptions::new(false), URI::new("ca.test.topic"), None, None).unwrap();
+        assert_eq!(serializer.events.load(Ordering::SeqCst), 2);
+
+        publisher.send_event(&URI::new("ca.test.topic"), None, None, EncodedEvents::unshared()).unwrap();
+        assert_eq!(serializer.events.load(Ordering::SeqCst), 7);
+    }
+
+    /// Times `handle_publish` delivering events to many subscribers, with each event serialized
+    /// once per subscription and, for comparison, once per subscriber.  Run it with
+    /// `cargo test --release publish_fanout -- --ignored --nocapture`.  The number of subscribers
+    /// and events can be changed with the `SUBSCRIBERS` and `EVENTS` environment variables.
+    #[test]
+    #[ignore]
+    fn publish_fanout() {
+        let setting = |name: &str, default: usize| env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+        let subscriber_count = setting("SUBSCRIBERS", 5000);
+        let events = setting("EVENTS", 100);
+
+        let mut router = Router::new();
+        router.add_realm("bench");
+        // Nothing reads the subscribers' queues, so they are kept short by dropping events
+        router.set_outbound_limit(1 << 16, SlowConsumerPolicy::DropNewest);
+        let subscribers: Vec<_> = (0..subscriber_count).map(|request_id| {
+            let mut subscriber = queued_session(&router, "bench", Arc::new(JsonSerializer));
+            subscribe(&mut subscriber, request_id as ID, "bench.fanout", MatchingPolicy::Strict);
+            subscriber
+        }).collect();
+        let mut publisher = queued_session(&router, "bench", Arc::new(JsonSerializer));
+        let payload = vec![Value::String("x".repeat(256)), Value::Integer(42), Value::Float(3.5)];
+
+        let start = Instant::now();
+        for _ in 0..events {
+            publisher.send_event(&URI::new("bench.fanout"), Some(payload.clone()), None, EncodedEvents::unshared()).unwrap();
+        }
+        let unshared = start.elapsed();
+        let start = Instant::now();
+        for request_id in 0..events {
+            publisher.handle_publish(request_id as ID, PublishOptions::new(false), URI::new("bench.fanout"), Some(payload.clone()), None).unwrap();
+        }
+        let shared = start.elapsed();
+
+        let millis = |duration: Duration| duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0;
+        println!("{} events to {} subscribers", events, subscribers.len());
+        println!("  serialized once per subscriber   {:>10.2} ms ({:.3} ms per event)", millis(unshared), millis(unshared) / events as f64);
+        println!("  serialized once per subscription {:>10.2} ms ({:.3} ms per event)", millis(shared), millis(shared) / events as f64);
+    }
+
+    #[test]
+    fn client_max_length() {
+        let mut router = Router::new();
+        router.add_realm("length_realm");
+        let listener = router.listen_rawsocket("127.0.0.1:0").unwrap();
+        let address = match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => address,
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+
+        // The subscriber only accepts messages of up to 512 bytes, so the first event is refused
+        let mut subscriber = RawClient::connect_with_exponent(&address, "length_realm", 0);
+        let subscription_id = subscriber.subscribe(1, "ca.test.topic", MatchingPolicy::Strict);
+        let mut publisher = Connection::new_local(&router, "length_realm").connect().unwrap();
+        publisher.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::String("x".repeat(1024))]), None).unwrap().await().unwrap();
+        publisher.publish_and_acknowledge(URI::new("ca.test.topic"), Some(vec![Value::Integer(1)]), None).unwrap().await().unwrap();
+
+        match subscriber.receive() {
+            Message::Event(id, _, _, args, _) => {
+                assert_eq!(id, subscription_id);
+                assert_eq!(args, Some(vec![Value::Integer(1)]));
+            },
+            message => panic!("Expected an event, got {:?}", message)
+        }
+
+        publisher.shutdown().unwrap().await().unwrap();
+    }
+}
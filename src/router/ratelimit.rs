@@ -0,0 +1,269 @@
+//! Contains the token buckets that limit how often a session can publish, call and subscribe.
+//!
+//! Each session has a bucket for every limited operation, which holds up to `burst` tokens and is
+//! refilled at `rate` tokens per second.  Every request takes a token, and a request made when the
+//! bucket is empty is refused.  Limits can also be set for an authrole, in which case every session
+//! with that authrole takes from the same bucket.
+use super::ConnectionHandler;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The reason given to clients whose request was refused because they exceeded a rate limit.
+pub static RATE_LIMITED: &'static str = "wamp.error.rate_limited";
+
+/// The operations that can be rate limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LimitedOperation {
+    Publish,
+    Call,
+    Subscribe
+}
+
+/// How often an operation can be performed: `rate` times per second on average, with bursts of
+/// up to `burst` at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: u32) -> RateLimit {
+        RateLimit {
+            rate: rate,
+            burst: burst as f64
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit: limit,
+            tokens: limit.burst,
+            updated: now
+        }
+    }
+
+    /// Refills the bucket for the time that has passed, and returns whether it holds a token.
+    fn has_token(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.updated = now;
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        if self.has_token(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The buckets of one session, or of every session with one authrole.
+pub struct Buckets {
+    buckets: HashMap<LimitedOperation, TokenBucket>
+}
+
+impl Buckets {
+    fn new(limits: &HashMap<LimitedOperation, RateLimit>) -> Buckets {
+        let now = Instant::now();
+        Buckets {
+            buckets: limits.iter().map(|(operation, limit)| (*operation, TokenBucket::new(*limit, now))).collect()
+        }
+    }
+
+    /// Returns whether there is a token for the operation, without taking it.  Operations without
+    /// a limit always have one.
+    fn has_token(&mut self, operation: LimitedOperation, now: Instant) -> bool {
+        match self.buckets.get_mut(&operation) {
+            Some(bucket) => bucket.has_token(now),
+            None => true
+        }
+    }
+
+    /// Takes a token for the operation, returning false if there were none left.  Operations
+    /// without a limit always succeed.
+    fn take(&mut self, operation: LimitedOperation, now: Instant) -> bool {
+        match self.buckets.get_mut(&operation) {
+            Some(bucket) => bucket.take(now),
+            None => true
+        }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+/// Takes a token for the operation from the session's buckets and from those of its authrole, if
+/// both have one.  Neither is taken when either bucket is empty, so that a request refused by one
+/// limit doesn't count against the other.
+fn take_both(session: &mut Buckets, role: Option<&mut Buckets>, operation: LimitedOperation) -> bool {
+    let now = Instant::now();
+    match role {
+        Some(role) => {
+            if !session.has_token(operation, now) || !role.has_token(operation, now) {
+                return false;
+            }
+            session.take(operation, now) && role.take(operation, now)
+        },
+        None => session.take(operation, now)
+    }
+}
+
+/// The rate limits configured for a router.
+pub struct RateLimits {
+    session_limits: HashMap<LimitedOperation, RateLimit>,
+    role_limits: HashMap<String, HashMap<LimitedOperation, RateLimit>>,
+    role_buckets: HashMap<String, Arc<Mutex<Buckets>>>
+}
+
+impl RateLimits {
+    pub fn new() -> RateLimits {
+        RateLimits {
+            session_limits: HashMap::new(),
+            role_limits: HashMap::new(),
+            role_buckets: HashMap::new()
+        }
+    }
+
+    pub fn set_session_limit(&mut self, operation: LimitedOperation, limit: RateLimit) {
+        self.session_limits.insert(operation, limit);
+    }
+
+    /// Sets a limit shared by every session with the given authrole.  The authrole's buckets
+    /// start out full again, including for sessions that were already established.
+    pub fn set_role_limit(&mut self, authrole: &str, operation: LimitedOperation, limit: RateLimit) {
+        let limits = self.role_limits.entry(authrole.to_string()).or_insert_with(HashMap::new);
+        limits.insert(operation, limit);
+        let buckets = Buckets::new(limits);
+        match self.role_buckets.entry(authrole.to_string()) {
+            Entry::Occupied(existing) => *existing.get().lock().unwrap() = buckets,
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::new(Mutex::new(buckets)));
+            }
+        }
+    }
+
+    /// Creates the buckets for a new session.
+    pub fn session_buckets(&self) -> Buckets {
+        Buckets::new(&self.session_limits)
+    }
+
+    /// The buckets shared by the sessions with the given authrole, if it has any limits.
+    pub fn role_buckets(&self, authrole: &str) -> Option<Arc<Mutex<Buckets>>> {
+        self.role_buckets.get(authrole).cloned()
+    }
+}
+
+impl ConnectionHandler {
+    /// Finds the buckets of the session's authrole once it is known, so that limiting its
+    /// requests doesn't have to look them up again.  Sessions without an authrole are limited as
+    /// the `anonymous` authrole.
+    pub fn set_role_rate_limits(&mut self) {
+        let authrole = self.info.lock().unwrap().authrole.clone().unwrap_or_else(|| "anonymous".to_string());
+        self.role_rate_limits = self.router.rate_limits.lock().unwrap().role_buckets(&authrole);
+    }
+
+    /// Takes a token for the operation from the session's buckets and from those of its authrole,
+    /// returning false if the session has exceeded either limit.
+    pub fn within_rate_limit(&mut self, operation: LimitedOperation) -> bool {
+        match self.role_rate_limits {
+            Some(ref role) => take_both(&mut self.rate_limits, Some(&mut *role.lock().unwrap()), operation),
+            None if self.rate_limits.is_empty() => true,
+            None => take_both(&mut self.rate_limits, None, operation)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TokenBucket, RateLimit, Buckets, LimitedOperation, take_both, RATE_LIMITED};
+    use router::Router;
+    use transport::local::{self, Envelope};
+    use messages::{Message, URI, HelloDetails, ClientRoles, CallOptions, PublishOptions, ErrorType, Reason};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn receive_error(receiver: &local::Receiver) -> (ErrorType, u64, Reason) {
+        match receiver.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(Message::Error(error_type, request_id, _, reason, _, _)) => (error_type, request_id, reason),
+            _ => panic!("Expected an error message")
+        }
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(2.0, 3), start);
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+
+        // The bucket never holds more than the burst size
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take(much_later));
+        }
+        assert!(!bucket.take(much_later));
+    }
+
+    #[test]
+    fn refused_by_role() {
+        let mut session_limits = HashMap::new();
+        session_limits.insert(LimitedOperation::Call, RateLimit::new(0.0, 1));
+        let mut session = Buckets::new(&session_limits);
+        let mut role_limits = HashMap::new();
+        role_limits.insert(LimitedOperation::Call, RateLimit::new(0.0, 0));
+        let mut role = Buckets::new(&role_limits);
+
+        // The role refuses the call, so the session keeps its only token
+        assert!(!take_both(&mut session, Some(&mut role), LimitedOperation::Call));
+        assert!(take_both(&mut session, None, LimitedOperation::Call));
+        assert!(!take_both(&mut session, None, LimitedOperation::Call));
+    }
+
+    #[test]
+    fn rate_limited_requests() {
+        let mut router = Router::new();
+        router.add_realm("limited_realm");
+        router.set_rate_limit(LimitedOperation::Call, RateLimit::new(0.0, 1));
+        router.set_role_rate_limit("anonymous", LimitedOperation::Publish, RateLimit::new(0.0, 0));
+
+        let (client_sender, receiver) = local::channel();
+        let router_sender = router.local_connector().connect(client_sender);
+        router_sender.send_message(Message::Hello(URI::new("limited_realm"), HelloDetails::new(ClientRoles::new()))).unwrap();
+        match receiver.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(Message::Welcome(..)) => {},
+            _ => panic!("Expected a welcome message")
+        }
+
+        // The first call takes the session's only token, and reaches the router
+        router_sender.send_message(Message::Call(1, CallOptions::new(), URI::new("ca.test.missing"), None, None)).unwrap();
+        assert_eq!(receive_error(&receiver), (ErrorType::Call, 1, Reason::NoSuchProcedure));
+        router_sender.send_message(Message::Call(2, CallOptions::new(), URI::new("ca.test.missing"), None, None)).unwrap();
+        assert_eq!(receive_error(&receiver), (ErrorType::Call, 2, Reason::CustomReason(URI::new(RATE_LIMITED))));
+
+        router_sender.send_message(Message::Publish(3, PublishOptions::new(true), URI::new("ca.test.topic"), None, None)).unwrap();
+        assert_eq!(receive_error(&receiver), (ErrorType::Publish, 3, Reason::CustomReason(URI::new(RATE_LIMITED))));
+    }
+}
@@ -0,0 +1,202 @@
+use super::{ConnectionHandler, RouterInfo, Listener, ListenerAddress, ListenerConfig};
+use super::listener::{ACCEPT_BACKOFF, HANDSHAKE_TIMEOUT};
+use transport::Sender;
+use transport::rawsocket::{self, Stream, Handshake, FrameType, ERROR_SERIALIZER_UNSUPPORTED};
+use ws::{Handler, Message as WSMessage, CloseCode};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// A bound socket that RawSocket connections can be accepted from.
+pub trait Accept: Send + 'static {
+    type Stream: Stream + 'static;
+
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+/// Accepts connections on a thread of its own until the returned listener is stopped, serving
+/// each connection on another thread.
+pub fn listen<L: Accept>(listener: L, address: ListenerAddress, router: Arc<RouterInfo>, config: Arc<ListenerConfig>) -> Listener {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread_stopped = stopped.clone();
+    let thread_address = address.clone();
+    let thread = thread::spawn(move || {
+        loop {
+            let stream = listener.accept_stream();
+            if thread_stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let router = router.clone();
+                    let config = config.clone();
+                    thread::spawn(move || {
+                        serve(Box::new(stream), router, config);
+                    });
+                },
+                Err(e) => {
+                    error!("Could not accept connection on {:?}: {}", thread_address, e);
+                    thread::sleep(Duration::from_millis(ACCEPT_BACKOFF));
+                }
+            }
+        }
+    });
+    info!("Listening for RawSocket connections on {:?}", address);
+    Listener::rawsocket(address, stopped, thread)
+}
+
+/// Runs a RawSocket connection until it is closed by either side.
+pub fn serve(mut stream: Box<Stream>, router: Arc<RouterInfo>, config: Arc<ListenerConfig>) {
+    debug!("New RawSocket connection");
+    // A client that connects but never sends its handshake would otherwise hold on to the thread
+    if let Err(e) = stream.set_stream_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT))) {
+        error!("Could not set RawSocket handshake timeout: {}", e);
+        return;
+    }
+    let handshake = rawsocket::read_handshake(&mut stream);
+    if let Err(e) = stream.set_stream_read_timeout(None) {
+        error!("Could not clear RawSocket handshake timeout: {}", e);
+        return;
+    }
+    let (serializer_id, client_max_length) = match handshake {
+        Ok(Handshake::Accepted{serializer, max_length}) => (serializer, max_length),
+        Ok(Handshake::Refused(error)) => {
+            warn!("Client sent an invalid RawSocket handshake (error {})", error);
+            rawsocket::write_handshake_error(&mut stream, error).ok();
+            return;
+        },
+        Err(e) => {
+            error!("Could not read RawSocket handshake: {}", e);
+            return;
+        }
+    };
+    let serializer = match router.serializers.lock().unwrap().get_raw_socket(serializer_id) {
+        Some(serializer) => serializer,
+        None => {
+            warn!("Client requested unsupported RawSocket serializer {}", serializer_id);
+            rawsocket::write_handshake_error(&mut stream, ERROR_SERIALIZER_UNSUPPORTED).ok();
+            return;
+        }
+    };
+    let length_exponent = router.length_exponent();
+    if let Err(e) = rawsocket::write_handshake(&mut stream, length_exponent, serializer_id) {
+        error!("Could not send RawSocket handshake: {}", e);
+        return;
+    }
+
+    let queue = router.outbound_queue(&*serializer);
+    let sender = match stream.try_clone_stream() {
+        Ok(write_stream) => rawsocket::spawn_writer(write_stream, queue, client_max_length),
+        Err(e) => {
+            error!("Could not clone RawSocket stream: {}", e);
+            return;
+        }
+    };
+    let mut handler = ConnectionHandler::new(router, Sender::Queued(sender.clone()));
+    handler.config = config;
+    {
+        let mut info = handler.info.lock().unwrap();
+        info.protocol = serializer.protocol().to_string();
+        info.serializer = serializer.clone();
+    }
+
+    loop {
+        match rawsocket::read_frame(&mut stream, rawsocket::max_length(length_exponent)) {
+            Ok((FrameType::Message, payload)) => {
+                let message = if serializer.is_binary() {
+                    WSMessage::Binary(payload)
+                } else {
+                    match String::from_utf8(payload) {
+                        Ok(payload) => WSMessage::Text(payload),
+                        Err(_) => {
+                            error!("Recieved {} message that was not valid UTF-8", serializer.protocol());
+                            break;
+                        }
+                    }
+                };
+                if let Err(e) = handler.on_message(message) {
+                    error!("Closing RawSocket connection: {}", e);
+                    break;
+                }
+            },
+            Ok((FrameType::Ping, payload)) => {
+                if sender.pong(payload).is_err() {
+                    break;
+                }
+            },
+            Ok((FrameType::Pong, _)) => {},
+            Err(e) => {
+                debug!("RawSocket connection closed: {}", e);
+                break;
+            }
+        }
+    }
+    sender.shutdown().ok();
+    handler.on_close(CloseCode::Normal, "");
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use router::Router;
+    use client::Connection;
+    use messages::{URI, Value, Dict, List};
+    use ::CallResult;
+    use eventual::Async;
+    use std::env;
+    use std::process;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    fn add(args: List, _kwargs: Dict) -> CallResult<(Option<List>, Option<Dict>)> {
+        let a = match args[0] { Value::Integer(a) => a, _ => 0 };
+        let b = match args[1] { Value::Integer(b) => b, _ => 0 };
+        Ok((Some(vec![Value::Integer(a + b)]), None))
+    }
+
+    #[test]
+    fn unix_socket() {
+        let path = env::temp_dir().join(format!("wamp-rawsocket-{}.sock", process::id()));
+        let mut router = Router::new();
+        router.add_realm("unix_realm");
+        let _listener = router.listen_unix(&path).unwrap();
+
+        let mut callee = Connection::new_unix(&path, "unix_realm").connect().unwrap();
+        callee.register(URI::new("ca.test.add"), Box::new(add)).unwrap().await().unwrap();
+        let (tx, rx) = channel();
+        callee.subscribe(URI::new("ca.test.topic"), Box::new(move |args, _kwargs| {
+            tx.send(args).unwrap();
+        })).unwrap().await().unwrap();
+
+        let mut caller = Connection::new_unix(&path, "unix_realm").connect().unwrap();
+        let (args, _) = caller.call(URI::new("ca.test.add"), Some(vec![Value::Integer(2), Value::Integer(3)]), None).unwrap().await().unwrap();
+        assert_eq!(args, vec![Value::Integer(5)]);
+
+        caller.publish(URI::new("ca.test.topic"), Some(vec![Value::String("hello".to_string())]), None).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), vec![Value::String("hello".to_string())]);
+
+        caller.shutdown().unwrap().await().unwrap();
+        callee.shutdown().unwrap().await().unwrap();
+    }
+}
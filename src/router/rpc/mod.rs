@@ -12,18 +12,20 @@ impl ConnectionHandler{
         debug!("Responding to register message (id: {}, procedure: {})", request_id, procedure.uri);
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.registration_manager;
-                let procedure_id = {
+                let (procedure_id, sent) = {
+                    let mut realm = realm.lock().unwrap();
+                    let mut manager = &mut realm.registration_manager;
                     let procedure_id = match manager.registrations.register_with(&procedure, self.info.clone(), options.pattern_match.clone(), options.invocation_policy.clone()) {
                         Ok(procedure_id) => procedure_id,
                         Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Register, request_id, e.reason())))
                     };
-                    self.registered_procedures.push(procedure_id);
-                    procedure_id
+                    manager.registration_ids_to_uris.insert(procedure_id, (procedure.uri, options.pattern_match == MatchingPolicy::Prefix));
+                    // As with subscriptions, the callee must hear of the registration before any
+                    // invocation of it
+                    (procedure_id, send_message(&self.info, &Message::Registered(request_id, procedure_id)))
                 };
-                manager.registration_ids_to_uris.insert(procedure_id, (procedure.uri, options.pattern_match == MatchingPolicy::Prefix));
-                send_message(&self.info, &Message::Registered(request_id, procedure_id))
+                self.registered_procedures.push(procedure_id);
+                sent
             },
              None => {
                 Err(Error::new(ErrorKind::InvalidState("Recieved a message while not attached to a realm")))
@@ -34,17 +36,18 @@ impl ConnectionHandler{
     pub fn handle_unregister(&mut self, request_id: ID, procedure_id: ID) -> WampResult<()> {
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.registration_manager;
-                let (procedure_uri, is_prefix) =  match manager.registration_ids_to_uris.get(&procedure_id) {
-                    Some(&(ref uri, ref is_prefix)) => (uri.clone(), is_prefix.clone()),
-                    None => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unregister, request_id, Reason::NoSuchProcedure)))
-                };
-
+                let procedure_id = {
+                    let mut realm = realm.lock().unwrap();
+                    let mut manager = &mut realm.registration_manager;
+                    let (procedure_uri, is_prefix) =  match manager.registration_ids_to_uris.get(&procedure_id) {
+                        Some(&(ref uri, ref is_prefix)) => (uri.clone(), is_prefix.clone()),
+                        None => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unregister, request_id, Reason::NoSuchProcedure)))
+                    };
 
-                let procedure_id = match manager.registrations.unregister_with(&procedure_uri, &self.info, is_prefix) {
-                    Ok(procedure_id) => procedure_id,
-                    Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unregister, request_id, e.reason())))
+                    match manager.registrations.unregister_with(&procedure_uri, &self.info, is_prefix) {
+                        Ok(procedure_id) => procedure_id,
+                        Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Unregister, request_id, e.reason())))
+                    }
                 };
                 self.registered_procedures.retain(|id| {
                     *id != procedure_id
@@ -57,19 +60,24 @@ impl ConnectionHandler{
         }
     }
 
+    /// Sends an invocation to the callee registered for the procedure.  As with publishing, the
+    /// realm is unlocked before the invocation is sent.
     pub fn handle_call(&mut self, request_id: ID, _options: CallOptions, procedure: URI, args: Option<List>, kwargs: Option<Dict>) -> WampResult<()> {
          debug!("Responding to call message (id: {}, procedure: {})", request_id, procedure.uri);
          match self.realm {
              Some(ref realm) => {
-                 let mut realm = realm.lock().unwrap();
-                 let mut manager = &mut realm.registration_manager;
                  let invocation_id = random_id();
-                 info!("Current procedure tree: {:?}", manager.registrations);
-                 let  (registrant, procedure_id, policy) = match manager.registrations.get_registrant_for(procedure.clone()) {
-                     Ok(registrant) => registrant,
-                     Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, e.reason())))
+                 let (registrant, procedure_id, policy) = {
+                     let mut realm = realm.lock().unwrap();
+                     let mut manager = &mut realm.registration_manager;
+                     info!("Current procedure tree: {:?}", manager.registrations);
+                     let (registrant, procedure_id, policy) = match manager.registrations.get_registrant_for(procedure.clone()) {
+                         Ok((registrant, procedure_id, policy)) => (registrant.clone(), procedure_id, policy),
+                         Err(e) => return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, e.reason())))
+                     };
+                     manager.active_calls.insert(invocation_id, (request_id, self.info.clone()));
+                     (registrant, procedure_id, policy)
                  };
-                 manager.active_calls.insert(invocation_id, (request_id, self.info.clone()));
                  let mut details = InvocationDetails::new();
                  details.procedure = if policy == MatchingPolicy::Strict {
                      None
@@ -77,7 +85,13 @@ impl ConnectionHandler{
                      Some(procedure)
                  };
                  let invocation_message = Message::Invocation(invocation_id, procedure_id, details, args, kwargs);
-                 try!(send_message(registrant, &invocation_message));
+                 // The callee's connection is cleaned up when it closes, so its failure only fails
+                 // the call, rather than the caller's connection
+                 if let Err(e) = send_message(&registrant, &invocation_message) {
+                     warn!("Could not send invocation to callee: {}", e);
+                     realm.lock().unwrap().registration_manager.active_calls.remove(&invocation_id);
+                     return Err(Error::new(ErrorKind::ErrorReason(ErrorType::Call, request_id, Reason::NetworkFailure)));
+                 }
 
 
                  Ok(())
@@ -92,11 +106,15 @@ impl ConnectionHandler{
         debug!("Responding to yield message (id: {})", invocation_id);
         match self.realm {
             Some(ref realm) => {
-                let mut realm = realm.lock().unwrap();
-                let mut manager = &mut realm.registration_manager;
-                if let Some((call_id, callee)) = manager.active_calls.remove(&invocation_id) {
+                let active_call = realm.lock().unwrap().registration_manager.active_calls.remove(&invocation_id);
+                if let Some((call_id, callee)) = active_call {
                     let result_message = Message::Result(call_id, ResultDetails::new(), args, kwargs);
-                    send_message(&callee, &result_message)
+                    // The caller's connection is cleaned up when it closes, and the callee is
+                    // not to blame for it
+                    if let Err(e) = send_message(&callee, &result_message) {
+                        warn!("Could not send result to caller: {}", e);
+                    }
+                    Ok(())
                 } else {
                     Err(Error::new(ErrorKind::InvalidState("Recieved a yield message for a call that wasn't sent")))
                 }
@@ -106,3 +124,52 @@ impl ConnectionHandler{
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use router::Router;
+    use transport::local::{self, Envelope};
+    use messages::{Message, URI, HelloDetails, ClientRoles, RegisterOptions, CallOptions, ErrorType, Reason};
+    use std::time::Duration;
+
+    fn receive(receiver: &local::Receiver) -> Message {
+        match receiver.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Envelope::Message(message) => message,
+            Envelope::Close => panic!("The router closed the connection")
+        }
+    }
+
+    #[test]
+    fn unreachable_callee() {
+        let mut router = Router::new();
+        router.add_realm("rpc_realm");
+        let hello = Message::Hello(URI::new("rpc_realm"), HelloDetails::new(ClientRoles::new()));
+
+        let (callee_sender, callee_receiver) = local::channel();
+        let callee = router.local_connector().connect(callee_sender);
+        callee.send_message(hello.clone()).unwrap();
+        receive(&callee_receiver);
+        callee.send_message(Message::Register(1, RegisterOptions::new(), URI::new("ca.test.procedure"))).unwrap();
+        match receive(&callee_receiver) {
+            Message::Registered(1, _) => {},
+            message => panic!("Expected a registered message, got {:?}", message)
+        }
+        // The callee stops listening, without its connection being closed
+        drop(callee_receiver);
+
+        let (caller_sender, caller_receiver) = local::channel();
+        let caller = router.local_connector().connect(caller_sender);
+        caller.send_message(hello).unwrap();
+        receive(&caller_receiver);
+        for request_id in 1..3 {
+            caller.send_message(Message::Call(request_id, CallOptions::new(), URI::new("ca.test.procedure"), None, None)).unwrap();
+            match receive(&caller_receiver) {
+                Message::Error(ErrorType::Call, id, _, reason, _, _) => {
+                    assert_eq!(id, request_id);
+                    assert_eq!(reason, Reason::NetworkFailure);
+                },
+                message => panic!("Expected an error message, got {:?}", message)
+            }
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! Contains the serializers used to turn WAMP messages into the payloads sent over a connection.
+//!
+//! JSON, MsgPack and CBOR are supported out of the box.  Other sub-protocols can be added by
+//! implementing `Serializer` and registering it with `Router::add_serializer` or
+//! `Connection::add_serializer`.
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{self, Visitor, SeqAccess, IgnoredAny};
+use serde_json;
+use serde_cbor;
+use rmp_serde::Deserializer as RMPDeserializer;
+use rmp_serde::Serializer as RMPSerializer;
+use utils::StructMapWriter;
+use messages::{Message, Value, List, Dict};
+use base64;
+use transport::rawsocket::{SERIALIZER_JSON, SERIALIZER_MSGPACK, SERIALIZER_CBOR};
+use std::sync::Arc;
+use std::fmt;
+use ::{WampResult, Error, ErrorKind, ID};
+
+static BATCHED_SUFFIX: &'static str = ".batched";
+
+/// Converts WAMP messages to and from the payloads of a WebSocket sub-protocol.
+pub trait Serializer: Send + Sync {
+    /// The name of the WebSocket sub-protocol, such as `wamp.2.json`.
+    fn protocol(&self) -> &str;
+
+    /// Whether payloads are sent in binary frames.  Payloads of other serializers must be valid UTF-8,
+    /// and are sent in text frames.
+    fn is_binary(&self) -> bool;
+
+    /// The serializer's identifier in a RawSocket handshake, if it can be used over RawSocket.
+    fn raw_socket_id(&self) -> Option<u8> {
+        None
+    }
+
+    fn serialize(&self, message: &Message) -> WampResult<Vec<u8>>;
+
+    fn deserialize(&self, payload: &[u8]) -> WampResult<Message>;
+
+    /// Reads the type and request ID of a message (its first two elements) without deserializing
+    /// the rest of it, so that a message too large to be handled can still be answered.  Returns
+    /// `None` if the payload is malformed, or if the serializer cannot read part of a message.
+    fn peek(&self, _payload: &[u8]) -> Option<(u64, ID)> {
+        None
+    }
+}
+
+pub struct JsonSerializer;
+pub struct MsgPackSerializer;
+pub struct CborSerializer;
+
+impl Serializer for JsonSerializer {
+    fn protocol(&self) -> &str {
+        "wamp.2.json"
+    }
+
+    fn is_binary(&self) -> bool {
+        false
+    }
+
+    fn raw_socket_id(&self) -> Option<u8> {
+        Some(SERIALIZER_JSON)
+    }
+
+    fn serialize(&self, message: &Message) -> WampResult<Vec<u8>> {
+        let result = if contains_bytes(message) {
+            let mut message = message.clone();
+            encode_bytes(&mut message);
+            serde_json::to_vec(&message)
+        } else {
+            serde_json::to_vec(message)
+        };
+        result.map_err(|e| Error::new(ErrorKind::JSONError(e)))
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> WampResult<Message> {
+        let mut message = try!(serde_json::from_slice(payload).map_err(|e| Error::new(ErrorKind::JSONError(e))));
+        decode_bytes(&mut message);
+        Ok(message)
+    }
+
+    fn peek(&self, payload: &[u8]) -> Option<(u64, ID)> {
+        serde_json::from_slice::<Header>(payload).ok().map(|header| (header.0, header.1))
+    }
+}
+
+impl Serializer for MsgPackSerializer {
+    fn protocol(&self) -> &str {
+        "wamp.2.msgpack"
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn raw_socket_id(&self) -> Option<u8> {
+        Some(SERIALIZER_MSGPACK)
+    }
+
+    fn serialize(&self, message: &Message) -> WampResult<Vec<u8>> {
+        let mut buf: Vec<u8> = Vec::new();
+        match message.serialize(&mut RMPSerializer::with(&mut buf, StructMapWriter)) {
+            Ok(()) => Ok(buf),
+            Err(e) => Err(Error::new(ErrorKind::SerializationError(e.to_string())))
+        }
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> WampResult<Message> {
+        let mut de = RMPDeserializer::new(payload);
+        Deserialize::deserialize(&mut de).map_err(|e| Error::new(ErrorKind::MsgPackError(e)))
+    }
+
+    fn peek(&self, payload: &[u8]) -> Option<(u64, ID)> {
+        let mut de = RMPDeserializer::new(payload);
+        Header::deserialize(&mut de).ok().map(|header| (header.0, header.1))
+    }
+}
+
+impl Serializer for CborSerializer {
+    fn protocol(&self) -> &str {
+        "wamp.2.cbor"
+    }
+
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn raw_socket_id(&self) -> Option<u8> {
+        Some(SERIALIZER_CBOR)
+    }
+
+    fn serialize(&self, message: &Message) -> WampResult<Vec<u8>> {
+        serde_cbor::to_vec(message).map_err(|e| Error::new(ErrorKind::CBORError(e)))
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> WampResult<Message> {
+        serde_cbor::from_slice(payload).map_err(|e| Error::new(ErrorKind::CBORError(e)))
+    }
+
+    fn peek(&self, payload: &[u8]) -> Option<(u64, ID)> {
+        serde_cbor::from_slice::<Header>(payload).ok().map(|header| (header.0, header.1))
+    }
+}
+
+/// The type and request ID at the start of a message.  The rest of the message is skipped over
+/// without being kept.
+struct Header(u64, ID);
+
+impl<'de> Deserialize<'de> for Header {
+    fn deserialize<D>(deserializer: D) -> Result<Header, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_seq(HeaderVisitor)
+    }
+}
+
+struct HeaderVisitor;
+
+impl<'de> Visitor<'de> for HeaderVisitor {
+    type Value = Header;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a WAMP message")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Header, A::Error> where A: SeqAccess<'de> {
+        let message_type = match try!(seq.next_element()) {
+            Some(message_type) => message_type,
+            None => return Err(de::Error::invalid_length(0, &self))
+        };
+        let request_id = match try!(seq.next_element()) {
+            Some(request_id) => request_id,
+            None => return Err(de::Error::invalid_length(1, &self))
+        };
+        while let Some(IgnoredAny) = try!(seq.next_element()) {}
+        Ok(Header(message_type, request_id))
+    }
+}
+
+/// Returns the details, arguments and keyword arguments of a message, if it has them.
+fn payload(message: &Message) -> (Option<&Dict>, Option<&List>, Option<&Dict>) {
+    match *message {
+        Message::Error(_, _, ref details, _, ref args, ref kwargs) => (Some(details), args.as_ref(), kwargs.as_ref()),
+        Message::Publish(_, _, _, ref args, ref kwargs) |
+        Message::Event(_, _, _, ref args, ref kwargs) |
+        Message::Call(_, _, _, ref args, ref kwargs) |
+        Message::Invocation(_, _, _, ref args, ref kwargs) |
+        Message::Yield(_, _, ref args, ref kwargs) |
+        Message::Result(_, _, ref args, ref kwargs) => (None, args.as_ref(), kwargs.as_ref()),
+        _ => (None, None, None)
+    }
+}
+
+fn payload_mut(message: &mut Message) -> (Option<&mut Dict>, Option<&mut List>, Option<&mut Dict>) {
+    match *message {
+        Message::Error(_, _, ref mut details, _, ref mut args, ref mut kwargs) => (Some(details), args.as_mut(), kwargs.as_mut()),
+        Message::Publish(_, _, _, ref mut args, ref mut kwargs) |
+        Message::Event(_, _, _, ref mut args, ref mut kwargs) |
+        Message::Call(_, _, _, ref mut args, ref mut kwargs) |
+        Message::Invocation(_, _, _, ref mut args, ref mut kwargs) |
+        Message::Yield(_, _, ref mut args, ref mut kwargs) |
+        Message::Result(_, _, ref mut args, ref mut kwargs) => (None, args.as_mut(), kwargs.as_mut()),
+        _ => (None, None, None)
+    }
+}
+
+fn value_contains_bytes(value: &Value) -> bool {
+    match *value {
+        Value::Bytes(_) => true,
+        Value::List(ref list) => list.iter().any(value_contains_bytes),
+        Value::Dict(ref dict) => dict.values().any(value_contains_bytes),
+        _ => false
+    }
+}
+
+fn contains_bytes(message: &Message) -> bool {
+    let (details, args, kwargs) = payload(message);
+    details.map_or(false, |details| details.values().any(value_contains_bytes)) ||
+        args.map_or(false, |args| args.iter().any(value_contains_bytes)) ||
+        kwargs.map_or(false, |kwargs| kwargs.values().any(value_contains_bytes))
+}
+
+/// JSON has no binary type, so WAMP sends binary values as a string holding a NUL followed by
+/// the base64 encoded value.
+fn encode_value_bytes(value: &mut Value) {
+    let encoded = match *value {
+        Value::Bytes(ref bytes) => format!("\0{}", base64::encode(bytes)),
+        Value::List(ref mut list) => {
+            for value in list.iter_mut() {
+                encode_value_bytes(value);
+            }
+            return;
+        },
+        Value::Dict(ref mut dict) => {
+            for value in dict.values_mut() {
+                encode_value_bytes(value);
+            }
+            return;
+        },
+        _ => return
+    };
+    *value = Value::String(encoded);
+}
+
+fn encode_bytes(message: &mut Message) {
+    let (details, args, kwargs) = payload_mut(message);
+    for dict in details.into_iter().chain(kwargs) {
+        for value in dict.values_mut() {
+            encode_value_bytes(value);
+        }
+    }
+    if let Some(args) = args {
+        for value in args.iter_mut() {
+            encode_value_bytes(value);
+        }
+    }
+}
+
+/// Turns the strings that `encode_value_bytes` made back into binary values.  Only the JSON
+/// serializer does this, since the other serializers have a binary type of their own.
+fn decode_value_bytes(value: &mut Value) {
+    let decoded = match *value {
+        Value::String(ref string) if string.starts_with('\0') => {
+            match base64::decode(&string[1..]) {
+                Ok(bytes) => bytes,
+                Err(_) => return
+            }
+        },
+        Value::List(ref mut list) => {
+            for value in list.iter_mut() {
+                decode_value_bytes(value);
+            }
+            return;
+        },
+        Value::Dict(ref mut dict) => {
+            for value in dict.values_mut() {
+                decode_value_bytes(value);
+            }
+            return;
+        },
+        _ => return
+    };
+    *value = Value::Bytes(decoded);
+}
+
+fn decode_bytes(message: &mut Message) {
+    let (details, args, kwargs) = payload_mut(message);
+    for dict in details.into_iter().chain(kwargs) {
+        for value in dict.values_mut() {
+            decode_value_bytes(value);
+        }
+    }
+    if let Some(args) = args {
+        for value in args.iter_mut() {
+            decode_value_bytes(value);
+        }
+    }
+}
+
+/// The serializers a router or client is willing to use, in order of preference.
+#[derive(Clone)]
+pub struct Serializers {
+    serializers: Vec<Arc<Serializer>>
+}
+
+impl Serializers {
+    /// Creates the default set of serializers: MsgPack, CBOR and JSON, in that order.
+    pub fn new() -> Serializers {
+        Serializers {
+            serializers: vec![Arc::new(MsgPackSerializer), Arc::new(CborSerializer), Arc::new(JsonSerializer)]
+        }
+    }
+
+    /// Adds a serializer, preferring it over the existing ones.  A serializer that has the same
+    /// protocol name as an existing one replaces it.
+    pub fn add(&mut self, serializer: Arc<Serializer>) {
+        self.serializers.retain(|existing| existing.protocol() != serializer.protocol());
+        self.serializers.insert(0, serializer);
+    }
+
+    pub fn get(&self, protocol: &str) -> Option<Arc<Serializer>> {
+        self.serializers.iter().find(|serializer| serializer.protocol() == protocol).cloned()
+    }
+
+    pub fn get_raw_socket(&self, id: u8) -> Option<Arc<Serializer>> {
+        self.serializers.iter().find(|serializer| serializer.raw_socket_id() == Some(id)).cloned()
+    }
+
+    /// The most preferred serializer that can be used over RawSocket.
+    pub fn preferred_raw_socket(&self) -> Option<Arc<Serializer>> {
+        self.serializers.iter().find(|serializer| serializer.raw_socket_id().is_some()).cloned()
+    }
+
+    /// Finds the serializer for a WebSocket sub-protocol, which may be the batched form of one of
+    /// the serializers (such as `wamp.2.json.batched`).  Returns the serializer, and whether it is batched.
+    pub fn negotiate(&self, protocol: &str) -> Option<(Arc<Serializer>, bool)> {
+        match self.get(protocol) {
+            Some(serializer) => Some((serializer, false)),
+            None => {
+                if protocol.ends_with(BATCHED_SUFFIX) {
+                    self.get(&protocol[..protocol.len() - BATCHED_SUFFIX.len()]).map(|serializer| (serializer, true))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// The names of the sub-protocols to offer, in order of preference.  If `batched` is true, the
+    /// batched form of each serializer is offered before any of the unbatched ones.
+    pub fn protocols(&self, batched: bool) -> Vec<String> {
+        let mut protocols = Vec::new();
+        if batched {
+            protocols.extend(self.serializers.iter().map(|serializer| format!("{}{}", serializer.protocol(), BATCHED_SUFFIX)));
+        }
+        protocols.extend(self.serializers.iter().map(|serializer| serializer.protocol().to_string()));
+        protocols
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Serializers, Serializer, JsonSerializer, MsgPackSerializer, CborSerializer};
+    use messages::{Message, ErrorDetails, Reason, Value, URI, PublishOptions};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use ::WampResult;
+
+    struct PrettyJsonSerializer;
+
+    impl Serializer for PrettyJsonSerializer {
+        fn protocol(&self) -> &str {
+            "wamp.2.json"
+        }
+
+        fn is_binary(&self) -> bool {
+            false
+        }
+
+        fn serialize(&self, message: &Message) -> WampResult<Vec<u8>> {
+            JsonSerializer.serialize(message).map(|mut payload| { payload.push(b'\n'); payload })
+        }
+
+        fn deserialize(&self, payload: &[u8]) -> WampResult<Message> {
+            JsonSerializer.deserialize(payload)
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        let mut serializers = Serializers::new();
+        assert_eq!(serializers.negotiate("wamp.2.cbor").unwrap().0.protocol(), "wamp.2.cbor");
+        assert_eq!(serializers.negotiate("wamp.2.msgpack.batched").unwrap().1, true);
+        assert!(serializers.negotiate("wamp.2.ubjson").is_none());
+        assert_eq!(serializers.get_raw_socket(2).unwrap().protocol(), "wamp.2.msgpack");
+
+        serializers.add(Arc::new(PrettyJsonSerializer));
+        assert_eq!(serializers.protocols(false), vec!["wamp.2.json", "wamp.2.msgpack", "wamp.2.cbor"]);
+        let message = Message::Goodbye(ErrorDetails::new(), Reason::GoodbyeAndOut);
+        let (serializer, _) = serializers.negotiate("wamp.2.json").unwrap();
+        assert_eq!(serializer.serialize(&message).unwrap(), b"[6,{},\"wamp.error.goodbye_and_out\"]\n".to_vec());
+        assert_eq!(serializer.raw_socket_id(), None);
+    }
+
+    #[test]
+    fn payload_values() {
+        let mut kwargs = HashMap::new();
+        kwargs.insert("data".to_string(), Value::List(vec![Value::Bytes(vec![0x10, 0x20, 0x30])]));
+        let message = Message::Publish(1, PublishOptions::new(false), URI::new("ca.test.topic"),
+                                       Some(vec![Value::Float(1.5), Value::Null, Value::Bytes(vec![0xFF, 0x00])]), Some(kwargs));
+
+        let payload = JsonSerializer.serialize(&message).unwrap();
+        assert_eq!(String::from_utf8(payload.clone()).unwrap(),
+                   "[16,1,{},\"ca.test.topic\",[1.5,null,\"\\u0000/wA=\"],{\"data\":[\"\\u0000ECAw\"]}]");
+        assert_eq!(JsonSerializer.deserialize(&payload).unwrap(), message);
+
+        let payload = MsgPackSerializer.serialize(&message).unwrap();
+        assert_eq!(MsgPackSerializer.deserialize(&payload).unwrap(), message);
+
+        let payload = CborSerializer.serialize(&message).unwrap();
+        assert_eq!(CborSerializer.deserialize(&payload).unwrap(), message);
+
+        // Only JSON uses strings for binary values, so the other serializers leave them alone
+        let strings = Message::Publish(1, PublishOptions::new(false), URI::new("ca.test.topic"),
+                                       Some(vec![Value::String("\0/wA=".to_string()), Value::String("\0".to_string())]), None);
+        let payload = MsgPackSerializer.serialize(&strings).unwrap();
+        assert_eq!(MsgPackSerializer.deserialize(&payload).unwrap(), strings);
+        let payload = CborSerializer.serialize(&strings).unwrap();
+        assert_eq!(CborSerializer.deserialize(&payload).unwrap(), strings);
+    }
+
+    #[test]
+    fn peek() {
+        let message = Message::Publish(764346, PublishOptions::new(true), URI::new("ca.test.topic"), Some(vec![Value::String("a value".to_string())]), None);
+        for serializer in [&JsonSerializer as &Serializer, &MsgPackSerializer, &CborSerializer].iter() {
+            let payload = serializer.serialize(&message).unwrap();
+            assert_eq!(serializer.peek(&payload), Some((16, 764346)));
+            assert_eq!(serializer.peek(&payload[..3]), None);
+        }
+        assert_eq!(PrettyJsonSerializer.peek(b"[16,1,{},\"ca.test.topic\"]"), None);
+    }
+
+    #[test]
+    fn cbor_byte_strings() {
+        // [16, 1, {}, "ca.test.topic", [h'010203']], as another implementation would send it
+        let mut payload = vec![0x85, 0x10, 0x01, 0xA0, 0x6D];
+        payload.extend_from_slice(b"ca.test.topic");
+        payload.extend_from_slice(&[0x81, 0x43, 0x01, 0x02, 0x03]);
+        let message = Message::Publish(1, PublishOptions::new(false), URI::new("ca.test.topic"), Some(vec![Value::Bytes(vec![1, 2, 3])]), None);
+        assert_eq!(CborSerializer.deserialize(&payload).unwrap(), message);
+
+        // Bytes are sent as a native byte string rather than as text
+        let serialized = CborSerializer.serialize(&message).unwrap();
+        assert!(serialized.windows(4).any(|window| window == [0x43, 0x01, 0x02, 0x03]));
+        assert_eq!(CborSerializer.deserialize(&serialized).unwrap(), message);
+    }
+}
@@ -0,0 +1,207 @@
+//! Contains the framing used by the batched serializers (`wamp.2.json.batched` and
+//! `wamp.2.msgpack.batched`), which pack several WAMP messages into a single WebSocket frame.
+//!
+//! In a JSON batch, every message is followed by the `0x18` separator.  In a MsgPack batch, every
+//! message is preceded by its length as a four octet big-endian integer.
+use ws::{Sender as WSSender, Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, CloseCode};
+use ws::util::Token;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use transport::outbound;
+
+pub const JSON_SEPARATOR: char = '\u{18}';
+
+/// The timeout used to flush a batch once its flush interval has passed.
+pub const FLUSH_TIMEOUT: Token = Token(126);
+
+/// The sending half of a WebSocket connection using a batched serializer.  Messages are
+/// collected until the flush interval passes, and are then sent together as one frame.
+#[derive(Clone)]
+pub struct Sender {
+    sender: WSSender,
+    queue: Option<outbound::Sender>,
+    buffer: Arc<Mutex<Vec<u8>>>,
+    json: bool,
+    flush_interval: u64
+}
+
+impl Sender {
+    /// Creates a sender that batches JSON (if `json` is true) or MsgPack messages.  If the flush
+    /// interval is 0, each message is sent as soon as it is given.
+    pub fn new(sender: WSSender, json: bool, flush_interval: u64) -> Sender {
+        Sender {
+            sender: sender,
+            queue: None,
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            json: json,
+            flush_interval: flush_interval
+        }
+    }
+
+    /// Sends the batches through a queue, rather than straight to the WebSocket connection.
+    pub fn queued(self, queue: outbound::Sender) -> Sender {
+        Sender {
+            queue: Some(queue),
+            ..self
+        }
+    }
+
+    pub fn send(&self, message: WSMessage) -> WSResult<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let was_empty = buffer.is_empty();
+        match message {
+            WSMessage::Text(payload) => append_json(&mut buffer, payload.as_bytes()),
+            WSMessage::Binary(payload) => append_msgpack(&mut buffer, &payload)
+        }
+        if self.flush_interval == 0 {
+            self.send_buffer(&mut buffer)
+        } else if was_empty {
+            self.sender.timeout(self.flush_interval, FLUSH_TIMEOUT)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends any messages that are waiting for the flush interval to pass.
+    pub fn flush(&self) -> WSResult<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        self.send_buffer(&mut buffer)
+    }
+
+    fn send_buffer(&self, buffer: &mut Vec<u8>) -> WSResult<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let payload = mem::replace(buffer, Vec::new());
+        if self.json {
+            let payload = try!(String::from_utf8(payload).map_err(|_| {
+                WSError::new(WSErrorKind::Internal, "Batched JSON messages were not valid UTF-8")
+            }));
+            self.send_frame(WSMessage::Text(payload))
+        } else {
+            self.send_frame(WSMessage::Binary(payload))
+        }
+    }
+
+    fn send_frame(&self, frame: WSMessage) -> WSResult<()> {
+        match self.queue {
+            Some(ref queue) => queue.send(frame),
+            None => self.sender.send(frame)
+        }
+    }
+
+    pub fn close(&self, code: CloseCode) -> WSResult<()> {
+        // Messages sent just before closing (such as GOODBYE) must not be left behind in the batch
+        try!(self.flush());
+        match self.queue {
+            Some(ref queue) => queue.close(),
+            None => self.sender.close(code)
+        }
+    }
+
+    pub fn shutdown(&self) -> WSResult<()> {
+        // Shutting down should still send what is in the batch, but not fail to shut down if it can't
+        self.flush().ok();
+        match self.queue {
+            Some(ref queue) => queue.shutdown(),
+            None => self.sender.shutdown()
+        }
+    }
+
+    #[inline]
+    pub fn is_queued(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    #[inline]
+    pub fn sender(&self) -> &WSSender {
+        &self.sender
+    }
+}
+
+/// Wraps a single message in a batch of its own, for sending outside of the usual batches.
+pub fn single(message: WSMessage) -> WSMessage {
+    let mut buffer = Vec::new();
+    match message {
+        WSMessage::Text(payload) => {
+            append_json(&mut buffer, payload.as_bytes());
+            WSMessage::Text(String::from_utf8(buffer).expect("A JSON message followed by a separator is valid UTF-8"))
+        },
+        WSMessage::Binary(payload) => {
+            append_msgpack(&mut buffer, &payload);
+            WSMessage::Binary(buffer)
+        }
+    }
+}
+
+fn append_json(buffer: &mut Vec<u8>, message: &[u8]) {
+    buffer.extend_from_slice(message);
+    buffer.push(JSON_SEPARATOR as u8);
+}
+
+fn append_msgpack(buffer: &mut Vec<u8>, message: &[u8]) {
+    let len = message.len();
+    buffer.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    buffer.extend_from_slice(message);
+}
+
+/// Splits a frame received over a batched serializer into the messages it contains.
+pub fn split(message: WSMessage) -> io::Result<Vec<WSMessage>> {
+    match message {
+        WSMessage::Text(payload) => {
+            Ok(payload.split(JSON_SEPARATOR).filter(|message| !message.is_empty()).map(|message| {
+                WSMessage::Text(message.to_string())
+            }).collect())
+        },
+        WSMessage::Binary(payload) => {
+            let mut messages = Vec::new();
+            let mut rest = &payload[..];
+            while !rest.is_empty() {
+                if rest.len() < 4 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Batched MsgPack frame ended in the middle of a length prefix"));
+                }
+                let len = ((rest[0] as usize) << 24) | ((rest[1] as usize) << 16) | ((rest[2] as usize) << 8) | rest[3] as usize;
+                if rest.len() - 4 < len {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Batched MsgPack frame ended in the middle of a message"));
+                }
+                messages.push(WSMessage::Binary(rest[4..4 + len].to_vec()));
+                rest = &rest[4 + len..];
+            }
+            Ok(messages)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append_json, append_msgpack, split};
+    use ws::Message as WSMessage;
+
+    #[test]
+    fn json_batch() {
+        let mut buffer = Vec::new();
+        append_json(&mut buffer, b"[6,{},\"wamp.close.normal\"]");
+        append_json(&mut buffer, b"[33,1,{}]");
+        let payload = String::from_utf8(buffer).unwrap();
+        assert_eq!(payload, "[6,{},\"wamp.close.normal\"]\u{18}[33,1,{}]\u{18}");
+        assert_eq!(split(WSMessage::Text(payload)).unwrap(), vec![
+            WSMessage::Text("[6,{},\"wamp.close.normal\"]".to_string()),
+            WSMessage::Text("[33,1,{}]".to_string())
+        ]);
+    }
+
+    #[test]
+    fn msgpack_batch() {
+        let mut buffer = Vec::new();
+        append_msgpack(&mut buffer, &[0x93, 0x21, 0x01, 0x80]);
+        append_msgpack(&mut buffer, &[]);
+        assert_eq!(buffer, vec![0, 0, 0, 4, 0x93, 0x21, 0x01, 0x80, 0, 0, 0, 0]);
+        assert_eq!(split(WSMessage::Binary(buffer)).unwrap(), vec![
+            WSMessage::Binary(vec![0x93, 0x21, 0x01, 0x80]),
+            WSMessage::Binary(vec![])
+        ]);
+        assert!(split(WSMessage::Binary(vec![0, 0, 0, 4, 0x93])).is_err());
+        assert!(split(WSMessage::Binary(vec![0, 0])).is_err());
+    }
+}
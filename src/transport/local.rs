@@ -0,0 +1,39 @@
+//! Contains the in-process transport, which connects a client to a router running in the same
+//! process.  Messages are passed over channels as they are, so they are never serialized.
+use ws::{Error as WSError, ErrorKind as WSErrorKind, Result as WSResult};
+use std::sync::mpsc::{self, channel as mpsc_channel};
+use messages::Message;
+
+/// What one side of a local connection passes to the other.
+pub enum Envelope {
+    Message(Message),
+    Close
+}
+
+pub type Receiver = mpsc::Receiver<Envelope>;
+
+/// The sending half of a local connection.
+#[derive(Clone)]
+pub struct Sender {
+    sender: mpsc::Sender<Envelope>
+}
+
+/// Creates the channel that carries messages towards one side of a local connection.
+pub fn channel() -> (Sender, Receiver) {
+    let (sender, receiver) = mpsc_channel();
+    (Sender { sender: sender }, receiver)
+}
+
+impl Sender {
+    pub fn send_message(&self, message: Message) -> WSResult<()> {
+        self.sender.send(Envelope::Message(message)).map_err(|_| {
+            WSError::new(WSErrorKind::Internal, "The other side of the local connection has gone away")
+        })
+    }
+
+    pub fn close(&self) -> WSResult<()> {
+        // If the other side has already gone away, the connection is as closed as it can get
+        self.sender.send(Envelope::Close).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+pub mod rawsocket;
+pub mod local;
+pub mod batched;
+pub mod outbound;
+pub mod websocket;
+#[cfg(feature = "ssl")]
+pub mod tls;
+
+use ws::{Sender as WSSender, Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult, CloseCode};
+use ws::util::{Token, Timeout};
+use std::sync::Arc;
+use messages::Message;
+use serializer::Serializer;
+use ::{WampResult, Error, ErrorKind};
+
+/// The sending half of a connection, regardless of which transport it was made over.
+#[derive(Clone)]
+pub enum Sender {
+    WebSocket(WSSender),
+    RawSocket(rawsocket::Sender),
+    Local(local::Sender),
+    Batched(batched::Sender),
+    Queued(outbound::Sender)
+}
+
+impl Sender {
+    /// Serializes a message with the given serializer and sends it.  Local connections don't
+    /// serialize messages, so the message is passed on as it is.
+    pub fn send_message(&self, serializer: &Serializer, message: &Message) -> WampResult<()> {
+        let send_result = match *self {
+            Sender::Local(ref sender) => sender.send_message(message.clone()),
+            _ => self.send_frame(try!(encode_message(serializer, message)), is_event(message))
+        };
+        send_result.map_err(|e| Error::new(ErrorKind::WSError(e)))
+    }
+
+    /// Sends a message that has already been encoded with `encode_message`, which saves
+    /// serializing the same message again for every connection it is sent to.  Local connections
+    /// still need the message itself, so it is passed along as well.
+    pub fn send_encoded(&self, message: &Message, encoded: &WSMessage) -> WampResult<()> {
+        let send_result = match *self {
+            Sender::Local(ref sender) => sender.send_message(message.clone()),
+            _ => self.send_frame(encoded.clone(), is_event(message))
+        };
+        send_result.map_err(|e| Error::new(ErrorKind::WSError(e)))
+    }
+
+    #[inline]
+    pub fn is_local(&self) -> bool {
+        match *self {
+            Sender::Local(_) => true,
+            _ => false
+        }
+    }
+
+    #[inline]
+    pub fn send(&self, message: WSMessage) -> WSResult<()> {
+        match *self {
+            Sender::WebSocket(ref sender) => sender.send(message),
+            Sender::RawSocket(ref sender) => sender.send(message),
+            Sender::Batched(ref sender) => sender.send(message),
+            Sender::Queued(ref sender) => sender.send(message),
+            Sender::Local(_) => Err(WSError::new(WSErrorKind::Internal, "Local connections pass messages without serializing them"))
+        }
+    }
+
+    /// Sends a frame, which queued senders may drop if it holds an event and the
+    /// peer has fallen behind.
+    fn send_frame(&self, message: WSMessage, droppable: bool) -> WSResult<()> {
+        match *self {
+            Sender::Queued(ref sender) if droppable => sender.send_event(message),
+            _ => self.send(message)
+        }
+    }
+
+    #[inline]
+    pub fn close(&self, code: CloseCode) -> WSResult<()> {
+        match *self {
+            Sender::WebSocket(ref sender) => sender.close(code),
+            Sender::RawSocket(ref sender) => sender.close(code),
+            Sender::Batched(ref sender) => sender.close(code),
+            Sender::Queued(ref sender) => sender.close(),
+            Sender::Local(ref sender) => sender.close()
+        }
+    }
+
+    #[inline]
+    pub fn shutdown(&self) -> WSResult<()> {
+        match *self {
+            Sender::WebSocket(ref sender) => sender.shutdown(),
+            Sender::RawSocket(ref sender) => sender.shutdown(),
+            Sender::Batched(ref sender) => sender.shutdown(),
+            Sender::Queued(ref sender) => sender.shutdown(),
+            Sender::Local(ref sender) => sender.close()
+        }
+    }
+
+    /// Schedules a timeout on the WebSocket event loop.  Other transports have no event loop, so
+    /// this does nothing for them.
+    #[inline]
+    pub fn timeout(&self, ms: u64, token: Token) -> WSResult<()> {
+        match *self {
+            Sender::WebSocket(ref sender) => sender.timeout(ms, token),
+            Sender::Batched(ref sender) => sender.sender().timeout(ms, token),
+            _ => Ok(())
+        }
+    }
+
+    #[inline]
+    pub fn cancel(&self, timeout: Timeout) -> WSResult<()> {
+        match *self {
+            Sender::WebSocket(ref sender) => sender.cancel(timeout),
+            Sender::Batched(ref sender) => sender.sender().cancel(timeout),
+            _ => Ok(())
+        }
+    }
+
+    /// Sends any messages waiting to be batched together.  Only batched senders hold on to
+    /// messages, so this does nothing for other senders.
+    #[inline]
+    pub fn flush(&self) -> WSResult<()> {
+        match *self {
+            Sender::Batched(ref sender) => sender.flush(),
+            _ => Ok(())
+        }
+    }
+
+    #[inline]
+    pub fn is_batched(&self) -> bool {
+        match *self {
+            Sender::Batched(_) => true,
+            _ => false
+        }
+    }
+
+    /// Switches a WebSocket sender over to sending batches of messages, once a batched serializer
+    /// has been negotiated.
+    pub fn into_batched(self, json: bool, flush_interval: u64) -> Sender {
+        match self {
+            Sender::WebSocket(sender) => Sender::Batched(batched::Sender::new(sender, json, flush_interval)),
+            sender => sender
+        }
+    }
+
+    /// Switches a WebSocket sender over to sending through a queue, which a thread of its own
+    /// writes to the connection.  See `websocket::spawn_writer`.
+    pub fn into_queued(self, queue: outbound::Queue, window: Arc<websocket::Window>, window_size: usize) -> Sender {
+        match self {
+            Sender::WebSocket(sender) => Sender::Queued(websocket::spawn_writer(sender, queue, window, window_size)),
+            Sender::Batched(sender) => {
+                let queue = websocket::spawn_writer(sender.sender().clone(), queue, window, window_size);
+                Sender::Batched(sender.queued(queue))
+            },
+            sender => sender
+        }
+    }
+
+    /// Stops whatever writes a queued sender's frames, once its connection has closed.  Other
+    /// senders have nothing to stop, and are left alone.
+    pub fn stop_writer(&self) {
+        match *self {
+            Sender::Queued(ref sender) => {
+                sender.shutdown().ok();
+            },
+            Sender::Batched(ref sender) if sender.is_queued() => {
+                sender.shutdown().ok();
+            },
+            _ => {}
+        }
+    }
+}
+
+#[inline]
+fn is_event(message: &Message) -> bool {
+    match *message {
+        Message::Event(..) => true,
+        _ => false
+    }
+}
+
+/// Serializes a message into the frame it is sent in, which is a binary frame for binary serializers
+/// and a text frame otherwise.
+pub fn encode_message(serializer: &Serializer, message: &Message) -> WampResult<WSMessage> {
+    let payload = try!(serializer.serialize(message));
+    if serializer.is_binary() {
+        Ok(WSMessage::Binary(payload))
+    } else {
+        match String::from_utf8(payload) {
+            Ok(payload) => Ok(WSMessage::Text(payload)),
+            Err(_) => Err(Error::new(ErrorKind::SerializationError(format!("{} produced a message that was not valid UTF-8", serializer.protocol()))))
+        }
+    }
+}
+
+/// The length of a frame's payload, in bytes.
+#[inline]
+pub fn message_len(message: &WSMessage) -> usize {
+    match *message {
+        WSMessage::Text(ref payload) => payload.len(),
+        WSMessage::Binary(ref payload) => payload.len()
+    }
+}
+
+/// Deserializes a message received in a WebSocket or RawSocket frame.
+pub fn parse_message(serializer: &Serializer, message: WSMessage) -> WampResult<Message> {
+    match message {
+        WSMessage::Text(payload) => serializer.deserialize(payload.as_bytes()),
+        WSMessage::Binary(payload) => serializer.deserialize(&payload)
+    }
+}
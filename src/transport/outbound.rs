@@ -0,0 +1,367 @@
+//! Contains the queue of frames waiting to be written to a connection, for transports whose frames
+//! are written by a thread or task of their own rather than by whoever sends them.  The router's
+//! RawSocket and WebSocket connections all work this way.
+//!
+//! The queue holds a limited number of bytes.  Once a peer falls so far behind that its queue is
+//! full, the queue's `SlowConsumerPolicy` decides what gives.  Only events are ever dropped, since
+//! dropping any other message (such as the result of a call) would leave the peer waiting forever,
+//! so a peer whose queue is full of other messages is disconnected whatever the policy.  A frame
+//! is always accepted by an empty queue, however long it is.
+use ws::{Message as WSMessage, Error as WSError, ErrorKind as WSErrorKind, Result as WSResult};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use transport::message_len;
+#[cfg(feature = "async")]
+use futures::{Async, Poll, Stream};
+#[cfg(feature = "async")]
+use futures::task::AtomicTask;
+
+/// How many bytes can wait to be written to a connection, unless set with `Router::set_outbound_limit`.
+pub const DEFAULT_LIMIT: usize = 1 << 22;
+
+/// What to do when a connection's queue is full, because the peer is not reading its messages as
+/// fast as they are being sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Discard the oldest events in the queue to make room for the new message
+    DropOldest,
+    /// Discard the new message if it is an event, and otherwise the oldest events in the queue
+    DropNewest,
+    /// Discard everything in the queue, say goodbye and close the connection
+    Disconnect
+}
+
+/// What is waiting to be written to the connection.
+pub enum Frame {
+    Message(WSMessage),
+    Pong(Vec<u8>),
+    Close
+}
+
+struct Queued {
+    frame: Frame,
+    droppable: bool
+}
+
+struct State {
+    frames: VecDeque<Queued>,
+    /// The number of bytes in the queued frames
+    len: usize,
+    closed: bool,
+    overflowed: bool
+}
+
+pub struct Queue {
+    state: Mutex<State>,
+    ready: Condvar,
+    #[cfg(feature = "async")]
+    task: AtomicTask,
+    limit: usize,
+    policy: SlowConsumerPolicy,
+    farewell: Option<WSMessage>
+}
+
+/// The sending half of a connection's queue.  Sending never blocks on a slow peer, since whoever
+/// owns the socket takes the frames from the queue and writes them out.  Cloning a `Sender` gives
+/// another handle to the same queue.
+#[derive(Clone)]
+pub struct Sender {
+    queue: Arc<Queue>,
+    max_length: usize
+}
+
+/// The frames waiting to be written, for a task that owns the socket.  The stream never ends on
+/// its own, so the task should stop once it sees a `Frame::Close`.
+#[cfg(feature = "async")]
+pub struct Receiver {
+    queue: Arc<Queue>
+}
+
+#[cfg(feature = "async")]
+impl Stream for Receiver {
+    type Item = Frame;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Frame>, ()> {
+        match try!(self.queue.poll_frame()) {
+            Async::Ready(frame) => Ok(Async::Ready(Some(frame))),
+            Async::NotReady => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Sender {
+    /// Creates a sender that refuses frames longer than `max_length`, which is the longest the
+    /// peer said it would accept.
+    pub fn new(queue: Queue, max_length: usize) -> Sender {
+        Sender {
+            queue: Arc::new(queue),
+            max_length: max_length
+        }
+    }
+
+    /// The queue the sender adds frames to, for a thread that writes them out.
+    pub fn queue(&self) -> Arc<Queue> {
+        self.queue.clone()
+    }
+
+    /// The frames the sender queues, for a task that writes them out.
+    #[cfg(feature = "async")]
+    pub fn receiver(&self) -> Receiver {
+        Receiver {
+            queue: self.queue.clone()
+        }
+    }
+
+    pub fn send(&self, message: WSMessage) -> WSResult<()> {
+        try!(self.check_length(message_len(&message)));
+        self.queue.push_message(message, false)
+    }
+
+    /// Sends an event, which may be dropped if the peer has fallen behind.
+    pub fn send_event(&self, message: WSMessage) -> WSResult<()> {
+        try!(self.check_length(message_len(&message)));
+        self.queue.push_message(message, true)
+    }
+
+    pub fn pong(&self, payload: Vec<u8>) -> WSResult<()> {
+        try!(self.check_length(payload.len()));
+        self.queue.push_pong(payload)
+    }
+
+    /// Closes the connection once the frames already queued have been written.
+    pub fn close(&self) -> WSResult<()> {
+        self.queue.close();
+        Ok(())
+    }
+
+    /// Closes the connection without writing the frames still in the queue.
+    pub fn shutdown(&self) -> WSResult<()> {
+        self.queue.shutdown();
+        Ok(())
+    }
+
+    fn check_length(&self, len: usize) -> WSResult<()> {
+        if len > self.max_length {
+            warn!("Refusing to send a {} byte message to a peer that accepts at most {} bytes", len, self.max_length);
+            return Err(WSError::new(WSErrorKind::Protocol, "The message is longer than the peer accepts"));
+        }
+        Ok(())
+    }
+}
+
+impl Queue {
+    /// Creates a queue holding up to `limit` bytes.  If the connection is closed because it fell
+    /// behind, `farewell` is the last message sent to it.
+    pub fn new(limit: usize, policy: SlowConsumerPolicy, farewell: Option<WSMessage>) -> Queue {
+        Queue {
+            state: Mutex::new(State {
+                frames: VecDeque::new(),
+                len: 0,
+                closed: false,
+                overflowed: false
+            }),
+            ready: Condvar::new(),
+            #[cfg(feature = "async")]
+            task: AtomicTask::new(),
+            limit: limit,
+            policy: policy,
+            farewell: farewell
+        }
+    }
+
+    /// Queues a message.  Events should be marked as `droppable`, so that the queue's policy can
+    /// discard them when the queue is full.
+    pub fn push_message(&self, message: WSMessage, droppable: bool) -> WSResult<()> {
+        self.push(Frame::Message(message), droppable)
+    }
+
+    pub fn push_pong(&self, payload: Vec<u8>) -> WSResult<()> {
+        self.push(Frame::Pong(payload), true)
+    }
+
+    /// Closes the connection once the frames already queued have been written.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.frames.push_back(Queued { frame: Frame::Close, droppable: false });
+            state.closed = true;
+            self.notify();
+        }
+    }
+
+    /// Discards the frames that have not been written yet and closes the connection straight away.
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.frames.clear();
+        state.len = 0;
+        state.frames.push_back(Queued { frame: Frame::Close, droppable: false });
+        state.closed = true;
+        self.notify();
+    }
+
+    /// Whether the connection has been closed, so that no more frames will be queued.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Whether the connection was closed because it fell too far behind.
+    pub fn overflowed(&self) -> bool {
+        self.state.lock().unwrap().overflowed
+    }
+
+    /// Takes the next frame to write, waiting until there is one.
+    pub fn pop(&self) -> Frame {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(queued) = state.frames.pop_front() {
+                state.len -= frame_len(&queued.frame);
+                return queued.frame;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Takes the next frame to write, if there is one, and otherwise wakes the current task once
+    /// there is.
+    #[cfg(feature = "async")]
+    pub fn poll_frame(&self) -> Poll<Frame, ()> {
+        self.task.register();
+        let mut state = self.state.lock().unwrap();
+        match state.frames.pop_front() {
+            Some(queued) => {
+                state.len -= frame_len(&queued.frame);
+                Ok(Async::Ready(queued.frame))
+            },
+            None => Ok(Async::NotReady)
+        }
+    }
+
+    fn push(&self, frame: Frame, droppable: bool) -> WSResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(WSError::new(WSErrorKind::Internal, "The connection has been closed"));
+        }
+        let len = frame_len(&frame);
+        if self.overflows(&state, len) && self.policy != SlowConsumerPolicy::Disconnect {
+            if droppable && self.policy == SlowConsumerPolicy::DropNewest {
+                debug!("Dropping a message to a slow consumer");
+                return Ok(());
+            }
+            while self.overflows(&state, len) {
+                match state.frames.iter().position(|queued| queued.droppable) {
+                    Some(index) => {
+                        debug!("Dropping the oldest event queued for a slow consumer");
+                        let dropped = state.frames.remove(index).unwrap();
+                        state.len -= frame_len(&dropped.frame);
+                    },
+                    None => break
+                }
+            }
+            if self.overflows(&state, len) && droppable {
+                debug!("Dropping a message to a slow consumer");
+                return Ok(());
+            }
+        }
+        if self.overflows(&state, len) {
+            warn!("Disconnecting a consumer that fell {} bytes behind", state.len);
+            state.frames.clear();
+            state.len = 0;
+            if let Some(ref farewell) = self.farewell {
+                state.len = message_len(farewell);
+                state.frames.push_back(Queued { frame: Frame::Message(farewell.clone()), droppable: false });
+            }
+            state.frames.push_back(Queued { frame: Frame::Close, droppable: false });
+            state.closed = true;
+            state.overflowed = true;
+            self.notify();
+            return Err(WSError::new(WSErrorKind::Capacity, "The connection fell too far behind and was closed"));
+        }
+        state.len += len;
+        state.frames.push_back(Queued { frame: frame, droppable: droppable });
+        self.notify();
+        Ok(())
+    }
+
+    /// Whether queueing `len` more bytes would take the queue past its limit.
+    #[inline]
+    fn overflows(&self, state: &State, len: usize) -> bool {
+        state.len > 0 && state.len + len > self.limit
+    }
+
+    fn notify(&self) {
+        self.ready.notify_one();
+        #[cfg(feature = "async")]
+        self.task.notify();
+    }
+}
+
+/// The number of bytes a queued frame counts for.
+#[inline]
+fn frame_len(frame: &Frame) -> usize {
+    match *frame {
+        Frame::Message(ref message) => message_len(message),
+        Frame::Pong(ref payload) => payload.len(),
+        Frame::Close => 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Queue, Frame, SlowConsumerPolicy};
+    use ws::Message as WSMessage;
+
+    fn text(frame: Frame) -> String {
+        match frame {
+            Frame::Message(WSMessage::Text(text)) => text,
+            Frame::Close => "close".to_string(),
+            _ => panic!("Unexpected frame")
+        }
+    }
+
+    fn push(queue: &Queue, text: &str, droppable: bool) -> bool {
+        queue.push_message(WSMessage::Text(text.to_string()), droppable).is_ok()
+    }
+
+    #[test]
+    fn policies() {
+        // Every message is 7 bytes long, so the queue holds two at a time
+        let queue = Queue::new(14, SlowConsumerPolicy::DropOldest, None);
+        assert!(push(&queue, "result1", false));
+        assert!(push(&queue, "event 1", true));
+        assert!(push(&queue, "event 2", true));
+        assert!(push(&queue, "result2", false));
+        assert_eq!(text(queue.pop()), "result1");
+        assert_eq!(text(queue.pop()), "result2");
+
+        let queue = Queue::new(14, SlowConsumerPolicy::DropNewest, None);
+        assert!(push(&queue, "event 1", true));
+        assert!(push(&queue, "event 2", true));
+        assert!(push(&queue, "event 3", true));
+        assert!(push(&queue, "result1", false));
+        assert_eq!(text(queue.pop()), "event 2");
+        assert_eq!(text(queue.pop()), "result1");
+
+        // Messages that can't be dropped disconnect the consumer, whatever the policy
+        let queue = Queue::new(14, SlowConsumerPolicy::DropOldest, Some(WSMessage::Text("goodbye".to_string())));
+        assert!(push(&queue, "result1", false));
+        assert!(push(&queue, "result2", false));
+        assert!(!push(&queue, "result3", false));
+        assert!(!push(&queue, "event 1", true));
+        assert!(queue.overflowed());
+        assert_eq!(text(queue.pop()), "goodbye");
+        assert_eq!(text(queue.pop()), "close");
+
+        let queue = Queue::new(7, SlowConsumerPolicy::Disconnect, Some(WSMessage::Text("goodbye".to_string())));
+        assert!(push(&queue, "event 1", true));
+        assert!(!push(&queue, "event 2", true));
+        assert_eq!(text(queue.pop()), "goodbye");
+        assert_eq!(text(queue.pop()), "close");
+
+        // An empty queue takes a message of any length
+        let queue = Queue::new(2, SlowConsumerPolicy::Disconnect, None);
+        assert!(push(&queue, "result1", false));
+        assert_eq!(text(queue.pop()), "result1");
+        assert!(!queue.overflowed());
+    }
+}
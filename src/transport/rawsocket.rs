@@ -0,0 +1,323 @@
+//! Contains the framing used by the WAMP RawSocket transport, which carries WAMP messages over
+//! plain byte streams (such as TCP connections or Unix domain sockets) without a WebSocket upgrade.
+//!
+//! A RawSocket connection begins with a four octet handshake in each direction, after which
+//! every message is prefixed by a four octet header holding the frame type and payload length.
+use ws::{Message as WSMessage, Result as WSResult, CloseCode};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use transport::outbound::{self, Queue, Frame};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+pub const MAGIC: u8 = 0x7F;
+
+pub const SERIALIZER_JSON: u8 = 1;
+pub const SERIALIZER_MSGPACK: u8 = 2;
+pub const SERIALIZER_CBOR: u8 = 3;
+
+pub const ERROR_SERIALIZER_UNSUPPORTED: u8 = 1;
+pub const ERROR_RESERVED_BITS: u8 = 3;
+
+/// The largest length exponent we announce.  The maximum message length is `2^(9 + exponent)`,
+/// so this allows messages of up to 16MB.
+pub const MAX_LENGTH_EXPONENT: u8 = 15;
+
+/// The type of a RawSocket frame, as given by the lowest three bits of its header.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FrameType {
+    Message,
+    Ping,
+    Pong
+}
+
+/// The result of a handshake, as sent by the other peer.
+#[derive(PartialEq, Debug)]
+pub enum Handshake {
+    /// The peer accepted (or requested) the given serializer, and will accept messages up to `max_length` bytes
+    Accepted {
+        serializer: u8,
+        max_length: usize
+    },
+    /// The peer refused the connection with the given error code
+    Refused(u8)
+}
+
+/// A byte stream that a RawSocket connection can be run over.
+pub trait Stream: Read + Write + Send {
+    fn try_clone_stream(&self) -> io::Result<Box<Stream>>;
+    fn shutdown_stream(&self) -> io::Result<()>;
+    fn set_stream_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone_stream(&self) -> io::Result<Box<Stream>> {
+        Ok(Box::new(try!(self.try_clone())))
+    }
+
+    fn shutdown_stream(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn set_stream_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone_stream(&self) -> io::Result<Box<Stream>> {
+        Ok(Box::new(try!(self.try_clone())))
+    }
+
+    fn shutdown_stream(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn set_stream_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+/// The sending half of a RawSocket connection, which writes each frame to the stream itself.
+/// Cloning a `Sender` yields another handle to the same stream.
+#[derive(Clone)]
+pub struct Sender {
+    stream: Arc<Mutex<Box<Stream>>>
+}
+
+impl Sender {
+    pub fn new(stream: Box<Stream>) -> Sender {
+        Sender {
+            stream: Arc::new(Mutex::new(stream))
+        }
+    }
+
+    pub fn send(&self, message: WSMessage) -> WSResult<()> {
+        let payload = match message {
+            WSMessage::Text(payload) => payload.into_bytes(),
+            WSMessage::Binary(payload) => payload
+        };
+        let mut stream = self.stream.lock().unwrap();
+        try!(write_frame(&mut *stream, FrameType::Message, &payload));
+        Ok(())
+    }
+
+    pub fn pong(&self, payload: &[u8]) -> WSResult<()> {
+        let mut stream = self.stream.lock().unwrap();
+        try!(write_frame(&mut *stream, FrameType::Pong, payload));
+        Ok(())
+    }
+
+    pub fn close(&self, _code: CloseCode) -> WSResult<()> {
+        self.shutdown()
+    }
+
+    pub fn shutdown(&self) -> WSResult<()> {
+        let stream = self.stream.lock().unwrap();
+        match stream.shutdown_stream() {
+            Ok(()) => Ok(()),
+            // The stream may already have been closed by the other side
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+/// Starts a thread that writes the frames in `queue` to `writer` in order, and returns a sender
+/// that adds frames to the queue, so that sending never blocks on a slow peer.  The sender
+/// refuses frames longer than `max_length`, the longest the peer accepts.  Closing the sender
+/// closes the stream once the queued frames have been written, while shutting it down closes the
+/// stream straight away.
+pub fn spawn_writer(mut writer: Box<Stream>, queue: Queue, max_length: usize) -> outbound::Sender {
+    let sender = outbound::Sender::new(queue, max_length);
+    let outgoing = sender.queue();
+    thread::spawn(move || {
+        loop {
+            let written = match outgoing.pop() {
+                Frame::Message(WSMessage::Text(payload)) => write_frame(&mut writer, FrameType::Message, payload.as_bytes()),
+                Frame::Message(WSMessage::Binary(payload)) => write_frame(&mut writer, FrameType::Message, &payload),
+                Frame::Pong(payload) => write_frame(&mut writer, FrameType::Pong, &payload),
+                Frame::Close => break
+            };
+            if let Err(e) = written {
+                debug!("Could not write RawSocket frame: {}", e);
+                outgoing.shutdown();
+                break;
+            }
+        }
+        writer.shutdown_stream().ok();
+    });
+    sender
+}
+
+/// Converts a length exponent from a handshake into the maximum message length it represents.
+#[inline]
+pub fn max_length(exponent: u8) -> usize {
+    1 << (9 + exponent as usize)
+}
+
+/// The largest length exponent that doesn't allow messages longer than `limit`, so that the peer
+/// never sends one.  Messages of up to 512 bytes are always allowed.
+pub fn length_exponent(limit: usize) -> u8 {
+    let mut exponent = 0;
+    while exponent < MAX_LENGTH_EXPONENT && max_length(exponent + 1) <= limit {
+        exponent += 1;
+    }
+    exponent
+}
+
+pub fn write_handshake<W: Write>(writer: &mut W, length_exponent: u8, serializer: u8) -> io::Result<()> {
+    writer.write_all(&[MAGIC, (length_exponent << 4) | (serializer & 0x0F), 0, 0])
+}
+
+pub fn write_handshake_error<W: Write>(writer: &mut W, error: u8) -> io::Result<()> {
+    writer.write_all(&[MAGIC, error << 4, 0, 0])
+}
+
+pub fn read_handshake<R: Read>(reader: &mut R) -> io::Result<Handshake> {
+    let mut buf = [0u8; 4];
+    try!(reader.read_exact(&mut buf));
+    parse_handshake(&buf)
+}
+
+/// Parses the four octets of a handshake.
+pub fn parse_handshake(buf: &[u8; 4]) -> io::Result<Handshake> {
+    if buf[0] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "RawSocket handshake did not begin with the magic octet"));
+    }
+    if buf[2] != 0 || buf[3] != 0 {
+        return Ok(Handshake::Refused(ERROR_RESERVED_BITS));
+    }
+    let serializer = buf[1] & 0x0F;
+    if serializer == 0 {
+        Ok(Handshake::Refused(buf[1] >> 4))
+    } else {
+        Ok(Handshake::Accepted {
+            serializer: serializer,
+            max_length: max_length(buf[1] >> 4)
+        })
+    }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame_type: FrameType, payload: &[u8]) -> io::Result<()> {
+    try!(writer.write_all(&try!(frame_header(frame_type, payload.len()))));
+    try!(writer.write_all(payload));
+    writer.flush()
+}
+
+/// Creates the header of a frame with a payload of the given length.  The length field is three
+/// octets, so payloads can be at most 2^24 - 1 bytes long.
+pub fn frame_header(frame_type: FrameType, len: usize) -> io::Result<[u8; 4]> {
+    if len >= 1 << 24 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Message too long for a RawSocket frame"));
+    }
+    let type_bits = match frame_type {
+        FrameType::Message => 0,
+        FrameType::Ping => 1,
+        FrameType::Pong => 2
+    };
+    Ok([type_bits, (len >> 16) as u8, (len >> 8) as u8, len as u8])
+}
+
+/// Parses the header of a frame into its type and the length of its payload, refusing any frame
+/// whose payload is longer than `max_length`.
+pub fn parse_frame_header(header: &[u8], max_length: usize) -> io::Result<(FrameType, usize)> {
+    let frame_type = match header[0] & 0x07 {
+        0 => FrameType::Message,
+        1 => FrameType::Ping,
+        2 => FrameType::Pong,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown RawSocket frame type"))
+    };
+    let len = ((header[1] as usize) << 16) | ((header[2] as usize) << 8) | header[3] as usize;
+    if len > max_length {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "RawSocket frame exceeded the maximum message length"));
+    }
+    Ok((frame_type, len))
+}
+
+/// Reads a single frame, refusing any frame whose payload is longer than `max_length`.
+pub fn read_frame<R: Read>(reader: &mut R, max_length: usize) -> io::Result<(FrameType, Vec<u8>)> {
+    let mut header = [0u8; 4];
+    try!(reader.read_exact(&mut header));
+    let (frame_type, len) = try!(parse_frame_header(&header, max_length));
+    let mut payload = vec![0u8; len];
+    try!(reader.read_exact(&mut payload));
+    Ok((frame_type, payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_handshake, write_handshake_error, read_handshake, write_frame, read_frame, max_length,
+                frame_header, length_exponent, Handshake, FrameType, SERIALIZER_MSGPACK, ERROR_SERIALIZER_UNSUPPORTED, MAX_LENGTH_EXPONENT};
+    use std::io::Cursor;
+    #[cfg(unix)]
+    use super::spawn_writer;
+    #[cfg(unix)]
+    use transport::outbound::{Queue, SlowConsumerPolicy, DEFAULT_LIMIT};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+    #[cfg(unix)]
+    use ws::Message as WSMessage;
+
+    #[test]
+    fn handshake() {
+        let mut buf = Vec::new();
+        write_handshake(&mut buf, MAX_LENGTH_EXPONENT, SERIALIZER_MSGPACK).unwrap();
+        assert_eq!(buf, vec![0x7F, 0xF2, 0, 0]);
+        assert_eq!(read_handshake(&mut Cursor::new(buf)).unwrap(), Handshake::Accepted {
+            serializer: SERIALIZER_MSGPACK,
+            max_length: 1 << 24
+        });
+
+        let mut buf = Vec::new();
+        write_handshake_error(&mut buf, ERROR_SERIALIZER_UNSUPPORTED).unwrap();
+        assert_eq!(read_handshake(&mut Cursor::new(buf)).unwrap(), Handshake::Refused(ERROR_SERIALIZER_UNSUPPORTED));
+
+        assert!(read_handshake(&mut Cursor::new(vec![0x7E, 0xF2, 0, 0])).is_err());
+
+        assert_eq!(length_exponent(100), 0);
+        assert_eq!(length_exponent(1 << 20), 11);
+        assert_eq!(length_exponent((1 << 20) + 1), 11);
+        assert_eq!(length_exponent(usize::max_value()), MAX_LENGTH_EXPONENT);
+    }
+
+    #[test]
+    fn frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, FrameType::Message, b"[6,{},\"wamp.close.normal\"]").unwrap();
+        write_frame(&mut buf, FrameType::Ping, b"").unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 26]);
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_frame(&mut cursor, max_length(0)).unwrap(), (FrameType::Message, b"[6,{},\"wamp.close.normal\"]".to_vec()));
+        assert_eq!(read_frame(&mut cursor, max_length(0)).unwrap(), (FrameType::Ping, Vec::new()));
+        assert!(read_frame(&mut cursor, max_length(0)).is_err());
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, FrameType::Message, &[0u8; 513]).unwrap();
+        assert!(read_frame(&mut Cursor::new(buf), max_length(0)).is_err());
+
+        // The length field can't hold 2^24, which would wrap around to 0
+        assert_eq!(frame_header(FrameType::Message, (1 << 24) - 1).unwrap(), [0, 0xFF, 0xFF, 0xFF]);
+        assert!(frame_header(FrameType::Message, 1 << 24).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writer_thread() {
+        let (mut stream, other) = UnixStream::pair().unwrap();
+        let sender = spawn_writer(Box::new(other), Queue::new(DEFAULT_LIMIT, SlowConsumerPolicy::Disconnect, None), max_length(0));
+        sender.send(WSMessage::Text("[6,{},\"wamp.close.normal\"]".to_string())).unwrap();
+        assert!(sender.send(WSMessage::Binary(vec![0u8; 513])).is_err());
+        sender.pong(b"ping".to_vec()).unwrap();
+        sender.close().unwrap();
+
+        assert_eq!(read_frame(&mut stream, max_length(0)).unwrap(), (FrameType::Message, b"[6,{},\"wamp.close.normal\"]".to_vec()));
+        assert_eq!(read_frame(&mut stream, max_length(0)).unwrap(), (FrameType::Pong, b"ping".to_vec()));
+        assert!(read_frame(&mut stream, max_length(0)).is_err());
+    }
+}
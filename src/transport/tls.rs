@@ -0,0 +1,310 @@
+//! Contains the configuration used to secure connections with TLS (`wss://`).
+use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslMethod, SslFiletype, SslVerifyMode, SslStream};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::x509::{X509Ref, X509NameRef};
+use mio::tcp::TcpStream;
+use ws::{Error as WSError, ErrorKind as WSErrorKind, Result as WSResult};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use ::{WampResult, Error, ErrorKind};
+
+macro_rules! try_ssl {
+    ($e: expr) => (
+        match $e {
+            Ok(result) => result,
+            Err(e) => return Err(ssl_error(e))
+        }
+    );
+}
+
+/// The certificates and keys a router uses to accept TLS connections.
+#[derive(Clone, Debug)]
+pub struct TlsServerConfig {
+    certificate_chain: PathBuf,
+    private_key: PathBuf,
+    client_ca: Option<PathBuf>,
+    require_client_certificate: bool,
+    default_authrole: String
+}
+
+/// The trust roots and (optional) certificate a client uses when connecting to a router over TLS.
+pub struct TlsClientConfig {
+    root_certificate: Option<PathBuf>,
+    certificate: Option<(PathBuf, PathBuf)>
+}
+
+/// Accepts TLS connections for a listener, recording the identity of each verified client.
+pub struct TlsAcceptor {
+    acceptor: SslAcceptor,
+    default_authrole: String
+}
+
+/// The identity of a client, as given by its verified certificate.
+#[derive(Clone, Debug)]
+pub struct TlsIdentity {
+    pub authid: String,
+    pub authrole: String
+}
+
+fn ssl_error(error: ErrorStack) -> Error {
+    Error::new(ErrorKind::WSError(WSError::new(WSErrorKind::Internal, format!("TLS configuration error: {}", error))))
+}
+
+impl TlsServerConfig {
+    /// Creates a configuration from a PEM encoded certificate chain (leaf certificate first)
+    /// and the PEM encoded private key for the leaf certificate.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(certificate_chain: P, private_key: Q) -> TlsServerConfig {
+        TlsServerConfig {
+            certificate_chain: certificate_chain.as_ref().to_path_buf(),
+            private_key: private_key.as_ref().to_path_buf(),
+            client_ca: None,
+            require_client_certificate: false,
+            default_authrole: "user".to_string()
+        }
+    }
+
+    /// Verifies client certificates against the certificate authorities in the given PEM file.
+    ///
+    /// If `required` is true, clients that do not present a certificate will be refused.
+    /// Otherwise, only certificates that are presented are verified.
+    pub fn set_client_ca<P: AsRef<Path>>(&mut self, ca_file: P, required: bool) {
+        self.client_ca = Some(ca_file.as_ref().to_path_buf());
+        self.require_client_certificate = required;
+    }
+
+    /// Sets the authrole given to clients authenticated with the `tls` authmethod whose certificate
+    /// subject does not contain an organizational unit.  Defaults to "user".
+    pub fn set_default_authrole(&mut self, authrole: &str) {
+        self.default_authrole = authrole.to_string();
+    }
+}
+
+impl TlsAcceptor {
+    /// Performs the TLS handshake, returning the stream along with a slot that will hold the
+    /// client's identity once its certificate has been verified.
+    pub fn accept(&self, stream: TcpStream) -> WSResult<(SslStream<TcpStream>, Arc<Mutex<Option<TlsIdentity>>>)> {
+        let identity = Arc::new(Mutex::new(None));
+        let mut ssl = try!(Ssl::new(self.acceptor.context()).map_err(|e| {
+            WSError::new(WSErrorKind::Internal, format!("Could not create TLS session: {}", e))
+        }));
+        let verify_mode = self.acceptor.context().verify_mode();
+        let default_authrole = self.default_authrole.clone();
+        let client_identity = identity.clone();
+        ssl.set_verify_callback(verify_mode, move |preverify_ok, context| {
+            if preverify_ok && context.error_depth() == 0 {
+                if let Some(certificate) = context.current_cert() {
+                    *client_identity.lock().unwrap() = TlsIdentity::from_certificate(certificate, &default_authrole);
+                }
+            }
+            preverify_ok
+        });
+        let stream = try!(ssl.accept(stream).map_err(WSError::from));
+        Ok((stream, identity))
+    }
+}
+
+impl TlsIdentity {
+    /// Derives an identity from a certificate.  The authid is taken from the first DNS name or
+    /// email address in the subject alternative names, falling back to the subject's common name.
+    /// The authrole is taken from the subject's organizational unit.
+    fn from_certificate(certificate: &X509Ref, default_authrole: &str) -> Option<TlsIdentity> {
+        let alt_name = certificate.subject_alt_names().and_then(|names| {
+            names.iter().filter_map(|name| {
+                name.dnsname().or_else(|| name.email()).map(|name| name.to_string())
+            }).next()
+        });
+        let authid = match alt_name {
+            Some(authid) => authid,
+            None => match name_entry(certificate.subject_name(), Nid::COMMONNAME) {
+                Some(authid) => authid,
+                None => return None
+            }
+        };
+        let authrole = name_entry(certificate.subject_name(), Nid::ORGANIZATIONALUNITNAME).unwrap_or(default_authrole.to_string());
+        Some(TlsIdentity {
+            authid: authid,
+            authrole: authrole
+        })
+    }
+}
+
+fn name_entry(name: &X509NameRef, nid: Nid) -> Option<String> {
+    name.entries_by_nid(nid).next().and_then(|entry| {
+        entry.data().as_utf8().ok().map(|data| data.to_string())
+    })
+}
+
+pub fn build_acceptor(config: &TlsServerConfig) -> WampResult<TlsAcceptor> {
+    let mut builder = try_ssl!(SslAcceptor::mozilla_intermediate(SslMethod::tls()));
+    try_ssl!(builder.set_private_key_file(&config.private_key, SslFiletype::PEM));
+    try_ssl!(builder.set_certificate_chain_file(&config.certificate_chain));
+    try_ssl!(builder.check_private_key());
+    if let Some(ref ca_file) = config.client_ca {
+        try_ssl!(builder.set_ca_file(ca_file));
+        if config.require_client_certificate {
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        } else {
+            builder.set_verify(SslVerifyMode::PEER);
+        }
+    }
+    Ok(TlsAcceptor {
+        acceptor: builder.build(),
+        default_authrole: config.default_authrole.clone()
+    })
+}
+
+impl TlsClientConfig {
+    /// Creates a configuration that trusts the system's default certificate authorities.
+    pub fn new() -> TlsClientConfig {
+        TlsClientConfig {
+            root_certificate: None,
+            certificate: None
+        }
+    }
+
+    /// Trusts the certificate authorities in the given PEM file, in addition to the system defaults.
+    ///
+    /// This is how a router using a self-signed certificate can be trusted.
+    pub fn set_root_certificate<P: AsRef<Path>>(&mut self, ca_file: P) {
+        self.root_certificate = Some(ca_file.as_ref().to_path_buf());
+    }
+
+    /// Presents the given certificate chain and private key (both PEM encoded) to the router.
+    pub fn set_certificate<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, certificate_chain: P, private_key: Q) {
+        self.certificate = Some((certificate_chain.as_ref().to_path_buf(), private_key.as_ref().to_path_buf()));
+    }
+}
+
+pub fn build_connector(config: &TlsClientConfig) -> WampResult<SslConnector> {
+    let mut builder = try_ssl!(SslConnector::builder(SslMethod::tls()));
+    if let Some(ref ca_file) = config.root_certificate {
+        try_ssl!(builder.set_ca_file(ca_file));
+    }
+    if let Some((ref certificate_chain, ref private_key)) = config.certificate {
+        try_ssl!(builder.set_certificate_chain_file(certificate_chain));
+        try_ssl!(builder.set_private_key_file(private_key, SslFiletype::PEM));
+        try_ssl!(builder.check_private_key());
+    }
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TlsServerConfig, TlsClientConfig, TlsIdentity};
+    use router::{Router, ListenerAddress};
+    use client::Connection;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509NameBuilder};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Creates a self-signed certificate with the given subject and alternative names, along with
+    /// its private key.
+    fn certificate(subject: &[(Nid, &str)], dns_name: Option<&str>, email: Option<&str>) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        for &(nid, value) in subject {
+            name.append_entry_by_nid(nid, value).unwrap();
+        }
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        if dns_name.is_some() || email.is_some() {
+            let mut alt_names = SubjectAlternativeName::new();
+            if let Some(dns_name) = dns_name {
+                alt_names.dns(dns_name);
+            }
+            if let Some(email) = email {
+                alt_names.email(email);
+            }
+            let alt_names = alt_names.build(&builder.x509v3_context(None, None)).unwrap();
+            builder.append_extension(alt_names).unwrap();
+        }
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    /// Creates a self-signed certificate for `localhost` and its private key.
+    fn self_signed() -> (X509, PKey<Private>) {
+        certificate(&[(Nid::COMMONNAME, "localhost")], Some("localhost"), None)
+    }
+
+    /// Writes the certificate and key as PEM files in a directory of their own.
+    fn write_pem(name: &str, certificate: &X509, key: &PKey<Private>) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("wamp-tls-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let certificate_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        File::create(&certificate_path).unwrap().write_all(&certificate.to_pem().unwrap()).unwrap();
+        File::create(&key_path).unwrap().write_all(&key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (certificate_path, key_path)
+    }
+
+    #[test]
+    fn self_signed_router() {
+        let (certificate, key) = self_signed();
+        let (certificate_path, key_path) = write_pem("router", &certificate, &key);
+
+        let mut router = Router::new();
+        router.add_realm("tls_realm");
+        let listener = router.listen_tls("127.0.0.1:0", &TlsServerConfig::new(&certificate_path, &key_path)).unwrap();
+        let port = match *listener.local_addr() {
+            ListenerAddress::Tcp(address) => address.port(),
+            ref address => panic!("Expected a TCP address, got {:?}", address)
+        };
+        // The certificate is for localhost, so the client has to connect by name rather than address
+        let url = format!("wss://localhost:{}", port);
+
+        let mut trusting = Connection::new(&url, "tls_realm");
+        let mut client_config = TlsClientConfig::new();
+        client_config.set_root_certificate(&certificate_path);
+        trusting.set_tls_config(&client_config).unwrap();
+        trusting.connect().unwrap();
+
+        let mut untrusting = Connection::new(&url, "tls_realm");
+        untrusting.set_timeout(1000);
+        assert!(untrusting.connect().is_err());
+    }
+
+    #[test]
+    fn identity_from_certificate() {
+        // A DNS name in the alternative names wins over the common name
+        let (with_dns_name, _) = certificate(&[(Nid::COMMONNAME, "ignored"), (Nid::ORGANIZATIONALUNITNAME, "admin")], Some("backend.example.com"), None);
+        let identity = TlsIdentity::from_certificate(&with_dns_name, "user").unwrap();
+        assert_eq!(identity.authid, "backend.example.com");
+        assert_eq!(identity.authrole, "admin");
+
+        // As does an email address
+        let (with_email, _) = certificate(&[(Nid::COMMONNAME, "ignored")], None, Some("alice@example.com"));
+        let identity = TlsIdentity::from_certificate(&with_email, "user").unwrap();
+        assert_eq!(identity.authid, "alice@example.com");
+        assert_eq!(identity.authrole, "user");
+
+        // Otherwise the common name is used, and the default authrole if there is no organizational unit
+        let (common_name_only, _) = certificate(&[(Nid::COMMONNAME, "bob")], None, None);
+        let identity = TlsIdentity::from_certificate(&common_name_only, "guest").unwrap();
+        assert_eq!(identity.authid, "bob");
+        assert_eq!(identity.authrole, "guest");
+
+        let (anonymous, _) = certificate(&[(Nid::ORGANIZATIONALUNITNAME, "admin")], None, None);
+        assert!(TlsIdentity::from_certificate(&anonymous, "user").is_none());
+    }
+}
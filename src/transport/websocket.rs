@@ -0,0 +1,135 @@
+//! Contains the writer that moves frames from a connection's queue to a WebSocket connection.
+//!
+//! The WebSocket library buffers whatever it is given, and never says when a frame has been
+//! written to the socket, so the writer paces itself by the peer instead.  Every so often it sends
+//! a ping carrying the number of bytes sent so far, and it stops sending once a window's worth of
+//! bytes has not been acknowledged by a pong.  A peer answers pings in the order it reads them, so
+//! a peer that stops reading stops answering, and messages for it pile up in the queue, where the
+//! queue's `SlowConsumerPolicy` deals with them.
+use ws::{Sender as WSSender, Message as WSMessage, Result as WSResult, CloseCode};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
+use transport::message_len;
+use transport::outbound::{self, Queue, Frame};
+
+/// How long the writer waits for a pong before checking whether the connection has been closed.
+const POLL_INTERVAL: u64 = 100;
+
+/// What the peer has acknowledged reading, shared between the writer and the connection's handler.
+pub struct Window {
+    acknowledged: Mutex<u64>,
+    changed: Condvar
+}
+
+impl Window {
+    pub fn new() -> Window {
+        Window {
+            acknowledged: Mutex::new(0),
+            changed: Condvar::new()
+        }
+    }
+
+    /// Records a pong from the peer.  Pongs that don't answer one of the writer's pings are ignored.
+    pub fn acknowledge(&self, payload: &[u8]) {
+        if payload.len() != 8 {
+            return;
+        }
+        let sent = payload.iter().fold(0u64, |sent, byte| (sent << 8) | *byte as u64);
+        let mut acknowledged = self.acknowledged.lock().unwrap();
+        if sent > *acknowledged {
+            *acknowledged = sent;
+            self.changed.notify_one();
+        }
+    }
+
+    /// How many of the `sent` bytes the peer has not acknowledged yet.
+    fn unacknowledged(&self, sent: u64) -> u64 {
+        sent.saturating_sub(*self.acknowledged.lock().unwrap())
+    }
+
+    /// Waits until fewer than `size` of the `sent` bytes are unacknowledged, or the queue is
+    /// closed.  Once it is closed, nothing more is queued, so the rest can be sent regardless.
+    fn wait(&self, sent: u64, size: u64, queue: &Queue) {
+        let mut acknowledged = self.acknowledged.lock().unwrap();
+        while sent.saturating_sub(*acknowledged) >= size && !queue.is_closed() {
+            acknowledged = self.changed.wait_timeout(acknowledged, Duration::from_millis(POLL_INTERVAL)).unwrap().0;
+        }
+    }
+}
+
+fn ping_payload(sent: u64) -> Vec<u8> {
+    (0..8).rev().map(|i| (sent >> (i * 8)) as u8).collect()
+}
+
+/// Starts a thread that sends the frames in the queue over the WebSocket connection, keeping no
+/// more than `window_size` bytes unacknowledged by the peer, and returns the sender that queues
+/// them.  Pongs received on the connection must be passed to `window`.
+///
+/// If the queue overflows, the connection is closed with a policy violation once the queue's
+/// farewell has been sent, and otherwise it is closed normally.
+pub fn spawn_writer(sender: WSSender, queue: Queue, window: Arc<Window>, window_size: usize) -> outbound::Sender {
+    let outbound = outbound::Sender::new(queue, usize::max_value());
+    let outgoing = outbound.queue();
+    let window_size = window_size as u64;
+    thread::spawn(move || {
+        let mut sent = 0u64;
+        let mut pinged = 0u64;
+        loop {
+            let written = match outgoing.pop() {
+                Frame::Message(message) => write(&sender, message, &outgoing, &window, window_size, &mut sent, &mut pinged),
+                // The WebSocket library answers pings itself
+                Frame::Pong(_) => Ok(()),
+                Frame::Close => {
+                    if outgoing.overflowed() {
+                        sender.close_with_reason(CloseCode::Policy, "wamp.close.slow_consumer").ok();
+                    } else {
+                        sender.close(CloseCode::Normal).ok();
+                    }
+                    break;
+                }
+            };
+            if let Err(e) = written {
+                debug!("Could not send WebSocket frame: {}", e);
+                outgoing.shutdown();
+                break;
+            }
+        }
+    });
+    outbound
+}
+
+/// Sends a message once the window has room for it, followed by a ping every half window, so
+/// that the peer's pongs usually arrive before the writer has to wait for them.
+fn write(sender: &WSSender, message: WSMessage, queue: &Queue, window: &Window, window_size: u64, sent: &mut u64, pinged: &mut u64) -> WSResult<()> {
+    if window.unacknowledged(*sent) >= window_size {
+        if *pinged < *sent {
+            *pinged = *sent;
+            try!(sender.ping(ping_payload(*sent)));
+        }
+        window.wait(*sent, window_size, queue);
+    }
+    *sent += message_len(&message) as u64;
+    try!(sender.send(message));
+    if *sent - *pinged >= window_size / 2 {
+        *pinged = *sent;
+        try!(sender.ping(ping_payload(*sent)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Window, ping_payload};
+
+    #[test]
+    fn acknowledgements() {
+        let window = Window::new();
+        window.acknowledge(&ping_payload(70000));
+        assert_eq!(window.unacknowledged(100000), 30000);
+        // Stale and unrelated pongs don't move the window
+        window.acknowledge(&ping_payload(500));
+        window.acknowledge(b"hello");
+        assert_eq!(window.unacknowledged(100000), 30000);
+    }
+}